@@ -8,3 +8,30 @@ pub const WORD_SIZE: usize = 64;
 
 pub const INVALID_KEY_ID: u32 = std::u32::MAX;
 
+
+/// Integer type for the key IDs, terminals and tail offsets handled while a
+/// trie is built. `u32` is enough unless the dictionary is large, and halves
+/// the size of those build-time copies.
+pub trait BuildIndex: Copy + std::fmt::Debug + Default + Ord {
+    fn from_usize(x: usize) -> Self;
+    fn to_usize(self) -> usize;
+}
+
+impl BuildIndex for u32 {
+    fn from_usize(x: usize) -> u32 {
+        assert!(x <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
+        x as u32
+    }
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
+impl BuildIndex for usize {
+    fn from_usize(x: usize) -> usize {
+        x
+    }
+    fn to_usize(self) -> usize {
+        self
+    }
+}
@@ -1,4 +1,6 @@
 use std;
+use std::io;
+use io::{Element, Reader, Writer};
 
 #[derive(Copy, Clone, Debug)]
 struct Union {
@@ -38,6 +40,12 @@ impl Cache {
         out
     }
 
+    /// Whether a node can have an entry. Node IDs are 32 bits here, and the
+    /// largest one marks unused entries.
+    pub fn fits(node_id: usize) -> bool {
+        node_id < std::u32::MAX as usize
+    }
+
     pub fn set_parent(&mut self, parent: u32) {
         self.parent_ = parent;
     }
@@ -80,3 +88,20 @@ impl Cache {
     }
 }
 
+impl Element for Cache {
+    fn io_size() -> usize {
+        12
+    }
+    fn read_from(reader: &mut Reader) -> io::Result<Cache> {
+        let parent = try!(reader.read_u32());
+        let child = try!(reader.read_u32());
+        let bits = try!(reader.read_u32());
+        Ok(Cache { parent_: parent, child_: child,
+                   union_: Union { bits_: bits } })
+    }
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(writer.write_u32(self.parent_));
+        try!(writer.write_u32(self.child_));
+        writer.write_u32(self.union_.bits_)
+    }
+}
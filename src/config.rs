@@ -1,4 +1,5 @@
 use quickcheck as qc;
+use vector::bit_vec::{RankBlock, RankLayout, SelectInterval};
 
 /// Min/max values, flags and masks for dictionary settings are defined below.
 /// Please note that unspecified settings will be replaced with the default
@@ -33,8 +34,7 @@ impl qc::Arbitrary for NumTries {
     }
     fn shrink(&self) -> Box<Iterator<Item=Self>> {
         let fewer = self.get() / 2;
-        let fewer = NumTries::new(fewer);
-        if fewer.get() > 0 { qc::single_shrinker(fewer) }
+        if fewer > 0 { qc::single_shrinker(NumTries::new(fewer)) }
             else { qc::empty_shrinker() }
     }
 }
@@ -96,12 +96,79 @@ impl Default for NodeOrder {
     }
 }
 
+/// Per-node subtree key counts let a trie count the keys below a prefix
+/// without enumerating them, at the cost of one packed integer per node.
+/// Counts are 32-bit unless the dictionary is large, so standard ones are
+/// limited to 2^32 keys.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SubtreeCounts {
+    Off = 0x100000,
+    On  = 0x200000,
+}
+impl Default for SubtreeCounts {
+    fn default() -> SubtreeCounts {
+        SubtreeCounts::Off
+    }
+}
+
+/// How key IDs are assigned.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IdOrder {
+    /// IDs follow the breadth-first order of the nodes keys end at. Cheapest,
+    /// but IDs say nothing about the keys themselves.
+    Node = 0x1000000,
+
+    /// Each key's ID is its position in sorted order, so keys that share a
+    /// prefix get a contiguous range of IDs. Costs a permutation between IDs
+    /// and nodes, which is limited to 2^32 keys unless the dictionary is
+    /// large.
+    Label = 0x2000000,
+}
+impl Default for IdOrder {
+    fn default() -> IdOrder {
+        IdOrder::Node
+    }
+}
+
+/// marisa-trie's format caps tail offsets and links at 32 bits, which runs
+/// out at 4 GiB of tail strings. Large dictionaries widen both, and are
+/// written with their own header so that marisa-trie and older versions of
+/// this crate refuse them instead of misreading them. Building one also
+/// widens the IDs and offsets of the keys copied during the build, and
+/// stores `IdOrder::Label` IDs, `SubtreeCounts::On` counts and `IdMap`s in
+/// 64-bit packed vectors. Sparse flags are written in their Elias-Fano form.
+///
+/// Unlike the settings above, this isn't one of the config flags, since it is
+/// recorded in the header.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DictSize {
+    /// Readable by marisa-trie. If a dictionary turns out too big for that,
+    /// the setting is automatically switched to DictSize::Large.
+    Standard,
+    Large,
+}
+impl Default for DictSize {
+    fn default() -> DictSize {
+        DictSize::Standard
+    }
+}
+
+// The select interval, rank block and rank layout of the bit vectors in a
+// trie aren't config flags either: each bit vector records its own. See
+// `SelectInterval`, `RankBlock` and `RankLayout`. Bit vectors too long for
+// `RankLayout::Compact`, or with other than the default rank block, are
+// automatically switched to `RankLayout::Wide`. A trie with any wide bit
+// vector, or any sampled for select at other than the default interval, is
+// written as `DictSize::Large`.
+
 /// Config masks
-const NUM_TRIES_MASK   : u32 = 0x0007F;
-const CACHE_LEVEL_MASK : u32 = 0x00F80;
-const TAIL_MODE_MASK   : u32 = 0x0F000;
-const NODE_ORDER_MASK  : u32 = 0xF0000;
-const CONFIG_MASK      : u32 = 0xFFFFF;
+const NUM_TRIES_MASK      : u32 = 0x000007F;
+const CACHE_LEVEL_MASK    : u32 = 0x0000F80;
+const TAIL_MODE_MASK      : u32 = 0x000F000;
+const NODE_ORDER_MASK     : u32 = 0x00F0000;
+const SUBTREE_COUNTS_MASK : u32 = 0x0F00000;
+const ID_ORDER_MASK       : u32 = 0xF000000;
+pub const CONFIG_MASK     : u32 = 0xFFFFFFF;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Config {
@@ -109,6 +176,12 @@ pub struct Config {
     cache_level_: CacheLevel,
     tail_mode_: TailMode,
     node_order_: NodeOrder,
+    subtree_counts_: SubtreeCounts,
+    id_order_: IdOrder,
+    dict_size_: DictSize,
+    select_interval_: SelectInterval,
+    rank_block_: RankBlock,
+    rank_layout_: RankLayout,
 }
 
 impl Config {
@@ -118,6 +191,12 @@ impl Config {
             cache_level_: Default::default(),
             tail_mode_: Default::default(),
             node_order_: Default::default(),
+            subtree_counts_: Default::default(),
+            id_order_: Default::default(),
+            dict_size_: Default::default(),
+            select_interval_: Default::default(),
+            rank_block_: Default::default(),
+            rank_layout_: Default::default(),
         }
     }
 
@@ -129,6 +208,8 @@ impl Config {
         out.parse_cache_level(config_flags);
         out.parse_tail_mode(config_flags);
         out.parse_node_order(config_flags);
+        out.parse_subtree_counts(config_flags);
+        out.parse_id_order(config_flags);
         out
     }
 
@@ -136,6 +217,8 @@ impl Config {
         self.num_tries_.get()
         | (self.tail_mode_ as u32)
         | (self.node_order_ as u32)
+        | (self.subtree_counts_ as u32)
+        | (self.id_order_ as u32)
     }
 
     pub fn with_num_tries(mut self, num_tries: NumTries) -> Config {
@@ -154,6 +237,32 @@ impl Config {
         self.set_node_order(node_order);
         self
     }
+    pub fn with_subtree_counts(mut self, subtree_counts: SubtreeCounts)
+      -> Config {
+        self.set_subtree_counts(subtree_counts);
+        self
+    }
+    pub fn with_id_order(mut self, id_order: IdOrder) -> Config {
+        self.set_id_order(id_order);
+        self
+    }
+    pub fn with_dict_size(mut self, dict_size: DictSize) -> Config {
+        self.set_dict_size(dict_size);
+        self
+    }
+    pub fn with_select_interval(mut self, select_interval: SelectInterval)
+      -> Config {
+        self.set_select_interval(select_interval);
+        self
+    }
+    pub fn with_rank_block(mut self, rank_block: RankBlock) -> Config {
+        self.set_rank_block(rank_block);
+        self
+    }
+    pub fn with_rank_layout(mut self, rank_layout: RankLayout) -> Config {
+        self.set_rank_layout(rank_layout);
+        self
+    }
 
     pub fn set_num_tries(&mut self, num_tries: NumTries) {
         self.num_tries_ = num_tries;
@@ -167,6 +276,24 @@ impl Config {
     pub fn set_node_order(&mut self, node_order: NodeOrder) {
         self.node_order_ = node_order;
     }
+    pub fn set_subtree_counts(&mut self, subtree_counts: SubtreeCounts) {
+        self.subtree_counts_ = subtree_counts;
+    }
+    pub fn set_id_order(&mut self, id_order: IdOrder) {
+        self.id_order_ = id_order;
+    }
+    pub fn set_dict_size(&mut self, dict_size: DictSize) {
+        self.dict_size_ = dict_size;
+    }
+    pub fn set_select_interval(&mut self, select_interval: SelectInterval) {
+        self.select_interval_ = select_interval;
+    }
+    pub fn set_rank_block(&mut self, rank_block: RankBlock) {
+        self.rank_block_ = rank_block;
+    }
+    pub fn set_rank_layout(&mut self, rank_layout: RankLayout) {
+        self.rank_layout_ = rank_layout;
+    }
 
     pub fn num_tries(&self) -> NumTries {
         self.num_tries_
@@ -180,6 +307,24 @@ impl Config {
     pub fn node_order(&self) -> NodeOrder {
         self.node_order_
     }
+    pub fn subtree_counts(&self) -> SubtreeCounts {
+        self.subtree_counts_
+    }
+    pub fn id_order(&self) -> IdOrder {
+        self.id_order_
+    }
+    pub fn dict_size(&self) -> DictSize {
+        self.dict_size_
+    }
+    pub fn select_interval(&self) -> SelectInterval {
+        self.select_interval_
+    }
+    pub fn rank_block(&self) -> RankBlock {
+        self.rank_block_
+    }
+    pub fn rank_layout(&self) -> RankLayout {
+        self.rank_layout_
+    }
 
     pub fn clear(&mut self) {
         *self = Config::new();
@@ -221,5 +366,22 @@ impl Config {
             _ => panic!("MARISA_CODE_ERROR: undefined node order"),
         }
     }
-}
 
+    fn parse_subtree_counts(&mut self, config_flags: u32) {
+        self.subtree_counts_ = match config_flags & SUBTREE_COUNTS_MASK {
+            0 => Default::default(),
+            x if x == SubtreeCounts::Off as u32 => SubtreeCounts::Off,
+            x if x == SubtreeCounts::On as u32 => SubtreeCounts::On,
+            _ => panic!("MARISA_CODE_ERROR: undefined subtree counts"),
+        }
+    }
+
+    fn parse_id_order(&mut self, config_flags: u32) {
+        self.id_order_ = match config_flags & ID_ORDER_MASK {
+            0 => Default::default(),
+            x if x == IdOrder::Node as u32 => IdOrder::Node,
+            x if x == IdOrder::Label as u32 => IdOrder::Label,
+            _ => panic!("MARISA_CODE_ERROR: undefined id order"),
+        }
+    }
+}
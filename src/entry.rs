@@ -1,17 +1,18 @@
 use std;
 use std::cmp::Ordering;
+use base::BuildIndex;
 use iter_util::common_count_eq;
 
-pub struct Entry<'a> {
+pub struct Entry<'a, I = u32> {
     slice_: &'a [u8],
-    id_: u32,
+    id_: I,
 }
 
-impl<'a> Entry<'a> {
-    pub fn new(slice: &'a [u8], id: u32) -> Entry<'a> {
-        Entry { slice_: slice, id_: id }
+impl<'a, I: BuildIndex> Entry<'a, I> {
+    pub fn new(slice: &'a [u8], id: usize) -> Entry<'a, I> {
+        Entry { slice_: slice, id_: I::from_usize(id) }
     }
-    pub fn common_count<'b>(&'a self, rhs: &Entry<'b>) -> usize {
+    pub fn common_count<'b>(&'a self, rhs: &Entry<'b, I>) -> usize {
         common_count_eq(self.slice_.iter(), rhs.slice_.iter())
     }
     pub fn len(&self) -> usize {
@@ -26,15 +27,15 @@ impl<'a> Entry<'a> {
     pub fn set_slice(&mut self, slice: &'a [u8]) {
         self.slice_ = slice;
     }
-    pub fn get_id(&self) -> u32 {
-        self.id_
+    pub fn get_id(&self) -> usize {
+        self.id_.to_usize()
     }
-    pub fn set_id(&mut self, id: u32) {
-        self.id_ = id;
+    pub fn set_id(&mut self, id: usize) {
+        self.id_ = I::from_usize(id);
     }
 }
 
-impl<'a> IntoIterator for &'a Entry<'a> {
+impl<'a, I: BuildIndex> IntoIterator for &'a Entry<'a, I> {
     type Item = &'a u8;
     type IntoIter = std::iter::Rev<std::slice::Iter<'a, u8> >;
     fn into_iter(self) -> Self::IntoIter {
@@ -42,11 +43,11 @@ impl<'a> IntoIterator for &'a Entry<'a> {
     }
 }
 
-pub fn cmp_slice(l: &Entry, r: &Entry) -> Ordering {
+pub fn cmp_slice<I>(l: &Entry<I>, r: &Entry<I>) -> Ordering {
     l.slice_.cmp(&r.slice_)
 }
 
-pub fn cmp_id(l: &Entry, r: &Entry) -> Ordering {
+pub fn cmp_id<I: Ord>(l: &Entry<I>, r: &Entry<I>) -> Ordering {
     l.id_.cmp(&r.id_)
 }
 
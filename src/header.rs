@@ -1,13 +1,28 @@
+use std::io;
+use config::DictSize;
+use io::{Reader, Writer, format_error};
+
 const HEADER_SIZE: usize = 16;
 
-struct Header;
+pub struct Header {
+    dict_size_: DictSize,
+}
+
+/// Includes the terminating null, as in marisa-trie
+const header: &'static [u8; HEADER_SIZE] = b"We love Marisa.\0";
 
-const header: &'static str = "We love Marisa.";
+/// For `DictSize::Large`. Same length, so a reader can tell the two apart
+/// before reading anything else.
+const large_header: &'static [u8; HEADER_SIZE] = b"We love Marisa64";
 
 impl Header {
 
-    fn new() -> Header {
-        Header
+    pub fn new(dict_size: DictSize) -> Header {
+        Header { dict_size_: dict_size }
+    }
+
+    pub fn dict_size(&self) -> DictSize {
+        self.dict_size_
     }
 
 /*
@@ -16,26 +31,39 @@ impl Header {
     mapper.map(&ptr, HEADER_SIZE);
     MARISA_THROW_IF(!test_header(ptr), MARISA_FORMAT_ERROR);
   }
-  void read(Reader &reader) {
-    char buf[HEADER_SIZE];
-    reader.read(buf, HEADER_SIZE);
-    MARISA_THROW_IF(!test_header(buf), MARISA_FORMAT_ERROR);
-  }
-  void write(Writer &writer) const {
-    writer.write(get_header(), HEADER_SIZE);
-  }
 */
 
-    fn io_size() -> usize {
+    pub fn read(reader: &mut Reader) -> io::Result<Header> {
+        let mut buf = [0u8; HEADER_SIZE];
+        try!(reader.read_bytes(&mut buf));
+        match Header::test_header(&buf) {
+            Some(dict_size) => Ok(Header::new(dict_size)),
+            None => Err(format_error("not a dictionary")),
+        }
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_bytes(self.get_header())
+    }
+
+    pub fn io_size() -> usize {
         HEADER_SIZE
     }
 
-    fn get_header() -> &'static str {
-        header
+    fn get_header(&self) -> &'static [u8] {
+        match self.dict_size_ {
+            DictSize::Standard => header,
+            DictSize::Large => large_header,
+        }
     }
 
-    fn test_header<'a>(x: &'a str) -> bool {
-        header == x
+    fn test_header(x: &[u8]) -> Option<DictSize> {
+        if &header[..] == x {
+            Some(DictSize::Standard)
+        } else if &large_header[..] == x {
+            Some(DictSize::Large)
+        } else {
+            None
+        }
     }
 }
-
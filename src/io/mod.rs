@@ -0,0 +1,138 @@
+use std;
+use std::io;
+
+pub mod reader;
+pub mod writer;
+
+pub use self::reader::Reader;
+pub use self::writer::Writer;
+
+/// Fixed-size values that can be stored in a serialized vector.
+///
+/// Everything is little-endian, in the same layout as marisa-trie uses on
+/// little-endian machines, including its padding of vectors to 8 bytes.
+pub trait Element: Sized {
+    /// Bytes per value
+    fn io_size() -> usize;
+    fn read_from(reader: &mut Reader) -> io::Result<Self>;
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()>;
+}
+
+impl Element for u8 {
+    fn io_size() -> usize {
+        1
+    }
+    fn read_from(reader: &mut Reader) -> io::Result<u8> {
+        reader.read_u8()
+    }
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_u8(*self)
+    }
+}
+
+impl Element for u32 {
+    fn io_size() -> usize {
+        4
+    }
+    fn read_from(reader: &mut Reader) -> io::Result<u32> {
+        reader.read_u32()
+    }
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_u32(*self)
+    }
+}
+
+impl Element for u64 {
+    fn io_size() -> usize {
+        8
+    }
+    fn read_from(reader: &mut Reader) -> io::Result<u64> {
+        reader.read_u64()
+    }
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()> {
+        writer.write_u64(*self)
+    }
+}
+
+/// Bit vector units are machine words, as in marisa-trie, so files written
+/// on 32-bit and 64-bit machines differ.
+impl Element for usize {
+    fn io_size() -> usize {
+        std::mem::size_of::<usize>()
+    }
+    fn read_from(reader: &mut Reader) -> io::Result<usize> {
+        if std::mem::size_of::<usize>() == 8 {
+            reader.read_u64().map(|x| x as usize)
+        } else {
+            reader.read_u32().map(|x| x as usize)
+        }
+    }
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()> {
+        if std::mem::size_of::<usize>() == 8 {
+            writer.write_u64(*self as u64)
+        } else {
+            writer.write_u32(*self as u32)
+        }
+    }
+}
+
+/// Error for input that doesn't hold what we expected
+pub fn format_error(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("MARISA_FORMAT_ERROR: {}", what))
+}
+
+#[cfg(test)]
+mod test {
+    use quickcheck as qc;
+    use std::io;
+    use super::{Reader, Writer};
+
+    fn round_trip_prop(a: Vec<u8>, b: Vec<u32>, c: Vec<usize>, d: u64)
+      -> bool {
+        let mut buf: Vec<u8> = Vec::new();
+        {
+            let mut writer = Writer::new(&mut buf);
+            writer.write_vec(&a).unwrap();
+            writer.write_vec(&b).unwrap();
+            writer.write_vec(&c).unwrap();
+            writer.write_u64(d).unwrap();
+        }
+        if buf.len() % 8 != 0 {
+            return false;
+        }
+        let mut slice = &buf[..];
+        let mut reader = Reader::new(&mut slice);
+        reader.read_vec::<u8>().unwrap() == a
+        && reader.read_vec::<u32>().unwrap() == b
+        && reader.read_vec::<usize>().unwrap() == c
+        && reader.read_u64().unwrap() == d
+        && reader.read_u8().is_err()
+    }
+
+    #[test]
+    fn io_round_trip_qc() {
+        qc::quickcheck(round_trip_prop
+                       as fn(Vec<u8>, Vec<u32>, Vec<usize>, u64) -> bool);
+    }
+
+    #[test]
+    fn io_layout_manual() {
+        let mut buf: Vec<u8> = Vec::new();
+        Writer::new(&mut buf).write_vec(&[1u32, 0x01020304]).unwrap();
+        assert!(buf == vec![8, 0, 0, 0, 0, 0, 0, 0,
+                            1, 0, 0, 0, 4, 3, 2, 1]);
+
+        // Lengths that aren't a multiple of the element size
+        let buf: Vec<u8> = vec![3, 0, 0, 0, 0, 0, 0, 0,
+                                1, 2, 3, 0, 0, 0, 0, 0];
+        let mut slice = &buf[..];
+        assert!(Reader::new(&mut slice).read_vec::<u32>().is_err());
+
+        // Lengths longer than the input
+        let buf: Vec<u8> = vec![0, 0, 0, 0, 0, 0, 0, 0x40];
+        let mut slice = &buf[..];
+        let err = Reader::new(&mut slice).read_vec::<u64>().unwrap_err();
+        assert!(err.kind() == io::ErrorKind::InvalidData);
+    }
+}
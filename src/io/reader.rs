@@ -1,215 +1,78 @@
-
-#ifndef MARISA_GRIMOIRE_IO_READER_H_
-#define MARISA_GRIMOIRE_IO_READER_H_
-
-#include <cstdio>
-#include <iostream>
-
-#include "marisa/base.h"
-
-namespace marisa {
-namespace grimoire {
-namespace io {
-
-class Reader {
- public:
-  Reader();
-  ~Reader();
-
-  void open(const char *filename);
-  void open(std::FILE *file);
-  void open(int fd);
-  void open(std::istream &stream);
-
-  template <typename T>
-  void read(T *obj) {
-    MARISA_THROW_IF(obj == NULL, MARISA_NULL_ERROR);
-    read_data(obj, sizeof(T));
-  }
-
-  template <typename T>
-  void read(T *objs, std::size_t num_objs) {
-    MARISA_THROW_IF((objs == NULL) && (num_objs != 0), MARISA_NULL_ERROR);
-    MARISA_THROW_IF(num_objs > (MARISA_SIZE_MAX / sizeof(T)),
-        MARISA_SIZE_ERROR);
-    read_data(objs, sizeof(T) * num_objs);
-  }
-
-  void seek(std::size_t size);
-
-  bool is_open() const;
-
-  void clear();
-  void swap(Reader &rhs);
-
- private:
-  std::FILE *file_;
-  int fd_;
-  std::istream *stream_;
-  bool needs_fclose_;
-
-  void open_(const char *filename);
-  void open_(std::FILE *file);
-  void open_(int fd);
-  void open_(std::istream &stream);
-
-  void read_data(void *buf, std::size_t size);
-
-  // Disallows copy and assignment.
-  Reader(const Reader &);
-  Reader &operator=(const Reader &);
-};
-
-}  // namespace io
-}  // namespace grimoire
-}  // namespace marisa
-
-#endif  // MARISA_GRIMOIRE_IO_READER_H_
-
-#include <stdio.h>
-
-#ifdef _WIN32
- #include <io.h>
-#else  // _WIN32
- #include <unistd.h>
-#endif  // _WIN32
-
-#include <limits>
-
-#include "marisa/grimoire/io/reader.h"
-
-namespace marisa {
-namespace grimoire {
-namespace io {
-
-Reader::Reader()
-    : file_(NULL), fd_(-1), stream_(NULL), needs_fclose_(false) {}
-
-Reader::~Reader() {
-  if (needs_fclose_) {
-    ::fclose(file_);
-  }
-}
-
-void Reader::open(const char *filename) {
-  MARISA_THROW_IF(filename == NULL, MARISA_NULL_ERROR);
-
-  Reader temp;
-  temp.open_(filename);
-  swap(temp);
+use std;
+use std::io;
+use std::io::Read;
+use super::{Element, format_error};
+
+/// Most bytes `read_vec` reserves before any values have been read, so that a
+/// corrupt length can't allocate more than the input holds
+const MAX_RESERVE: usize = 1 << 20;
+
+/// Reads the layout written by `Writer`
+pub struct Reader<'a> {
+    inner_: &'a mut Read,
 }
 
-void Reader::open(std::FILE *file) {
-  MARISA_THROW_IF(file == NULL, MARISA_NULL_ERROR);
-
-  Reader temp;
-  temp.open_(file);
-  swap(temp);
-}
-
-void Reader::open(int fd) {
-  MARISA_THROW_IF(fd == -1, MARISA_CODE_ERROR);
-
-  Reader temp;
-  temp.open_(fd);
-  swap(temp);
-}
-
-void Reader::open(std::istream &stream) {
-  Reader temp;
-  temp.open_(stream);
-  swap(temp);
-}
-
-void Reader::clear() {
-  Reader().swap(*this);
-}
-
-void Reader::swap(Reader &rhs) {
-  marisa::swap(file_, rhs.file_);
-  marisa::swap(fd_, rhs.fd_);
-  marisa::swap(stream_, rhs.stream_);
-  marisa::swap(needs_fclose_, rhs.needs_fclose_);
-}
-
-void Reader::seek(std::size_t size) {
-  MARISA_THROW_IF(!is_open(), MARISA_STATE_ERROR);
-  if (size == 0) {
-    return;
-  } else if (size <= 16) {
-    char buf[16];
-    read_data(buf, size);
-  } else {
-    char buf[1024];
-    while (size != 0) {
-      const std::size_t count = (size < sizeof(buf)) ? size : sizeof(buf);
-      read_data(buf, count);
-      size -= count;
+impl<'a> Reader<'a> {
+    pub fn new(inner: &'a mut Read) -> Reader<'a> {
+        Reader { inner_: inner }
     }
-  }
-}
 
-bool Reader::is_open() const {
-  return (file_ != NULL) || (fd_ != -1) || (stream_ != NULL);
-}
-
-void Reader::open_(const char *filename) {
-  std::FILE *file = NULL;
-#ifdef _MSC_VER
-  MARISA_THROW_IF(::fopen_s(&file, filename, "rb") != 0, MARISA_IO_ERROR);
-#else  // _MSC_VER
-  file = ::fopen(filename, "rb");
-  MARISA_THROW_IF(file == NULL, MARISA_IO_ERROR);
-#endif  // _MSC_VER
-  file_ = file;
-  needs_fclose_ = true;
-}
-
-void Reader::open_(std::FILE *file) {
-  file_ = file;
-}
-
-void Reader::open_(int fd) {
-  fd_ = fd;
-}
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner_.read_exact(buf)
+    }
 
-void Reader::open_(std::istream &stream) {
-  stream_ = &stream;
-}
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        try!(self.read_bytes(&mut buf));
+        Ok(buf[0])
+    }
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        try!(self.read_bytes(&mut buf));
+        Ok(buf.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        try!(self.read_bytes(&mut buf));
+        Ok(buf.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
 
-void Reader::read_data(void *buf, std::size_t size) {
-  MARISA_THROW_IF(!is_open(), MARISA_STATE_ERROR);
-  if (size == 0) {
-    return;
-  } else if (fd_ != -1) {
-    while (size != 0) {
-#ifdef _WIN32
-      static const std::size_t CHUNK_SIZE =
-          std::numeric_limits<int>::max();
-      const unsigned int count = (size < CHUNK_SIZE) ? size : CHUNK_SIZE;
-      const int size_read = ::_read(fd_, buf, count);
-#else  // _WIN32
-      static const std::size_t CHUNK_SIZE =
-          std::numeric_limits< ::ssize_t>::max();
-      const ::size_t count = (size < CHUNK_SIZE) ? size : CHUNK_SIZE;
-      const ::ssize_t size_read = ::read(fd_, buf, count);
-#endif  // _WIN32
-      MARISA_THROW_IF(size_read <= 0, MARISA_IO_ERROR);
-      buf = static_cast<char *>(buf) + size_read;
-      size -= size_read;
+    /// Skip `size` bytes of padding
+    pub fn seek(&mut self, size: usize) -> io::Result<()> {
+        let mut buf = [0u8; 8];
+        let mut size = size;
+        while size != 0 {
+            let count = std::cmp::min(size, buf.len());
+            try!(self.read_bytes(&mut buf[..count]));
+            size -= count;
+        }
+        Ok(())
     }
-  } else if (file_ != NULL) {
-    MARISA_THROW_IF(::fread(buf, 1, size, file_) != size, MARISA_IO_ERROR);
-  } else if (stream_ != NULL) {
-    try {
-      MARISA_THROW_IF(!stream_->read(static_cast<char *>(buf), size),
-          MARISA_IO_ERROR);
-    } catch (const std::ios_base::failure &) {
-      MARISA_THROW(MARISA_IO_ERROR, "std::ios_base::failure");
+
+    /// A vector as written by `Writer::write_vec`: its size in bytes, the
+    /// values, and padding up to a multiple of 8 bytes
+    pub fn read_vec<T: Element>(&mut self) -> io::Result<Vec<T>> {
+        let total_size = try!(self.read_u64());
+        if total_size > std::usize::MAX as u64 {
+            return Err(format_error("vector too large"));
+        }
+        let total_size = total_size as usize;
+        if total_size % T::io_size() != 0 {
+            return Err(format_error("vector size"));
+        }
+        let len = total_size / T::io_size();
+        let mut out: Vec<T> =
+            Vec::with_capacity(std::cmp::min(len, MAX_RESERVE / T::io_size()));
+        for _ in 0..len {
+            match T::read_from(self) {
+                Ok(value) => out.push(value),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(format_error("vector truncated"));
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        try!(self.seek((8 - (total_size % 8)) % 8));
+        Ok(out)
     }
-  }
 }
-
-}  // namespace io
-}  // namespace grimoire
-}  // namespace marisa
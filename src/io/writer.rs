@@ -1,214 +1,60 @@
-#ifndef MARISA_GRIMOIRE_IO_WRITER_H_
-#define MARISA_GRIMOIRE_IO_WRITER_H_
+use std;
+use std::io;
+use std::io::Write;
+use super::Element;
 
-#include <cstdio>
-#include <iostream>
-
-#include "marisa/base.h"
-
-namespace marisa {
-namespace grimoire {
-namespace io {
-
-class Writer {
- public:
-  Writer();
-  ~Writer();
-
-  void open(const char *filename);
-  void open(std::FILE *file);
-  void open(int fd);
-  void open(std::ostream &stream);
-
-  template <typename T>
-  void write(const T &obj) {
-    write_data(&obj, sizeof(T));
-  }
-
-  template <typename T>
-  void write(const T *objs, std::size_t num_objs) {
-    MARISA_THROW_IF((objs == NULL) && (num_objs != 0), MARISA_NULL_ERROR);
-    MARISA_THROW_IF(num_objs > (MARISA_SIZE_MAX / sizeof(T)),
-                    MARISA_SIZE_ERROR);
-    write_data(objs, sizeof(T) * num_objs);
-  }
-
-  void seek(std::size_t size);
-
-  bool is_open() const;
-
-  void clear();
-  void swap(Writer &rhs);
-
- private:
-  std::FILE *file_;
-  int fd_;
-  std::ostream *stream_;
-  bool needs_fclose_;
-
-  void open_(const char *filename);
-  void open_(std::FILE *file);
-  void open_(int fd);
-  void open_(std::ostream &stream);
-
-  void write_data(const void *data, std::size_t size);
-
-  // Disallows copy and assignment.
-  Writer(const Writer &);
-  Writer &operator=(const Writer &);
-};
-
-}  // namespace io
-}  // namespace grimoire
-}  // namespace marisa
-
-#endif  // MARISA_GRIMOIRE_IO_WRITER_H_
-
-#include <stdio.h>
-
-#ifdef _WIN32
- #include <io.h>
-#else  // _WIN32
- #include <unistd.h>
-#endif  // _WIN32
-
-#include <limits>
-
-#include "marisa/grimoire/io/writer.h"
-
-namespace marisa {
-namespace grimoire {
-namespace io {
-
-Writer::Writer()
-    : file_(NULL), fd_(-1), stream_(NULL), needs_fclose_(false) {}
-
-Writer::~Writer() {
-  if (needs_fclose_) {
-    ::fclose(file_);
-  }
-}
-
-void Writer::open(const char *filename) {
-  MARISA_THROW_IF(filename == NULL, MARISA_NULL_ERROR);
-
-  Writer temp;
-  temp.open_(filename);
-  swap(temp);
+/// Writes values little-endian, in marisa-trie's layout
+pub struct Writer<'a> {
+    inner_: &'a mut Write,
 }
 
-void Writer::open(std::FILE *file) {
-  MARISA_THROW_IF(file == NULL, MARISA_NULL_ERROR);
-
-  Writer temp;
-  temp.open_(file);
-  swap(temp);
-}
-
-void Writer::open(int fd) {
-  MARISA_THROW_IF(fd == -1, MARISA_CODE_ERROR);
-
-  Writer temp;
-  temp.open_(fd);
-  swap(temp);
-}
-
-void Writer::open(std::ostream &stream) {
-  Writer temp;
-  temp.open_(stream);
-  swap(temp);
-}
-
-void Writer::clear() {
-  Writer().swap(*this);
-}
-
-void Writer::swap(Writer &rhs) {
-  marisa::swap(file_, rhs.file_);
-  marisa::swap(fd_, rhs.fd_);
-  marisa::swap(stream_, rhs.stream_);
-  marisa::swap(needs_fclose_, rhs.needs_fclose_);
-}
-
-void Writer::seek(std::size_t size) {
-  MARISA_THROW_IF(!is_open(), MARISA_STATE_ERROR);
-  if (size == 0) {
-    return;
-  } else if (size <= 16) {
-    const char buf[16] = {};
-    write_data(buf, size);
-  } else {
-    const char buf[1024] = {};
-    do {
-      const std::size_t count = (size < sizeof(buf)) ? size : sizeof(buf);
-      write_data(buf, count);
-      size -= count;
-    } while (size != 0);
-  }
-}
-
-bool Writer::is_open() const {
-  return (file_ != NULL) || (fd_ != -1) || (stream_ != NULL);
-}
-
-void Writer::open_(const char *filename) {
-  std::FILE *file = NULL;
-#ifdef _MSC_VER
-  MARISA_THROW_IF(::fopen_s(&file, filename, "wb") != 0, MARISA_IO_ERROR);
-#else  // _MSC_VER
-  file = ::fopen(filename, "wb");
-  MARISA_THROW_IF(file == NULL, MARISA_IO_ERROR);
-#endif  // _MSC_VER
-  file_ = file;
-  needs_fclose_ = true;
-}
-
-void Writer::open_(std::FILE *file) {
-  file_ = file;
-}
+impl<'a> Writer<'a> {
+    pub fn new(inner: &'a mut Write) -> Writer<'a> {
+        Writer { inner_: inner }
+    }
 
-void Writer::open_(int fd) {
-  fd_ = fd;
-}
+    pub fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner_.write_all(buf)
+    }
 
-void Writer::open_(std::ostream &stream) {
-  stream_ = &stream;
-}
+    pub fn write_u8(&mut self, value: u8) -> io::Result<()> {
+        self.write_bytes(&[value])
+    }
+    pub fn write_u32(&mut self, value: u32) -> io::Result<()> {
+        let mut buf = [0u8; 4];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (value >> (i * 8)) as u8;
+        }
+        self.write_bytes(&buf)
+    }
+    pub fn write_u64(&mut self, value: u64) -> io::Result<()> {
+        let mut buf = [0u8; 8];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (value >> (i * 8)) as u8;
+        }
+        self.write_bytes(&buf)
+    }
 
-void Writer::write_data(const void *data, std::size_t size) {
-  MARISA_THROW_IF(!is_open(), MARISA_STATE_ERROR);
-  if (size == 0) {
-    return;
-  } else if (fd_ != -1) {
-    while (size != 0) {
-#ifdef _WIN32
-      static const std::size_t CHUNK_SIZE =
-          std::numeric_limits<int>::max();
-      const unsigned int count = (size < CHUNK_SIZE) ? size : CHUNK_SIZE;
-      const int size_written = ::_write(fd_, data, count);
-#else  // _WIN32
-      static const std::size_t CHUNK_SIZE =
-          std::numeric_limits< ::ssize_t>::max();
-      const ::size_t count = (size < CHUNK_SIZE) ? size : CHUNK_SIZE;
-      const ::ssize_t size_written = ::write(fd_, data, count);
-#endif  // _WIN32
-      MARISA_THROW_IF(size_written <= 0, MARISA_IO_ERROR);
-      data = static_cast<const char *>(data) + size_written;
-      size -= size_written;
+    /// Write `size` bytes of zero padding
+    pub fn seek(&mut self, size: usize) -> io::Result<()> {
+        let buf = [0u8; 8];
+        let mut size = size;
+        while size != 0 {
+            let count = std::cmp::min(size, buf.len());
+            try!(self.write_bytes(&buf[..count]));
+            size -= count;
+        }
+        Ok(())
     }
-  } else if (file_ != NULL) {
-    MARISA_THROW_IF(::fwrite(data, 1, size, file_) != size, MARISA_IO_ERROR);
-    MARISA_THROW_IF(::fflush(file_) != 0, MARISA_IO_ERROR);
-  } else if (stream_ != NULL) {
-    try {
-      MARISA_THROW_IF(!stream_->write(static_cast<const char *>(data), size),
-          MARISA_IO_ERROR);
-    } catch (const std::ios_base::failure &) {
-      MARISA_THROW(MARISA_IO_ERROR, "std::ios_base::failure");
+
+    /// Size in bytes, then the values, then padding up to a multiple of 8
+    /// bytes
+    pub fn write_vec<T: Element>(&mut self, values: &[T]) -> io::Result<()> {
+        let total_size = values.len() * T::io_size();
+        try!(self.write_u64(total_size as u64));
+        for value in values {
+            try!(value.write_to(self));
+        }
+        self.seek((8 - (total_size % 8)) % 8)
     }
-  }
 }
-
-}  // namespace io
-}  // namespace grimoire
-}  // namespace marisa
@@ -1,30 +1,32 @@
 use std;
+use base::BuildIndex;
 use entry::Entry;
 
 #[derive(Copy, Clone, Debug)]
-struct Union {
-    // weight or terminal
-    bits_: u32,
+struct Union<I> {
+    // weight or terminal. Terminals are tail offsets in the last trie, so
+    // large dictionaries need more than 32 bits.
+    bits_: I,
 }
 
-impl Union {
-    fn new() -> Union {
-        Union { bits_: 0 }
+impl<I: BuildIndex> Union<I> {
+    fn new() -> Union<I> {
+        Union { bits_: I::default() }
     }
 
     fn get_weight(&self) -> f32 {
-        unsafe { std::mem::transmute(self.bits_) }
+        unsafe { std::mem::transmute(self.bits_.to_usize() as u32) }
     }
     fn get_terminal(&self) -> usize {
-        self.bits_ as usize
+        self.bits_.to_usize()
     }
 
     fn set_weight(&mut self, weight: f32) {
-        self.bits_ = unsafe { std::mem::transmute(weight) };
+        let bits: u32 = unsafe { std::mem::transmute(weight) };
+        self.bits_ = I::from_usize(bits as usize);
     }
     fn set_terminal(&mut self, terminal: usize) {
-        assert!(terminal <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
-        self.bits_ = terminal as u32;
+        self.bits_ = I::from_usize(terminal);
     }
 }
 
@@ -47,16 +49,18 @@ pub trait IKey<'a> {
     fn len(&self) -> usize;
 }
 
+/// A key to build a trie from. IDs and terminals are `I`s, so keys only take
+/// more space when there are too many for 32 bits.
 #[derive(Copy, Clone, Debug)]
-pub struct Key<'a> {
+pub struct Key<'a, I = u32> {
     slice_: &'a[u8],
-    union_: Union,
-    id_: u32,
+    union_: Union<I>,
+    id_: I,
 }
 
-impl<'a> Key<'a> {
-    pub fn new(slice: &'a[u8]) -> Key<'a> {
-        Key { slice_: slice, union_: Union::new(), id_: 0 }
+impl<'a, I: BuildIndex> Key<'a, I> {
+    pub fn new(slice: &'a[u8]) -> Key<'a, I> {
+        Key { slice_: slice, union_: Union::new(), id_: I::default() }
     }
     pub fn with_weight(&self, weight: f32) -> Self {
         let mut out = *self;
@@ -68,13 +72,13 @@ impl<'a> Key<'a> {
     }
 }
 
-impl<'a> From<&'a[u8]> for Key<'a> {
-    fn from(slice: &'a[u8]) -> Key<'a> {
+impl<'a, I: BuildIndex> From<&'a[u8]> for Key<'a, I> {
+    fn from(slice: &'a[u8]) -> Key<'a, I> {
         Key::new(slice)
     }
 }
 
-impl<'a> IKey<'a> for Key<'a> {
+impl<'a, I: BuildIndex> IKey<'a> for Key<'a, I> {
     fn at(&self, i: usize) -> u8 {
         self.slice_[i]
     }
@@ -94,8 +98,7 @@ impl<'a> IKey<'a> for Key<'a> {
         self.union_.set_terminal(terminal);
     }
     fn set_id(&mut self, id: usize) {
-        assert!(id <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
-        self.id_ = id as u32;
+        self.id_ = I::from_usize(id);
     }
     fn get_slice(&self) -> &'a[u8] {
         self.slice_
@@ -107,28 +110,28 @@ impl<'a> IKey<'a> for Key<'a> {
         self.union_.get_terminal()
     }
     fn get_id(&self) -> usize {
-        self.id_ as usize
+        self.id_.to_usize()
     }
     fn len(&self) -> usize {
         self.get_slice().len()
     }
 }
 
-impl<'a> PartialEq for Key<'a> {
+impl<'a, I: BuildIndex> PartialEq for Key<'a, I> {
     fn eq(&self, rhs: &Self) -> bool {
         self.get_slice() == rhs.get_slice()
     }
 }
 
-impl<'a> Eq for Key<'a> {}
+impl<'a, I: BuildIndex> Eq for Key<'a, I> {}
 
-impl<'a> PartialOrd for Key<'a> {
+impl<'a, I: BuildIndex> PartialOrd for Key<'a, I> {
     fn partial_cmp(&self, rhs: &Self) -> Option<std::cmp::Ordering> {
         self.get_slice().partial_cmp(rhs.get_slice())
     }
 }
 
-impl<'a> Ord for Key<'a> {
+impl<'a, I: BuildIndex> Ord for Key<'a, I> {
     fn cmp(&self, rhs: &Self) -> std::cmp::Ordering {
         self.get_slice().cmp(rhs.get_slice())
     }
@@ -136,18 +139,18 @@ impl<'a> Ord for Key<'a> {
 
 /// Just like Key, except we index and subslice from the end of the slice
 #[derive(Copy, Clone, Debug)]
-pub struct ReverseKey<'a> {
+pub struct ReverseKey<'a, I = u32> {
     slice_: &'a[u8],
-    union_: Union,
-    id_: u32,
+    union_: Union<I>,
+    id_: I,
 }
 
 // FIXME: Reduce amount of identical code between Key and ReverseKey. Only
 //        at() and subslice() are different at all!
 
-impl<'a> ReverseKey<'a> {
-    pub fn new(slice: &'a[u8]) -> ReverseKey<'a> {
-        ReverseKey { slice_: slice, union_: Union::new(), id_: 0 }
+impl<'a, I: BuildIndex> ReverseKey<'a, I> {
+    pub fn new(slice: &'a[u8]) -> ReverseKey<'a, I> {
+        ReverseKey { slice_: slice, union_: Union::new(), id_: I::default() }
     }
     pub fn with_weight(&self, weight: f32) -> Self {
         let mut out = *self;
@@ -159,13 +162,13 @@ impl<'a> ReverseKey<'a> {
     }
 }
 
-impl<'a> From<&'a[u8]> for ReverseKey<'a> {
-    fn from(slice: &'a[u8]) -> ReverseKey<'a> {
+impl<'a, I: BuildIndex> From<&'a[u8]> for ReverseKey<'a, I> {
+    fn from(slice: &'a[u8]) -> ReverseKey<'a, I> {
         ReverseKey::new(slice)
     }
 }
 
-impl<'a> IKey<'a> for ReverseKey<'a> {
+impl<'a, I: BuildIndex> IKey<'a> for ReverseKey<'a, I> {
     fn at(&self, i: usize) -> u8 {
         self.slice_[self.slice_.len() - i - 1]
     }
@@ -187,8 +190,7 @@ impl<'a> IKey<'a> for ReverseKey<'a> {
         self.union_.set_terminal(terminal);
     }
     fn set_id(&mut self, id: usize) {
-        assert!(id <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
-        self.id_ = id as u32;
+        self.id_ = I::from_usize(id);
     }
     fn get_slice(&self) -> &'a[u8] {
         self.slice_
@@ -200,30 +202,31 @@ impl<'a> IKey<'a> for ReverseKey<'a> {
         self.union_.get_terminal()
     }
     fn get_id(&self) -> usize {
-        self.id_ as usize
+        self.id_.to_usize()
     }
     fn len(&self) -> usize {
         self.get_slice().len()
     }
 }
 
-impl<'a> PartialEq for ReverseKey<'a> {
+impl<'a, I: BuildIndex> PartialEq for ReverseKey<'a, I> {
     fn eq(&self, rhs: &Self) -> bool {
         self.get_slice() == rhs.get_slice()
     }
 }
 
-impl<'a> Eq for ReverseKey<'a> {}
+impl<'a, I: BuildIndex> Eq for ReverseKey<'a, I> {}
 
-impl<'a> PartialOrd for ReverseKey<'a> {
+impl<'a, I: BuildIndex> PartialOrd for ReverseKey<'a, I> {
     fn partial_cmp(&self, rhs: &Self) -> Option<std::cmp::Ordering> {
-        self.get_slice().partial_cmp(rhs.get_slice())
+        Some(self.cmp(rhs))
     }
 }
 
-impl<'a> Ord for ReverseKey<'a> {
+/// Compares from the end, to match `at`
+impl<'a, I: BuildIndex> Ord for ReverseKey<'a, I> {
     fn cmp(&self, rhs: &Self) -> std::cmp::Ordering {
-        self.get_slice().cmp(rhs.get_slice())
+        self.get_slice().iter().rev().cmp(rhs.get_slice().iter().rev())
     }
 }
 
@@ -7,15 +7,27 @@ mod base;
 mod config;
 mod error;
 mod iter_util;
-mod vector;
+/// Succinct building blocks the trie is made of: bit vectors with rank and
+/// select, and vectors of bit-packed integers. Each has `read` and `write`
+/// for the `io` layout.
+pub mod vector;
 
 mod cache;
 mod entry;
 mod header;
+/// The little-endian, 8-byte-padded layout of marisa-trie files
+pub mod io;
 mod key;
 mod louds_trie;
 mod range;
 
+pub use louds_trie::{LoudsBits, LoudsTrie};
+pub use louds_trie::delta::DeltaTrie;
+pub use louds_trie::id_map::IdMap;
+pub use louds_trie::pinned::PinnedTrie;
+pub use louds_trie::diff::{Diff, DiffEntry};
+pub use vector::rank_select::RankSelect;
+
 #[cfg(test)]
 extern crate env_logger;
 
@@ -0,0 +1,289 @@
+use std;
+use super::{LoudsTrie, LoudsBits, NodeID, LoudsPos};
+use super::nav::{Keys, Nav};
+use vector::bit_flags::BitFlags;
+
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    node_id_: NodeID,
+    louds_pos_: LoudsPos,
+    /// Offset of this node's label in `Cursor::path_`
+    key_pos_: u32,
+}
+
+/// Incremental, byte-at-a-time matching cursor.
+///
+/// Bytes are fed in with `push` and taken back with `pop`. The cursor keeps
+/// its place inside multi-byte labels (links into the next trie or the tail),
+/// so nothing has to be re-matched from the root on each keystroke.
+///
+/// Bytes that don't match anything are still recorded, so `pop` always
+/// undoes exactly one `push`. `exists` reports whether the bytes pushed so far
+/// are a prefix of some key.
+#[derive(Debug)]
+pub struct Cursor<'a, L: 'a = BitFlags> {
+    trie_: &'a LoudsTrie<L>,
+
+    /// Nodes on the matched path. The root is always at the bottom.
+    history_: Vec<Frame>,
+
+    /// Full labels of the nodes in `history_`, concatenated. May run past the
+    /// matched bytes if we're in the middle of the top node's label.
+    path_: Vec<u8>,
+
+    /// Every byte pushed so far, matching or not
+    query_: Vec<u8>,
+
+    /// Length of the prefix of `query_` that matches `path_`
+    matched_: usize,
+
+    label_buf_: Vec<u8>,
+}
+
+impl<'a, L: 'a + LoudsBits> Cursor<'a, L> {
+    pub fn new(trie: &'a LoudsTrie<L>) -> Cursor<'a, L> {
+        let mut out = Cursor { trie_: trie, history_: Vec::new(),
+                               path_: Vec::new(), query_: Vec::new(),
+                               matched_: 0, label_buf_: Vec::new() };
+        out.history_.push(Frame { node_id_: NodeID(0),
+                                  louds_pos_: LoudsPos(0), key_pos_: 0 });
+        out
+    }
+
+    /// Append one byte to the query. Returns true if the query is still a
+    /// prefix of some key.
+    pub fn push(&mut self, c: u8) -> bool {
+        self.query_.push(c);
+        if self.matched_ + 1 != self.query_.len() {
+            debug!("push: already past the end of the trie");
+            return false;
+        }
+        if self.matched_ < self.path_.len() {
+            // In the middle of a label
+            if self.path_[self.matched_] != c {
+                return false;
+            }
+        } else {
+            let node_id = self.history_.last().unwrap().node_id_;
+            if !self.go_to_child(node_id, c) {
+                return false;
+            }
+        }
+        self.matched_ += 1;
+        true
+    }
+
+    /// Remove the last byte from the query
+    pub fn pop(&mut self) -> Option<u8> {
+        let c = match self.query_.pop() {
+            Some(c) => c,
+            None => { return None; }
+        };
+        if self.matched_ > self.query_.len() {
+            self.matched_ -= 1;
+            let top = *self.history_.last().unwrap();
+            if self.history_.len() > 1
+            && self.matched_ == top.key_pos_ as usize {
+                self.history_.pop();
+                self.path_.truncate(top.key_pos_ as usize);
+            }
+        }
+        Some(c)
+    }
+
+    pub fn clear(&mut self) {
+        self.history_.truncate(1);
+        self.path_.clear();
+        self.query_.clear();
+        self.matched_ = 0;
+    }
+
+    /// Bytes pushed so far
+    pub fn query(&self) -> &[u8] {
+        &self.query_[..]
+    }
+
+    /// Is the query a prefix of at least one key?
+    pub fn exists(&self) -> bool {
+        self.matched_ == self.query_.len()
+    }
+
+    /// Is the query itself a key?
+    pub fn is_key(&self) -> bool {
+        self.key_id().is_some()
+    }
+
+    /// Key ID of the query, if it is a key that hasn't been deleted
+    pub fn key_id(&self) -> Option<usize> {
+        if !self.exists() || self.matched_ != self.path_.len() {
+            return None;
+        }
+        let node_id = self.history_.last().unwrap().node_id_.0;
+        if !self.trie_.terminal_flags_.at(node_id) {
+            return None;
+        }
+        let id = self.trie_.key_id(node_id);
+        if self.trie_.is_deleted(id) { None } else { Some(id) }
+    }
+
+    /// The node the query has reached. If the query ends partway through a
+    /// label, this is the node the label belongs to.
+    pub fn node(&self) -> (NodeID, LoudsPos) {
+        let top = self.history_.last().unwrap();
+        (top.node_id_, top.louds_pos_)
+    }
+
+    /// All keys that start with the query, including the query itself if it
+    /// is a key.
+    pub fn completions(&self) -> Keys<'a, L> {
+        if !self.exists() {
+            return Keys::empty(self.trie_);
+        }
+        let top = self.history_.last().unwrap();
+        Keys::new(Nav::from_node(self.trie_, top.node_id_, top.louds_pos_,
+                                 &self.path_))
+    }
+
+    /// Find the child of `node_id` whose label starts with `c`, and push it
+    /// onto the path.
+    fn go_to_child(&mut self, node_id: NodeID, c: u8) -> bool {
+        let trie = self.trie_;
+        let (mut child_id, mut louds_pos) = match trie.child_pos(node_id) {
+            Some(x) => x,
+            None => { return false; }
+        };
+        loop {
+            let id = child_id.0;
+            self.label_buf_.clear();
+            trie.label_into(id, &mut self.label_buf_);
+            if self.label_buf_[0] == c {
+                let key_pos = self.path_.len();
+                assert!(key_pos <= std::u32::MAX as usize,
+                        "MARISA_SIZE_ERROR");
+                self.history_.push(Frame { node_id_: child_id,
+                                           louds_pos_: louds_pos,
+                                           key_pos_: key_pos as u32 });
+                self.path_.extend(&self.label_buf_);
+                return true;
+            }
+            if !trie.louds_.at(louds_pos.0 + 1) {
+                return false;
+            }
+            child_id = NodeID(child_id.0 + 1);
+            louds_pos = LoudsPos(louds_pos.0 + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use config::{Config, NumTries};
+    use key::{IKey, Key};
+    use super::super::LoudsTrie;
+
+    fn build(v: &Vec<String>, num_tries: NumTries) -> LoudsTrie {
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        LoudsTrie::build(&mut keys, &config)
+    }
+
+    fn cursor_prop(v: Vec<String>, num_tries: NumTries) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let trie = build(&v, num_tries);
+        let mut cursor = trie.cursor();
+        for s in v.iter() {
+            cursor.clear();
+            for (i, &c) in s.as_bytes().iter().enumerate() {
+                if !cursor.push(c) {
+                    return qc::TestResult::failed();
+                }
+                let prefix = &s.as_bytes()[..i + 1];
+                let mut expected: Vec<&[u8]> = v.iter().map(|x| x.as_bytes())
+                    .filter(|x| x.starts_with(prefix)).collect();
+                expected.sort();
+                expected.dedup();
+                let mut found: Vec<Vec<u8>> = cursor.completions()
+                    .map(|(key, id)| {
+                        assert!(trie.id_lookup(id) == key);
+                        key
+                    })
+                    .collect();
+                found.sort();
+                if !found.iter().map(|x| &x[..])
+                    .eq(expected.iter().cloned()) {
+                    return qc::TestResult::failed();
+                }
+            }
+            match cursor.key_id() {
+                Some(id) => {
+                    if trie.id_lookup(id) != s.as_bytes() {
+                        return qc::TestResult::failed();
+                    }
+                },
+                None => { return qc::TestResult::failed(); }
+            }
+            // Back out one byte at a time
+            for i in (0..s.len()).rev() {
+                if cursor.pop() != Some(s.as_bytes()[i]) || !cursor.exists() {
+                    return qc::TestResult::failed();
+                }
+                let is_key = v.iter().any(|x| x.as_bytes() == cursor.query());
+                if cursor.is_key() != is_key {
+                    return qc::TestResult::failed();
+                }
+            }
+            if cursor.pop().is_some() {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn cursor_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(cursor_prop as fn(Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn cursor_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "applesauce", "apply", "banana"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            assert!(!cursor_prop(v.clone(), NumTries::new(n)).is_failure());
+
+            let trie = build(&v, NumTries::new(n));
+            let mut cursor = trie.cursor();
+            for &c in b"applx".iter() {
+                cursor.push(c);
+            }
+            assert!(!cursor.exists());
+            assert!(cursor.completions().next().is_none());
+            assert!(cursor.push(b'q') == false);
+            assert!(cursor.pop() == Some(b'q'));
+            assert!(cursor.pop() == Some(b'x'));
+            assert!(cursor.exists());
+            assert!(!cursor.is_key());
+            assert!(cursor.completions().count() == 3);
+            assert!(cursor.push(b'e'));
+            assert!(cursor.is_key());
+            assert!(cursor.completions().count() == 2);
+        }
+
+        let mut keys: Vec<Key> = vec![Key::new(b"apple")];
+        let trie = LoudsTrie::build(&mut keys, &Config::new());
+        let mut cursor = trie.cursor();
+        assert!(cursor.push(b'a'));
+        assert!(cursor.push(b'p'));
+        assert!(cursor.key_id().is_none());
+        let found: Vec<(Vec<u8>, usize)> = cursor.completions().collect();
+        assert!(found == vec![(b"apple".to_vec(), keys[0].get_id())]);
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, BTreeSet};
+use config::Config;
+use super::{KeyArena, LoudsTrie};
+use super::id_map::IdMap;
+
+/// A static `LoudsTrie` with an in-memory layer of changes on top.
+///
+/// Keys of the base trie keep their IDs until the next `compact`. Inserted
+/// keys get IDs counting up from the base trie's number of keys, and removed
+/// base keys leave a tombstone behind, so an ID is never reused before
+/// compaction. Queries look at both layers and give the merged result.
+#[derive(Debug)]
+pub struct DeltaTrie {
+    base_: LoudsTrie,
+    config_: Config,
+    /// Keys that aren't in the base trie, with their IDs
+    added_: BTreeMap<Vec<u8>, usize>,
+    /// IDs of base keys that have been removed
+    removed_: BTreeSet<usize>,
+    next_id_: usize,
+}
+
+impl DeltaTrie {
+    /// `config` is used to rebuild the base trie on `compact`
+    pub fn new(base: LoudsTrie, config: &Config) -> DeltaTrie {
+        let next_id = base.len();
+        DeltaTrie { base_: base, config_: *config, added_: BTreeMap::new(),
+                    removed_: BTreeSet::new(), next_id_: next_id }
+    }
+
+    pub fn base(&self) -> &LoudsTrie {
+        &self.base_
+    }
+
+    /// Number of keys after applying the changes
+    pub fn len(&self) -> usize {
+        self.base_.len() - self.base_.num_deleted() - self.removed_.len()
+        + self.added_.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of changes since the last compaction
+    pub fn num_changes(&self) -> usize {
+        self.added_.len() + self.removed_.len()
+    }
+
+    /// Add a key and return its ID. A key that's already present keeps its
+    /// ID; a base key that was removed gets its old ID back.
+    pub fn insert(&mut self, key: &[u8]) -> usize {
+        if let Some(id) = self.base_.lookup(key) {
+            self.removed_.remove(&id);
+            return id;
+        }
+        if let Some(&id) = self.added_.get(key) {
+            return id;
+        }
+        let id = self.next_id_;
+        self.next_id_ += 1;
+        self.added_.insert(key.to_vec(), id);
+        id
+    }
+
+    /// Remove a key, returning the ID it had
+    pub fn remove(&mut self, key: &[u8]) -> Option<usize> {
+        match self.base_.lookup(key) {
+            Some(id) => {
+                if self.removed_.insert(id) { Some(id) } else { None }
+            },
+            None => self.added_.remove(key),
+        }
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> Option<usize> {
+        match self.base_.lookup(key) {
+            Some(id) => {
+                if self.removed_.contains(&id) { None } else { Some(id) }
+            },
+            None => self.added_.get(key).map(|&id| id),
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// All keys that start with `prefix` and their IDs, in label order
+    pub fn predictive_search(&self, prefix: &[u8]) -> Vec<(Vec<u8>, usize)> {
+        let mut out: Vec<(Vec<u8>, usize)> =
+            self.base_.predictive_search(prefix).into_iter()
+                .filter(|&(_, id)| !self.removed_.contains(&id)).collect();
+        let added = self.added_.range(prefix.to_vec()..)
+                        .take_while(|&(key, _)| key.starts_with(prefix));
+        out.extend(added.map(|(key, &id)| (key.clone(), id)));
+        out.sort();
+        out
+    }
+
+    /// All keys that are prefixes of `query` and their IDs, shortest first
+    pub fn common_prefix_search(&self, query: &[u8]) -> Vec<(Vec<u8>, usize)> {
+        let mut out: Vec<(Vec<u8>, usize)> = Vec::new();
+        let mut cursor = self.base_.cursor();
+        for i in 0..query.len() + 1 {
+            let key = &query[..i];
+            let id = match cursor.key_id() {
+                Some(id) if !self.removed_.contains(&id) => Some(id),
+                _ => self.added_.get(key).map(|&id| id),
+            };
+            if let Some(id) = id {
+                out.push((key.to_vec(), id));
+            }
+            if i < query.len() && cursor.exists() {
+                cursor.push(query[i]);
+            }
+        }
+        out
+    }
+
+    /// Rebuild the base trie with all changes applied and clear the changes.
+    ///
+    /// Returns how every ID handed out so far maps to the new IDs. Removed
+    /// keys are left unmapped.
+    pub fn compact(&mut self) -> IdMap {
+        let mut arena = KeyArena::new();
+        let mut old_ids: Vec<usize> = Vec::with_capacity(self.len());
+        {
+            let mut base_keys = self.base_.sorted_iter();
+            let mut added = self.added_.iter().peekable();
+            loop {
+                let next = base_keys.next_key();
+                // Added keys aren't in the base trie, so there are no ties
+                while let Some(&(key, &id)) = added.peek() {
+                    if next.map_or(false, |(base_key, _)| base_key < &key[..]) {
+                        break;
+                    }
+                    arena.push(key);
+                    old_ids.push(id);
+                    added.next();
+                }
+                match next {
+                    Some((key, id)) => {
+                        if !self.removed_.contains(&id) {
+                            arena.push(key);
+                            old_ids.push(id);
+                        }
+                    },
+                    None => { break; },
+                }
+            }
+        }
+        let config = self.config_;
+        let (base, id_map) = LoudsTrie::build_mapped(&arena, &old_ids,
+                                                     self.next_id_, &config);
+        *self = DeltaTrie::new(base, &config);
+        id_map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use std::collections::BTreeMap;
+    use config::{Config, NumTries};
+    use key::{IKey, Key};
+    use super::DeltaTrie;
+    use super::super::LoudsTrie;
+
+    /// Insert a key for each `(true, key)` and remove one for each
+    /// `(false, key)`, then check every query against a map
+    fn delta_prop(v: Vec<String>, ops: Vec<(bool, String)>,
+                  num_tries: NumTries) -> qc::TestResult {
+        if v.iter().chain(ops.iter().map(|x| &x.1)).any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let base = LoudsTrie::build(&mut keys, &config);
+        let mut model: BTreeMap<Vec<u8>, usize> =
+            keys.iter().map(|k| (k.get_slice().to_vec(), k.get_id()))
+            .collect();
+        let mut trie = DeltaTrie::new(base, &config);
+
+        let check = |trie: &DeltaTrie, model: &BTreeMap<Vec<u8>, usize>| {
+            if trie.len() != model.len() {
+                return false;
+            }
+            let all: Vec<(Vec<u8>, usize)> =
+                model.iter().map(|(k, &id)| (k.clone(), id)).collect();
+            if trie.predictive_search(b"") != all {
+                return false;
+            }
+            for (key, &id) in model.iter() {
+                if trie.lookup(key) != Some(id) {
+                    return false;
+                }
+                let prefix = &key[..key.len() / 2];
+                let expected: Vec<(Vec<u8>, usize)> = all.iter()
+                    .filter(|&&(ref k, _)| k.starts_with(prefix))
+                    .cloned().collect();
+                if trie.predictive_search(prefix) != expected {
+                    return false;
+                }
+                let expected: Vec<(Vec<u8>, usize)> = all.iter()
+                    .filter(|&&(ref k, _)| key.starts_with(k))
+                    .cloned().collect();
+                if trie.common_prefix_search(key) != expected {
+                    return false;
+                }
+            }
+            true
+        };
+
+        for &(insert, ref key) in ops.iter() {
+            let key = key.as_bytes();
+            if insert {
+                let id = trie.insert(key);
+                if *model.entry(key.to_vec()).or_insert(id) != id {
+                    return qc::TestResult::failed();
+                }
+            } else if trie.remove(key) != model.remove(key) {
+                return qc::TestResult::failed();
+            }
+            if trie.lookup(key) != model.get(key).cloned() {
+                return qc::TestResult::failed();
+            }
+        }
+        if !check(&trie, &model) {
+            return qc::TestResult::failed();
+        }
+
+        let id_map = trie.compact();
+        if trie.num_changes() != 0 || trie.base().len() != model.len() {
+            return qc::TestResult::failed();
+        }
+        for (key, id) in model.iter_mut() {
+            match id_map.new_id(*id) {
+                Some(new_id) => { *id = new_id; },
+                None => { return qc::TestResult::failed(); },
+            }
+            if trie.base().lookup(key) != Some(*id) {
+                return qc::TestResult::failed();
+            }
+        }
+        if (0..id_map.num_old()).filter(|&id| id_map.new_id(id).is_some())
+                                .count() != model.len() {
+            return qc::TestResult::failed();
+        }
+        qc::TestResult::from_bool(check(&trie, &model))
+    }
+
+    #[test]
+    fn delta_trie_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(delta_prop
+                       as fn(Vec<String>, Vec<(bool, String)>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn delta_trie_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "applesauce", "banana", "band"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let ops: Vec<(bool, String)> =
+            vec![(true, "apply"), (false, "banana"), (true, "app"),
+                 (false, "apply"), (true, "banana"), (false, "apple"),
+                 (true, "bandana"), (true, "bandana"), (false, "can")]
+            .iter().map(|&(insert, s)| (insert, s.to_owned())).collect();
+        for &n in [1, 2, 3].iter() {
+            assert!(!delta_prop(v.clone(), ops.clone(), NumTries::new(n))
+                     .is_failure());
+            assert!(!delta_prop(vec![], ops.clone(), NumTries::new(n))
+                     .is_failure());
+        }
+
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new();
+        let mut trie = DeltaTrie::new(LoudsTrie::build(&mut keys, &config),
+                                      &config);
+        let apple = trie.lookup(b"apple").unwrap();
+        assert!(trie.remove(b"apple") == Some(apple));
+        assert!(trie.remove(b"apple") == None);
+        assert!(trie.insert(b"apply") == 4);
+        assert!(trie.insert(b"apple") == apple);
+        assert!(trie.remove(b"band").is_some());
+        let id_map = trie.compact();
+        assert!(id_map.num_old() == 5);
+        assert!(id_map.new_id(keys.iter().find(|k| k.get_slice() == b"band")
+                              .unwrap().get_id()) == None);
+        assert!(trie.lookup(b"apply") == id_map.new_id(4));
+        assert!(trie.len() == 4);
+    }
+}
@@ -0,0 +1,192 @@
+use std::cmp::Ordering;
+use super::{LoudsBits, LoudsTrie};
+use super::nav::SortedKeys;
+use vector::bit_flags::BitFlags;
+
+/// One key's change between two versions of a dictionary
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiffEntry<'k> {
+    /// Only in the old trie
+    Removed { key: &'k [u8], old_id: usize },
+    /// Only in the new trie
+    Added { key: &'k [u8], new_id: usize },
+    /// In both tries. The IDs may differ.
+    Kept { key: &'k [u8], old_id: usize, new_id: usize },
+}
+
+impl<'k> DiffEntry<'k> {
+    pub fn key(&self) -> &'k [u8] {
+        match *self {
+            DiffEntry::Removed { key, .. } => key,
+            DiffEntry::Added { key, .. } => key,
+            DiffEntry::Kept { key, .. } => key,
+        }
+    }
+}
+
+/// The keys of two tries side by side, in label order.
+///
+/// Both tries are walked at once with `SortedKeys`, so neither key set is
+/// collected; memory use is bounded by the depth of the tries.
+#[derive(Debug)]
+pub struct Diff<'a, L: 'a = BitFlags> {
+    old_: SortedKeys<'a, L>,
+    new_: SortedKeys<'a, L>,
+    /// Key IDs of the keys `old_`/`new_` hold, if they hold one
+    old_id_: Option<usize>,
+    new_id_: Option<usize>,
+    /// Should `old_`/`new_` be advanced before the next comparison?
+    advance_old_: bool,
+    advance_new_: bool,
+}
+
+impl<'a, L: 'a + LoudsBits> Diff<'a, L> {
+    pub fn new(old: &'a LoudsTrie<L>, new: &'a LoudsTrie<L>) -> Diff<'a, L> {
+        Diff { old_: old.sorted_iter(), new_: new.sorted_iter(),
+               old_id_: None, new_id_: None, advance_old_: true,
+               advance_new_: true }
+    }
+
+    /// Advance to the next key in either trie, including keys that haven't
+    /// changed
+    pub fn next_entry(&mut self) -> Option<DiffEntry> {
+        if self.advance_old_ {
+            self.old_id_ = self.old_.next_key().map(|(_, id)| id);
+            self.advance_old_ = false;
+        }
+        if self.advance_new_ {
+            self.new_id_ = self.new_.next_key().map(|(_, id)| id);
+            self.advance_new_ = false;
+        }
+        let order = match (self.old_id_, self.new_id_) {
+            (Some(_), Some(_)) => self.old_.key().cmp(self.new_.key()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => { return None; },
+        };
+        match order {
+            Ordering::Less => {
+                self.advance_old_ = true;
+                Some(DiffEntry::Removed { key: self.old_.key(),
+                                          old_id: self.old_id_.unwrap() })
+            },
+            Ordering::Greater => {
+                self.advance_new_ = true;
+                Some(DiffEntry::Added { key: self.new_.key(),
+                                        new_id: self.new_id_.unwrap() })
+            },
+            Ordering::Equal => {
+                self.advance_old_ = true;
+                self.advance_new_ = true;
+                Some(DiffEntry::Kept { key: self.old_.key(),
+                                       old_id: self.old_id_.unwrap(),
+                                       new_id: self.new_id_.unwrap() })
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use std::collections::BTreeMap;
+    use config::{Config, IdOrder, NumTries};
+    use key::Key;
+    use super::DiffEntry;
+    use super::super::LoudsTrie;
+
+    fn build(v: &Vec<String>, num_tries: NumTries, id_order: IdOrder)
+      -> LoudsTrie {
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_id_order(id_order);
+        LoudsTrie::build(&mut keys, &config)
+    }
+
+    fn ids(trie: &LoudsTrie) -> BTreeMap<Vec<u8>, usize> {
+        trie.sorted_iter().collect()
+    }
+
+    fn diff_prop(old: Vec<String>, new: Vec<String>, num_tries: NumTries,
+                 label_ids: bool) -> qc::TestResult {
+        if old.iter().chain(new.iter()).any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let id_order = if label_ids { IdOrder::Label } else { IdOrder::Node };
+        let old_trie = build(&old, num_tries, id_order);
+        let new_trie = build(&new, num_tries, IdOrder::Node);
+        let old_ids = ids(&old_trie);
+        let new_ids = ids(&new_trie);
+
+        let mut expected: BTreeMap<Vec<u8>, (Option<usize>, Option<usize>)> =
+            BTreeMap::new();
+        for (key, &id) in old_ids.iter() {
+            expected.entry(key.clone()).or_insert((None, None)).0 = Some(id);
+        }
+        for (key, &id) in new_ids.iter() {
+            expected.entry(key.clone()).or_insert((None, None)).1 = Some(id);
+        }
+
+        let mut diff = old_trie.diff(&new_trie);
+        let mut found: Vec<(Vec<u8>, (Option<usize>, Option<usize>))> =
+            Vec::new();
+        while let Some(entry) = diff.next_entry() {
+            let ids = match entry {
+                DiffEntry::Removed { old_id, .. } => (Some(old_id), None),
+                DiffEntry::Added { new_id, .. } => (None, Some(new_id)),
+                DiffEntry::Kept { old_id, new_id, .. } => {
+                    (Some(old_id), Some(new_id))
+                },
+            };
+            found.push((entry.key().to_vec(), ids));
+        }
+        let expected: Vec<(Vec<u8>, (Option<usize>, Option<usize>))> =
+            expected.into_iter().collect();
+        qc::TestResult::from_bool(found == expected)
+    }
+
+    #[test]
+    fn diff_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(diff_prop
+                       as fn(Vec<String>, Vec<String>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn diff_manual() {
+        let _ = env_logger::init();
+        let old: Vec<String> = vec!["apple", "applesauce", "banana", "band"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let new: Vec<String> = vec!["apple", "apply", "band", "bandana"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &label_ids in [false, true].iter() {
+                assert!(!diff_prop(old.clone(), new.clone(), NumTries::new(n),
+                                   label_ids).is_failure());
+                assert!(!diff_prop(vec![], new.clone(), NumTries::new(n),
+                                   label_ids).is_failure());
+            }
+        }
+
+        let old_trie = build(&old, NumTries::new(3), IdOrder::Label);
+        let new_trie = build(&new, NumTries::new(3), IdOrder::Label);
+        let mut diff = old_trie.diff(&new_trie);
+        assert!(diff.next_entry() == Some(DiffEntry::Kept {
+            key: b"apple", old_id: 0, new_id: 0 }));
+        assert!(diff.next_entry() == Some(DiffEntry::Removed {
+            key: b"applesauce", old_id: 1 }));
+        assert!(diff.next_entry() == Some(DiffEntry::Added {
+            key: b"apply", new_id: 1 }));
+        assert!(diff.next_entry() == Some(DiffEntry::Removed {
+            key: b"banana", old_id: 2 }));
+        assert!(diff.next_entry() == Some(DiffEntry::Kept {
+            key: b"band", old_id: 3, new_id: 2 }));
+        assert!(diff.next_entry() == Some(DiffEntry::Added {
+            key: b"bandana", new_id: 3 }));
+        assert!(diff.next_entry() == None);
+        assert!(diff.next_entry() == None);
+    }
+}
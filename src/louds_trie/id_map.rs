@@ -0,0 +1,174 @@
+use std::io;
+use config::DictSize;
+use io::{Reader, Writer, format_error};
+use super::id_vec::IdVec;
+
+/// How key IDs of one trie map to the IDs of the same keys in another, in
+/// both directions.
+///
+/// Both directions are packed into vectors holding `id + 1`, with 0 for keys
+/// that only exist on one side, so a map between two tries of `n` keys takes
+/// about `2 * n * log2(n)` bits. The vectors are as wide as the `DictSize`
+/// the map is built with: with `DictSize::Standard` both tries need fewer
+/// than 2^32 keys.
+#[derive(Clone, Debug)]
+pub struct IdMap {
+    old_to_new_: IdVec,
+    new_to_old_: IdVec,
+}
+
+impl IdMap {
+    pub fn new() -> IdMap {
+        IdMap { old_to_new_: IdVec::new(), new_to_old_: IdVec::new() }
+    }
+
+    /// Build from the new ID of every old ID, where there are `num_new` new
+    /// IDs. No two old IDs may map to the same new ID.
+    pub fn from_old_to_new(old_to_new: &[Option<usize>], num_new: usize,
+                           dict_size: DictSize) -> IdMap {
+        let mut new_to_old: Vec<usize> = vec![0; num_new];
+        let packed: Vec<usize> = old_to_new.iter().enumerate()
+            .map(|(old_id, new_id)| match *new_id {
+                Some(new_id) => {
+                    assert!(new_to_old[new_id] == 0, "MARISA_CODE_ERROR");
+                    new_to_old[new_id] = old_id + 1;
+                    new_id + 1
+                },
+                None => 0,
+            }).collect();
+        IdMap { old_to_new_: IdVec::build(&packed, dict_size),
+                new_to_old_: IdVec::build(&new_to_old, dict_size) }
+    }
+
+    /// The identity on `n` IDs
+    pub fn identity(n: usize, dict_size: DictSize) -> IdMap {
+        let ids: Vec<Option<usize>> = (0..n).map(Some).collect();
+        IdMap::from_old_to_new(&ids, n, dict_size)
+    }
+
+    /// Whether the map is written as wide as a large dictionary
+    pub fn dict_size(&self) -> DictSize {
+        self.old_to_new_.dict_size()
+    }
+
+    pub fn num_old(&self) -> usize {
+        self.old_to_new_.len()
+    }
+    pub fn num_new(&self) -> usize {
+        self.new_to_old_.len()
+    }
+
+    /// The new ID of `old_id`, unless its key is gone
+    pub fn new_id(&self, old_id: usize) -> Option<usize> {
+        match self.old_to_new_.at(old_id) {
+            0 => None,
+            x => Some(x - 1),
+        }
+    }
+
+    /// The old ID of `new_id`, unless its key is new
+    pub fn old_id(&self, new_id: usize) -> Option<usize> {
+        match self.new_to_old_.at(new_id) {
+            0 => None,
+            x => Some(x - 1),
+        }
+    }
+
+    /// The same map the other way around
+    pub fn inverse(&self) -> IdMap {
+        IdMap { old_to_new_: self.new_to_old_.clone(),
+                new_to_old_: self.old_to_new_.clone() }
+    }
+
+    /// Read a map written with `dict_size`
+    pub fn read(reader: &mut Reader, dict_size: DictSize)
+      -> io::Result<IdMap> {
+        let old_to_new = try!(IdVec::read(reader, dict_size));
+        let new_to_old = try!(IdVec::read(reader, dict_size));
+        let out = IdMap { old_to_new_: old_to_new, new_to_old_: new_to_old };
+        for old_id in 0..out.num_old() {
+            let consistent = match out.new_id(old_id) {
+                Some(new_id) => {
+                    new_id < out.num_new() && out.old_id(new_id) == Some(old_id)
+                },
+                None => true,
+            };
+            if !consistent {
+                return Err(format_error("ID map"));
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(self.old_to_new_.write(writer));
+        self.new_to_old_.write(writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quickcheck as qc;
+    use config::DictSize;
+    use io::{Reader, Writer};
+    use super::IdMap;
+
+    fn id_map_prop(old_to_new: Vec<bool>, num_added: usize, large: bool)
+      -> bool {
+        let dict_size = if large { DictSize::Large }
+                        else { DictSize::Standard };
+        let num_added = num_added % 100;
+        // Keep old IDs in order, but leave out some and add some new ones
+        let mut next = 0;
+        let ids: Vec<Option<usize>> = old_to_new.iter().map(|&kept| {
+            if kept {
+                next += 1;
+                Some(next - 1)
+            } else {
+                None
+            }
+        }).collect();
+        let num_new = next + num_added;
+        let map = IdMap::from_old_to_new(&ids, num_new, dict_size);
+
+        let mut buf: Vec<u8> = Vec::new();
+        map.write(&mut Writer::new(&mut buf)).unwrap();
+        let read = IdMap::read(&mut Reader::new(&mut &buf[..]), dict_size)
+                   .unwrap();
+        for map in [&map, &read, &map.inverse().inverse()].iter() {
+            if map.num_old() != ids.len() || map.num_new() != num_new
+            || map.dict_size() != dict_size {
+                return false;
+            }
+            for (old_id, &new_id) in ids.iter().enumerate() {
+                if map.new_id(old_id) != new_id
+                || new_id.map_or(false, |x| map.old_id(x) != Some(old_id)) {
+                    return false;
+                }
+            }
+            if (next..num_new).any(|new_id| map.old_id(new_id).is_some()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn id_map_qc() {
+        qc::quickcheck(id_map_prop as fn(Vec<bool>, usize, bool) -> bool);
+    }
+
+    #[test]
+    fn id_map_manual() {
+        let map = IdMap::from_old_to_new(&[Some(2), None, Some(0)], 4,
+                                         DictSize::Standard);
+        assert!(map.new_id(0) == Some(2) && map.new_id(1) == None);
+        assert!(map.old_id(0) == Some(2) && map.old_id(1) == None);
+        assert!(map.old_id(3) == None);
+        let inverse = map.inverse();
+        assert!(inverse.num_old() == 4 && inverse.new_id(2) == Some(0));
+        assert!(IdMap::identity(5, DictSize::Standard).new_id(4) == Some(4));
+        assert!(id_map_prop(vec![true, false, true], 2, false));
+        assert!(id_map_prop(vec![true, false, true], 2, true));
+    }
+}
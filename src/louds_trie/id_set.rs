@@ -0,0 +1,126 @@
+use std::ops::Range;
+use vector::packed_vec::PackedVec;
+
+/// A set of key IDs, as returned by `LoudsTrie::id_range_for_prefix`
+#[derive(Clone, Debug)]
+pub enum IdSet {
+    /// Every ID in the range. Always the case with `IdOrder::Label`.
+    Range(Range<usize>),
+
+    /// IDs in ascending order, for when they aren't contiguous. Packed just
+    /// wide enough for the largest.
+    Sorted(PackedVec<u64>),
+}
+
+impl IdSet {
+    /// Takes a range if it can, otherwise packs the IDs
+    pub fn from_sorted(ids: &[usize]) -> IdSet {
+        debug_assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        match (ids.first(), ids.last()) {
+            (Some(&first), Some(&last)) if last - first + 1 == ids.len() => {
+                IdSet::Range(first..last + 1)
+            },
+            (None, _) | (_, None) => IdSet::Range(0..0),
+            _ => {
+                let ids: Vec<u64> = ids.iter().map(|&id| id as u64).collect();
+                IdSet::Sorted(PackedVec::build(&ids))
+            },
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        match self {
+            &IdSet::Range(ref r) => r.start <= id && id < r.end,
+            &IdSet::Sorted(ref ids) => {
+                let id = id as u64;
+                // Binary search
+                let (mut begin, mut end) = (0, ids.len());
+                while begin < end {
+                    let middle = begin + (end - begin) / 2;
+                    let x = ids.at(middle);
+                    if x == id {
+                        return true;
+                    } else if x < id {
+                        begin = middle + 1;
+                    } else {
+                        end = middle;
+                    }
+                }
+                false
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn len(&self) -> usize {
+        match self {
+            &IdSet::Range(ref r) => r.end - r.start,
+            &IdSet::Sorted(ref ids) => ids.len(),
+        }
+    }
+
+    /// IDs in ascending order
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=usize> + 'a> {
+        match self {
+            &IdSet::Range(ref r) => Box::new(r.clone()),
+            &IdSet::Sorted(ref ids) => {
+                Box::new(ids.iter().map(|id| id as usize))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quickcheck as qc;
+    use std;
+    use super::IdSet;
+
+    fn id_set_prop(ids: Vec<usize>) -> qc::TestResult {
+        let mut ids: Vec<usize> = ids.iter().map(|&x| x % 1000).collect();
+        ids.sort();
+        ids.dedup();
+        let set = IdSet::from_sorted(&ids);
+        if set.len() != ids.len()
+        || !set.iter().eq(ids.iter().cloned()) {
+            return qc::TestResult::failed();
+        }
+        for x in 0..1001 {
+            if set.contains(x) != ids.binary_search(&x).is_ok() {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn id_set_qc() {
+        qc::quickcheck(id_set_prop as fn(Vec<usize>) -> qc::TestResult);
+    }
+
+    #[test]
+    fn id_set_manual() {
+        match IdSet::from_sorted(&[3, 4, 5]) {
+            IdSet::Range(r) => assert!(r == (3..6)),
+            _ => panic!("expected a range"),
+        }
+        match IdSet::from_sorted(&[3, 5]) {
+            IdSet::Sorted(_) => {},
+            _ => panic!("expected a sorted set"),
+        }
+        assert!(IdSet::from_sorted(&[]).is_empty());
+        assert!(!id_set_prop(vec![0, 1, 2, 999]).is_failure());
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn id_set_wide() {
+        let wide = std::u32::MAX as usize + 5;
+        let set = IdSet::from_sorted(&[7, wide]);
+        // Truncated to 32 bits, `wide` would be 4
+        assert!(set.contains(wide) && !set.contains(4));
+        assert!(set.iter().eq(vec![7, wide].into_iter()));
+    }
+}
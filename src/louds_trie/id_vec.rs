@@ -0,0 +1,123 @@
+use std;
+use std::io;
+use config::DictSize;
+use io::{Reader, Writer};
+use vector::flat_vec::FlatVec;
+use vector::packed_vec::PackedVec;
+
+/// Static vector of key IDs or key counts. Standard dictionaries keep these
+/// in a `FlatVec`, as they have always been written. Large ones may have
+/// more than 2^32 keys, so they use a `PackedVec<u64>` instead.
+#[derive(Clone, Debug)]
+pub enum IdVec {
+    Flat(FlatVec),
+    Wide(PackedVec<u64>),
+}
+
+impl IdVec {
+    pub fn new() -> IdVec {
+        IdVec::Flat(FlatVec::new())
+    }
+
+    /// With `DictSize::Standard`, every value must fit in 32 bits
+    pub fn build(values: &[usize], dict_size: DictSize) -> IdVec {
+        match dict_size {
+            DictSize::Standard => {
+                let values: Vec<u32> = values.iter().map(|&x| {
+                    assert!(x <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
+                    x as u32
+                }).collect();
+                IdVec::Flat(FlatVec::from_values(&values))
+            },
+            DictSize::Large => {
+                let values: Vec<u64> = values.iter().map(|&x| x as u64)
+                                       .collect();
+                IdVec::Wide(PackedVec::build(&values))
+            },
+        }
+    }
+
+    pub fn at(&self, i: usize) -> usize {
+        match *self {
+            IdVec::Flat(ref x) => x.at(i) as usize,
+            IdVec::Wide(ref x) => x.at(i) as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn len(&self) -> usize {
+        match *self {
+            IdVec::Flat(ref x) => x.len(),
+            IdVec::Wide(ref x) => x.len(),
+        }
+    }
+
+    pub fn dict_size(&self) -> DictSize {
+        match *self {
+            IdVec::Flat(_) => DictSize::Standard,
+            IdVec::Wide(_) => DictSize::Large,
+        }
+    }
+
+    pub fn read(reader: &mut Reader, dict_size: DictSize)
+      -> io::Result<IdVec> {
+        match dict_size {
+            DictSize::Standard => Ok(IdVec::Flat(try!(FlatVec::read(reader)))),
+            DictSize::Large => Ok(IdVec::Wide(try!(PackedVec::read(reader)))),
+        }
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        match *self {
+            IdVec::Flat(ref x) => x.write(writer),
+            IdVec::Wide(ref x) => x.write(writer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std;
+    use quickcheck as qc;
+    use config::DictSize;
+    use io::{Reader, Writer};
+    use super::IdVec;
+
+    fn id_vec_prop(values: Vec<usize>, large: bool) -> bool {
+        let dict_size = if large { DictSize::Large }
+                        else { DictSize::Standard };
+        // Standard vectors only take 32-bit values
+        let values: Vec<usize> = if large { values } else {
+            values.iter().map(|&x| x as u32 as usize).collect()
+        };
+        let v = IdVec::build(&values, dict_size);
+        let mut buf: Vec<u8> = Vec::new();
+        v.write(&mut Writer::new(&mut buf)).unwrap();
+        let read = IdVec::read(&mut Reader::new(&mut &buf[..]), dict_size)
+                   .unwrap();
+        [&v, &read].iter().all(|v| {
+            v.dict_size() == dict_size && v.len() == values.len()
+            && values.iter().enumerate().all(|(i, &x)| v.at(i) == x)
+        })
+    }
+
+    #[test]
+    fn id_vec_qc() {
+        qc::quickcheck(id_vec_prop as fn(Vec<usize>, bool) -> bool);
+    }
+
+    #[test]
+    fn id_vec_manual() {
+        let big = std::u32::MAX as usize + 1;
+        assert!(id_vec_prop(vec![0, big, 3], true));
+        assert!(id_vec_prop(vec![], false));
+    }
+
+    #[test]
+    #[should_panic(expected = "MARISA_SIZE_ERROR")]
+    fn id_vec_too_big_for_standard() {
+        IdVec::build(&[std::u32::MAX as usize + 1], DictSize::Standard);
+    }
+}
@@ -1,36 +1,77 @@
 use std;
 use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
 
+use base::BuildIndex;
 use cache::Cache;
-use config::Config;
+use config::{Config, CONFIG_MASK};
 use config::CacheLevel;
+use config::DictSize;
+use config::IdOrder;
 use config::NodeOrder;
+use config::SubtreeCounts;
 use config::TailMode;
 use entry::Entry;
+use header::Header;
+use io::{Reader, Writer, format_error};
 use range::Range;
 use range::WeightedRange;
 use key::IKey;
 use key::Key;
 use key::ReverseKey;
+use louds_trie::cursor::Cursor;
+use louds_trie::diff::{Diff, DiffEntry};
+use louds_trie::id_map::IdMap;
+use louds_trie::id_set::IdSet;
+use louds_trie::id_vec::IdVec;
+use louds_trie::nav::Keys;
+use louds_trie::nav::Nav;
+use louds_trie::nav::SortedKeys;
+use louds_trie::pinned::PinnedTrie;
+use louds_trie::set_ops::{SetOp, SetOpKeys};
 use louds_trie::tail::Tail;
-use vector::bit_vec::BitVec;
+use vector::bit_flags::BitFlags;
+use vector::bit_vec::{BitVec, RankLayout, SelectInterval};
+use vector::rank_select::RankSelect;
 use vector::flat_vec::FlatVec;
-
+use vector::packed_vec::PackedVec;
+use vector::sparse_bit_vec::SparseBitVec;
+
+pub mod cursor;
+pub mod delta;
+pub mod diff;
+pub mod id_map;
+pub mod id_set;
+mod id_vec;
 pub mod nav;
+pub mod pinned;
+pub mod set_ops;
 mod tail;
 
 pub const INVALID_EXTRA: u32 = std::u32::MAX >> 8;
 
+/// Set in the config flags of a written trie that has deleted keys. Never
+/// part of `config_`.
+const DELETED_FLAG: u32 = 0x10000000;
+
+/// Written before each bit vector of a large dictionary, saying which form
+/// follows
+const PLAIN_BITS: u64 = 0;
+const SPARSE_BITS: u64 = 1;
+
 #[derive(Clone, Copy, Debug)]
-pub struct LoudsPos(u32);
+pub struct LoudsPos(usize);
 
 #[derive(Clone, Copy, Debug)]
-pub struct NodeID(u32);
+pub struct NodeID(usize);
 
 #[derive(Clone, Copy, Debug)]
-pub struct LinkID(u32);
+pub struct LinkID(usize);
 
-pub const INVALID_LINK_ID: LinkID = LinkID(std::u32::MAX);
+pub const INVALID_LINK_ID: LinkID = LinkID(std::usize::MAX);
 
 /// Recursive LOUDS trie
 ///
@@ -91,23 +132,24 @@ pub const INVALID_LINK_ID: LinkID = LinkID(std::u32::MAX);
 /// 'louds_pos' variables refer to bit indexes in 'louds_'
 ///
 #[derive(Debug)]
-pub struct LoudsTrie {
+pub struct LoudsTrie<L = BitFlags> {
     /// The tree structure
-    louds_: BitVec,
+    louds_: L,
 
     /// Bit vector of terminal-ness per node id. Indexed by node. Can be used to
-    /// retrieve NodeID from user-facing word ID:
+    /// retrieve NodeID from user-facing word ID (with `IdOrder::Node`):
     ///
     ///     let node_id = NodeID(self.terminal_flags_.select1(id));
     ///
-    terminal_flags_: BitVec,
+    /// Use `key_node` and `key_id` to convert in either ID order.
+    terminal_flags_: L,
 
     /// Per node, does this node have a link to another trie? Indexed by node.
     /// LinkIDs are assigned sequentially to nodes that have links, so
     ///
     ///     let link_id = LinkID(self.link_flags_.rank1(node_id))
     ///
-    link_flags_: BitVec,
+    link_flags_: L,
 
     /// Base characters, limited to one per node. Indexed by node. Present if
     /// link_flags_[node_id] is false.
@@ -124,64 +166,274 @@ pub struct LoudsTrie {
     tail_: Tail,
 
     /// Next trie
-    next_trie_: Option<Box<LoudsTrie> >,
+    next_trie_: Option<Box<LoudsTrie<L>> >,
 
     cache_: Vec<Cache>,
     cache_mask_: usize,
     num_l1_nodes_: usize,
 
+    /// Number of keys in each node's subtree, counting the node itself.
+    /// Indexed by node. Only built for the root trie, and only with
+    /// `SubtreeCounts::On`.
+    subtree_counts_: IdVec,
+
+    /// Number of deleted keys in each node's subtree, kept up to date by
+    /// `delete_id` so that counts and ranks can subtract them on the way
+    /// down. Only with `SubtreeCounts::On`, and empty until the first
+    /// deletion.
+    deleted_counts_: PackedVec<u64>,
+
+    /// With `IdOrder::Label`, key ID by terminal index (`rank1` of the node in
+    /// `terminal_flags_`), and the reverse. Empty otherwise.
+    terminal_ids_: IdVec,
+    id_terminals_: IdVec,
+
+    /// Deleted keys, indexed by key ID. Only for the root trie, and empty
+    /// until the first deletion.
+    deleted_: BitVec,
+
     config_: Config,
 //    mapper_: Mapper,
 }
 
-trait CallBuildNextTrie {
-    fn build_next_trie(&mut self, louds_trie: &mut LoudsTrie,
-                       terminals: &mut Vec<u32>, config: &mut Config,
-                       trie_id: usize);
+/// Reusable buffers for restoring keys. See `LoudsTrie::id_lookup_into`.
+#[derive(Clone, Debug, Default)]
+pub struct KeyBuf {
+    key_: Vec<u8>,
+    /// Scratch space for the path from a terminal node up to the root
+    path_: Vec<usize>,
+}
+
+impl KeyBuf {
+    pub fn new() -> KeyBuf {
+        KeyBuf { key_: Vec::new(), path_: Vec::new() }
+    }
+    pub fn get_u8(&self) -> &[u8] {
+        &self.key_[..]
+    }
+    pub fn is_empty(&self) -> bool {
+        self.key_.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.key_.len()
+    }
+    pub fn clear(&mut self) {
+        self.key_.clear();
+        self.path_.clear();
+    }
+}
+
+/// Keys restored by `LoudsTrie::id_lookup_batch`, stored back to back in one
+/// buffer. Key `i` is `bytes()[offsets()[i]..offsets()[i + 1]]`.
+#[derive(Clone, Debug, Default)]
+pub struct KeyArena {
+    buf_: Vec<u8>,
+    /// One more than the number of keys; starts with 0
+    offsets_: Vec<usize>,
+
+    // Scratch space, kept around so repeated batches don't allocate
+    order_: Vec<usize>,
+    spans_: Vec<(usize, usize)>,
+    sorted_buf_: Vec<u8>,
+    key_buf_: KeyBuf,
+}
+
+impl KeyArena {
+    pub fn new() -> KeyArena {
+        KeyArena { buf_: Vec::new(), offsets_: vec![0], order_: Vec::new(),
+                   spans_: Vec::new(), sorted_buf_: Vec::new(),
+                   key_buf_: KeyBuf::new() }
+    }
+    pub fn get(&self, i: usize) -> &[u8] {
+        assert!(i < self.len(), "MARISA_BOUND_ERROR");
+        &self.buf_[self.offsets_[i]..self.offsets_[i + 1]]
+    }
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf_[..]
+    }
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets_[..]
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn len(&self) -> usize {
+        self.offsets_.len() - 1
+    }
+    pub fn clear(&mut self) {
+        self.buf_.clear();
+        self.offsets_.clear();
+        self.offsets_.push(0);
+    }
+    pub fn push(&mut self, key: &[u8]) {
+        self.buf_.extend_from_slice(key);
+        self.offsets_.push(self.buf_.len());
+    }
+}
+
+/// Bit vectors that can hold the tree structure and flags of a `LoudsTrie`.
+/// Each is built as a `BitVec`, with select0 enabled only for the root trie's
+/// structure, then converted.
+///
+/// Standard dictionaries write them back as that `BitVec`, so the file
+/// format is the same whatever `L` is and marisa-trie can read it. Large
+/// dictionaries keep sparse vectors in their Elias-Fano form, so those files
+/// shrink too; any `L` can read either form.
+pub trait LoudsBits: RankSelect + From<BitVec> + std::fmt::Debug + Sized {
+    /// Read a `BitVec` and convert it
+    fn read_bits(reader: &mut Reader) -> io::Result<Self> {
+        Ok(Self::from(try!(BitVec::read(reader))))
+    }
+    /// Write the `BitVec` this was converted from
+    fn write_bits(&self, writer: &mut Writer) -> io::Result<()>;
+    /// Rank layout of that `BitVec`
+    fn layout(&self) -> RankLayout;
+    /// Select interval of that `BitVec`
+    fn select_interval(&self) -> SelectInterval;
+    /// The sparse form, if this is held in one
+    fn as_sparse(&self) -> Option<&SparseBitVec>;
+    /// Convert a sparse vector read from a large dictionary
+    fn from_sparse(sparse: SparseBitVec) -> Self;
+
+    /// Read what `write_for` wrote for `dict_size`
+    fn read_for(reader: &mut Reader, dict_size: DictSize) -> io::Result<Self> {
+        if dict_size == DictSize::Standard {
+            return Self::read_bits(reader);
+        }
+        match try!(reader.read_u64()) {
+            PLAIN_BITS => Self::read_bits(reader),
+            SPARSE_BITS => {
+                Ok(Self::from_sparse(try!(SparseBitVec::read_sparse(reader))))
+            },
+            _ => Err(format_error("bit vector form")),
+        }
+    }
+    /// Write with `write_bits` in a standard dictionary. In a large one, tag
+    /// the form, and write sparse vectors with `SparseBitVec::write_sparse`.
+    fn write_for(&self, writer: &mut Writer, dict_size: DictSize)
+      -> io::Result<()> {
+        if dict_size == DictSize::Standard {
+            return self.write_bits(writer);
+        }
+        match self.as_sparse() {
+            Some(sparse) => {
+                try!(writer.write_u64(SPARSE_BITS));
+                sparse.write_sparse(writer)
+            },
+            None => {
+                try!(writer.write_u64(PLAIN_BITS));
+                self.write_bits(writer)
+            },
+        }
+    }
+}
+
+impl LoudsBits for BitVec {
+    fn write_bits(&self, writer: &mut Writer) -> io::Result<()> {
+        self.write(writer)
+    }
+    fn layout(&self) -> RankLayout {
+        BitVec::layout(self)
+    }
+    fn select_interval(&self) -> SelectInterval {
+        BitVec::select_interval(self)
+    }
+    fn as_sparse(&self) -> Option<&SparseBitVec> {
+        None
+    }
+    fn from_sparse(sparse: SparseBitVec) -> BitVec {
+        sparse.to_plain()
+    }
+}
+
+impl LoudsBits for BitFlags {
+    fn write_bits(&self, writer: &mut Writer) -> io::Result<()> {
+        self.write(writer)
+    }
+    fn layout(&self) -> RankLayout {
+        BitFlags::layout(self)
+    }
+    fn select_interval(&self) -> SelectInterval {
+        BitFlags::select_interval(self)
+    }
+    fn as_sparse(&self) -> Option<&SparseBitVec> {
+        match *self {
+            BitFlags::Plain(_) => None,
+            BitFlags::Sparse(ref sparse) => Some(sparse),
+        }
+    }
+    fn from_sparse(sparse: SparseBitVec) -> BitFlags {
+        BitFlags::Sparse(sparse)
+    }
+}
+
+impl LoudsBits for SparseBitVec {
+    fn write_bits(&self, writer: &mut Writer) -> io::Result<()> {
+        self.write(writer)
+    }
+    fn layout(&self) -> RankLayout {
+        SparseBitVec::layout(self)
+    }
+    fn select_interval(&self) -> SelectInterval {
+        SparseBitVec::select_interval(self)
+    }
+    fn as_sparse(&self) -> Option<&SparseBitVec> {
+        Some(self)
+    }
+    fn from_sparse(sparse: SparseBitVec) -> SparseBitVec {
+        sparse
+    }
+}
+
+trait CallBuildNextTrie<I> {
+    fn build_next_trie<L: LoudsBits>(
+        &mut self, louds_trie: &mut LoudsTrie<L>, terminals: &mut Vec<I>,
+        config: &mut Config, trie_id: usize);
 }
 
-impl<'a> CallBuildNextTrie for Vec<Key<'a>> {
-    fn build_next_trie(&mut self, louds_trie: &mut LoudsTrie,
-                       terminals: &mut Vec<u32>, config: &mut Config,
-                       trie_id: usize) {
+impl<'a, I: BuildIndex> CallBuildNextTrie<I> for Vec<Key<'a, I>> {
+    fn build_next_trie<L: LoudsBits>(
+        &mut self, louds_trie: &mut LoudsTrie<L>, terminals: &mut Vec<I>,
+        config: &mut Config, trie_id: usize) {
         louds_trie.build_next_trie_fwd(self, terminals, config, trie_id);
     }
 }
 
-impl<'a> CallBuildNextTrie for Vec<ReverseKey<'a>> {
-    fn build_next_trie(&mut self, louds_trie: &mut LoudsTrie,
-                       terminals: &mut Vec<u32>, config: &mut Config,
-                       trie_id: usize) {
+impl<'a, I: BuildIndex> CallBuildNextTrie<I> for Vec<ReverseKey<'a, I>> {
+    fn build_next_trie<L: LoudsBits>(
+        &mut self, louds_trie: &mut LoudsTrie<L>, terminals: &mut Vec<I>,
+        config: &mut Config, trie_id: usize) {
         louds_trie.build_next_trie_rev(self, terminals, config, trie_id);
     }
 }
 
 trait CallCache {
-    fn cache(&self, louds_trie: &mut LoudsTrie, parent: usize, child: usize,
-             weight: f32, label: u8);
+    fn cache<L: LoudsBits>(&self, louds_trie: &mut LoudsTrie<L>,
+                           parent: usize, child: usize, weight: f32, label: u8);
 }
 
-impl<'a> CallCache for Vec<Key<'a>> {
-    fn cache(&self, louds_trie: &mut LoudsTrie, parent: usize, child: usize,
-             weight: f32, label: u8) {
+impl<'a, I: BuildIndex> CallCache for Vec<Key<'a, I>> {
+    fn cache<L: LoudsBits>(&self, louds_trie: &mut LoudsTrie<L>,
+                           parent: usize, child: usize, weight: f32,
+                           label: u8) {
         louds_trie.cache_fwd(parent, child, weight, label);
     }
 }
 
-impl<'a> CallCache for Vec<ReverseKey<'a>> {
-    fn cache(&self, louds_trie: &mut LoudsTrie, parent: usize, child: usize,
-             weight: f32, _: u8) {
+impl<'a, I: BuildIndex> CallCache for Vec<ReverseKey<'a, I>> {
+    fn cache<L: LoudsBits>(&self, louds_trie: &mut LoudsTrie<L>,
+                           parent: usize, child: usize, weight: f32, _: u8) {
         louds_trie.cache_rev(parent, child, weight);
     }
 }
 
-impl LoudsTrie {
+impl<L: LoudsBits> LoudsTrie<L> {
     // We shouldn't expose this. Clients can just use build, map, and read.
-    fn new() -> LoudsTrie {
-        LoudsTrie { 
-            louds_: BitVec::new(),
-            terminal_flags_: BitVec::new(),
-            link_flags_: BitVec::new(),
+    fn new() -> LoudsTrie<L> {
+        LoudsTrie {
+            louds_: L::from(BitVec::new()),
+            terminal_flags_: L::from(BitVec::new()),
+            link_flags_: L::from(BitVec::new()),
             bases_: Vec::new(),
             extras_: FlatVec::new(),
             tail_: Tail::new(),
@@ -189,6 +441,11 @@ impl LoudsTrie {
             cache_: Vec::new(),
             cache_mask_: 0,
             num_l1_nodes_: 0,
+            subtree_counts_: IdVec::new(),
+            deleted_counts_: PackedVec::new(),
+            terminal_ids_: IdVec::new(),
+            id_terminals_: IdVec::new(),
+            deleted_: BitVec::new(),
             config_: Config::new(),
             // mapper: Mapper::new(),
         }
@@ -210,71 +467,177 @@ impl LoudsTrie {
         debug!("  pre-select0. node_id: {:?}", node_id);
         debug!("  louds: {:?}", self.louds_);
 //            node_id = self.louds_.select1(node_id) - node_id - 1;
-        let child_louds_pos = self.louds_.select0(node_id.0 as usize) + 1;
+        let child_louds_pos = self.louds_.select0(node_id.0) + 1;
         debug!("  pre-at. child_louds_pos: {:?}", child_louds_pos);
         if self.louds_.at(child_louds_pos) {
-            let child_node_id = child_louds_pos - node_id.0 as usize - 1;
-            assert!(child_node_id <= std::u32::MAX as usize);
-            assert!(child_louds_pos <= std::u32::MAX as usize);
-            Some((NodeID(child_node_id as u32),
-                  LoudsPos(child_louds_pos as u32)))
+            let child_node_id = child_louds_pos - node_id.0 - 1;
+            Some((NodeID(child_node_id), LoudsPos(child_louds_pos)))
         } else {
             None
         }
     }
 
-    pub fn build<'a>(keys: &mut Vec<Key<'a> >, config: &Config) -> LoudsTrie {
+    /// Like `build`, but with the tree structure and flags in another kind
+    /// of bit vector. The rest of the trie is the same whatever `L` is.
+    pub fn build_with_louds<'a, I: BuildIndex>(keys: &mut Vec<Key<'a, I>>,
+                                               config: &Config)
+      -> LoudsTrie<L> {
+        // Node IDs and tail offsets are bounded by the key bytes plus one
+        // terminator per key, and IDs by the number of keys
+        let num_bytes = keys.iter().fold(keys.len(), |sum, key| {
+            sum.saturating_add(key.len())
+        });
+        if config.dict_size() == DictSize::Standard
+        && num_bytes.saturating_mul(2) < std::u32::MAX as usize {
+            LoudsTrie::build_with_index::<I, u32>(keys, config)
+        } else {
+            LoudsTrie::build_with_index::<I, usize>(keys, config)
+        }
+    }
+
+    /// `build_with_louds`, with `W` for the IDs, terminals and offsets of the
+    /// keys copied for the build
+    fn build_with_index<'a, I: BuildIndex, W: BuildIndex>(
+        keys: &mut Vec<Key<'a, I>>, config: &Config) -> LoudsTrie<L> {
+        let subtree_counts = config.subtree_counts();
+        let id_order = config.id_order();
+        let dict_size = config.dict_size();
+        let select_interval = config.select_interval();
+        let rank_block = config.rank_block();
+        let rank_layout = config.rank_layout();
         let mut config = *config;
         let mut out = LoudsTrie::new();
 
-        let mut keys_cpy = keys.clone();
-        let mut terminals: Vec<u32> = Vec::new();
+        let mut keys_cpy: Vec<Key<W>> = keys.iter().map(Key::from_key)
+                                        .collect();
+        let mut terminals: Vec<W> = Vec::new();
         out.build_trie(&mut keys_cpy, &mut terminals, &mut config, 1);
 
-        let mut pairs: Vec<(u32, u32)> = Vec::new();
-        let mut pairs: Vec<(u32, u32)> = terminals.iter().enumerate()
-                                         .map(|(i, &x)| (x, i as u32))
-                                         .collect();
+        let mut pairs: Vec<(W, W)> = terminals.iter().enumerate()
+                                     .map(|(i, &x)| (x, W::from_usize(i)))
+                                     .collect();
         terminals.clear();
         pairs.sort();
 
-        // FIXME: Clean up this usize/u32 situation.
-
+        let mut terminal_flags = BitVec::new();
         let mut node_id: usize = 0;
         for pair in &pairs {
-            while node_id < pair.0 as usize {
-                out.terminal_flags_.push(false);
+            while node_id < pair.0.to_usize() {
+                terminal_flags.push(false);
                 node_id += 1;
             }
-            if node_id == pair.0 as usize {
-                out.terminal_flags_.push(true);
+            if node_id == pair.0.to_usize() {
+                terminal_flags.push(true);
                 node_id += 1;
             }
         }
         while node_id < out.bases_.len() {
-            out.terminal_flags_.push(false);
+            terminal_flags.push(false);
             node_id += 1;
         }
-        out.terminal_flags_.push(false);
-        out.terminal_flags_.build(false, true);
+        terminal_flags.push(false);
+        let layout = rank_layout.fit(terminal_flags.len());
+        terminal_flags.build_with_options(false, true, layout,
+                                          select_interval, rank_block);
+        out.terminal_flags_ = L::from(terminal_flags);
 
         assert!(pairs.len() == keys.len());
         for pair in &pairs {
-            keys[pair.1 as usize].set_id(
-                out.terminal_flags_.rank1(pair.0 as usize));
+            keys[pair.1.to_usize()]
+                .set_id(out.terminal_flags_.rank1(pair.0.to_usize()));
+        }
+
+        // build_trie only passes on the flags the next tries care about
+        let wide = rank_layout == RankLayout::Wide;
+        let dict_size = if wide || out.needs_large() { DictSize::Large }
+                        else { dict_size };
+        out.config_.set_dict_size(dict_size);
+        out.config_.set_subtree_counts(subtree_counts);
+        if subtree_counts == SubtreeCounts::On {
+            out.build_subtree_counts();
+        }
+        out.config_.set_id_order(id_order);
+        if id_order == IdOrder::Label {
+            out.build_label_ids(keys);
         }
         out
     }
 
-    fn build_trie<'a, T>(
-        &mut self, keys: &mut Vec<T>, terminals: &mut Vec<u32>,
+    /// Is any part of this trie or the ones after it too big for marisa-trie's
+    /// format, or sampled for select at an interval it would misread?
+    fn needs_large(&self) -> bool {
+        let interval = SelectInterval::default();
+        self.louds_.layout() == RankLayout::Wide
+        || self.terminal_flags_.layout() == RankLayout::Wide
+        || self.link_flags_.layout() == RankLayout::Wide
+        || self.louds_.select_interval() != interval
+        || self.terminal_flags_.select_interval() != interval
+        || self.link_flags_.select_interval() != interval
+        || self.tail_.len() > std::u32::MAX as usize
+        || self.next_trie_.as_ref().map_or(false, |x| x.needs_large())
+    }
+
+    /// `DictSize::Large` if the trie was built that way or had to be
+    pub fn dict_size(&self) -> DictSize {
+        self.config_.dict_size()
+    }
+
+    /// Renumber keys by sorted order. `keys` comes in with IDs set to terminal
+    /// indexes.
+    fn build_label_ids<'a, I: BuildIndex>(&mut self,
+                                          keys: &mut Vec<Key<'a, I>>) {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].get_slice().cmp(keys[b].get_slice()));
+
+        let mut terminal_ids: Vec<usize> = vec![0; self.len()];
+        let mut id_terminals: Vec<usize> = Vec::with_capacity(self.len());
+        for &i in order.iter() {
+            let terminal = keys[i].get_id();
+            // Duplicates are next to each other, and share a terminal
+            if id_terminals.last() != Some(&terminal) {
+                terminal_ids[terminal] = id_terminals.len();
+                id_terminals.push(terminal);
+            }
+            keys[i].set_id(terminal_ids[terminal]);
+        }
+        assert!(id_terminals.len() == self.len());
+        let dict_size = self.dict_size();
+        self.terminal_ids_ = IdVec::build(&terminal_ids, dict_size);
+        self.id_terminals_ = IdVec::build(&id_terminals, dict_size);
+    }
+
+    fn build_subtree_counts(&mut self) {
+        let num_nodes = self.bases_.len();
+        let mut counts: Vec<usize> = vec![0; num_nodes];
+        // Children always come after their parents, so by the time we get to
+        // a node, everything below it has been added in.
+        for node_id in (0..num_nodes).rev() {
+            if self.terminal_flags_.at(node_id) {
+                counts[node_id] += 1;
+            }
+            if node_id != 0 {
+                // parent_node_id
+                let parent = self.louds_.select1(node_id) - node_id - 1;
+                counts[parent] += counts[node_id];
+            }
+        }
+        self.subtree_counts_ = IdVec::build(&counts, self.dict_size());
+    }
+
+    fn build_trie<'a, I, T>(
+        &mut self, keys: &mut Vec<T>, terminals: &mut Vec<I>,
         config: &mut Config, trie_id: usize)
-        where T: IKey<'a> + Ord + From<&'a[u8]>,
-              Vec<T>: CallCache + CallBuildNextTrie
+        where I: BuildIndex, T: IKey<'a> + Ord + From<&'a[u8]>,
+              Vec<T>: CallCache + CallBuildNextTrie<I>
     {
-        self.build_current_trie(keys, terminals, config, trie_id);
-
-        let mut next_terminals: Vec<u32> = Vec::new();
+        // `config` only holds the flags after the next tries are built
+        let rank_block = config.rank_block();
+        let rank_layout = config.rank_layout();
+        let mut link_flags = BitVec::new();
+        self.build_current_trie(keys, terminals, &mut link_flags, config,
+                                trie_id);
+
+        let mut next_terminals: Vec<I> = Vec::new();
         if !keys.is_empty() {
             keys.build_next_trie(self, &mut next_terminals, config, trie_id);
         }
@@ -295,24 +658,34 @@ impl LoudsTrie {
                 *config = Config::parse(new_cfg as u32);
             }
         }
-        self.link_flags_.build(false, false);
+        self.config_ = *config;
+        let layout = rank_layout.fit(link_flags.len());
+        link_flags.build_with_options(false, false, layout,
+                                      Default::default(), rank_block);
         let mut node_id: usize = 0;
-        for nt in next_terminals.iter_mut() {
-            while !self.link_flags_.at(node_id) {
+        let mut extras: Vec<u32> = Vec::with_capacity(next_terminals.len());
+        for nt in next_terminals.iter() {
+            while !link_flags.at(node_id) {
                 node_id += 1;
             }
-            self.bases_[node_id] = (*nt % 256) as u8;
-            *nt /= 256;
+            let nt = nt.to_usize();
+            self.bases_[node_id] = (nt % 256) as u8;
+            // Links have 40 bits, which is 1 TiB of tail
+            assert!(nt / 256 <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
+            extras.push((nt / 256) as u32);
             node_id += 1;
         }
-        self.extras_.build(next_terminals.iter());
+        self.extras_.build(extras.iter());
+        self.link_flags_ = L::from(link_flags);
         self.fill_cache();
     }
 
-    fn build_current_trie<'a, T>(
-        &mut self, keys: &mut Vec<T>, terminals: &mut Vec<u32>, config: &Config,
+    fn build_current_trie<'a, I, T>(
+        &mut self, keys: &mut Vec<T>, terminals: &mut Vec<I>,
+        link_flags: &mut BitVec, config: &Config,
         trie_id: usize)
-        where T: IKey<'a> + Ord + From<&'a[u8]>, Vec<T>: CallCache
+        where I: BuildIndex, T: IKey<'a> + Ord + From<&'a[u8]>,
+              Vec<T>: CallCache
     {
         for (i, key) in keys.iter_mut().enumerate() {
             key.set_id(i);
@@ -322,19 +695,20 @@ impl LoudsTrie {
         let num_keys = keys.len();
 
         self.reserve_cache(config, trie_id, num_keys);
-        self.louds_.push(true);
-        self.louds_.push(false);
+        let mut louds = BitVec::new();
+        louds.push(true);
+        louds.push(false);
         self.bases_.push(0);
-        self.link_flags_.push(false);
+        link_flags.push(false);
 
         let mut next_keys: Vec<T> = Vec::new();
-        let mut queue: VecDeque<Range> = VecDeque::new();
-        let mut w_ranges: Vec<WeightedRange> = Vec::new();
+        let mut queue: VecDeque<Range<I>> = VecDeque::new();
+        let mut w_ranges: Vec<WeightedRange<I>> = Vec::new();
 
         queue.push_back(Range::new(0, keys.len(), 0));
 
         while let Some(mut range) = queue.pop_front() {
-            let node_id: usize = self.link_flags_.len() - queue.len() - 1;
+            let node_id: usize = link_flags.len() - queue.len() - 1;
 
             while (range.begin() < range.end()) &&
                   (keys[range.begin()].len() == range.key_pos()) {
@@ -344,7 +718,7 @@ impl LoudsTrie {
             }
 
             if range.begin() == range.end() {
-                self.louds_.push(false);
+                louds.push(false);
                 continue;
             }
 
@@ -390,10 +764,10 @@ impl LoudsTrie {
                 if key_pos == w_range.key_pos() + 1 {
                     self.bases_.push(keys[w_range.begin()]
                                      .at(w_range.key_pos()));
-                    self.link_flags_.push(false);
+                    link_flags.push(false);
                 } else {
                     self.bases_.push(0);
-                    self.link_flags_.push(true);
+                    link_flags.push(true);
                     let mut next_key =
                         T::from(keys[w_range.begin()].get_slice());
                     next_key.subslice(w_range.key_pos(),
@@ -403,13 +777,17 @@ impl LoudsTrie {
                 }
                 w_range.set_key_pos(key_pos);
                 queue.push_back(*w_range.range());
-                self.louds_.push(true);
+                louds.push(true);
             }
-            self.louds_.push(false);
+            louds.push(false);
         }
 
-        self.louds_.push(false);
-        self.louds_.build(trie_id == 1, true);
+        louds.push(false);
+        let layout = config.rank_layout().fit(louds.len());
+        louds.build_with_options(trie_id == 1, true, layout,
+                                 config.select_interval(),
+                                 config.rank_block());
+        self.louds_ = L::from(louds);
         self.bases_.shrink_to_fit();
 
         self.build_terminals(keys, terminals);
@@ -419,10 +797,11 @@ impl LoudsTrie {
     fn cache_fwd(&mut self, parent: usize, child: usize, weight: f32, label: u8)
     {
         assert!(parent < child, "MARISA_RANGE_ERROR");
+        if !Cache::fits(child) {
+            return;
+        }
         let cache_id = self.get_cache_id_with_label(parent, label);
         if weight > self.cache_[cache_id].weight() {
-            assert!(parent <= std::u32::MAX as usize);
-            assert!(child <= std::u32::MAX as usize);
             self.cache_[cache_id].set_parent(parent as u32);
             self.cache_[cache_id].set_child(child as u32);
             self.cache_[cache_id].set_weight(weight);
@@ -431,10 +810,11 @@ impl LoudsTrie {
 
     fn cache_rev(&mut self, parent: usize, child: usize, weight: f32) {
         assert!(parent < child, "MARISA_RANGE_ERROR");
+        if !Cache::fits(child) {
+            return;
+        }
         let cache_id = self.get_cache_id(child);
         if weight > self.cache_[cache_id].weight() {
-            assert!(parent <= std::u32::MAX as usize);
-            assert!(child <= std::u32::MAX as usize);
             self.cache_[cache_id].set_parent(parent as u32);
             self.cache_[cache_id].set_child(child as u32);
             self.cache_[cache_id].set_weight(weight);
@@ -451,10 +831,10 @@ impl LoudsTrie {
         self.cache_mask_ = cache_size - 1;
     }
 
-    fn build_tail<'a, T: Ord + IKey<'a>>(&mut self, keys: &Vec<T>,
-                                         terminals: &mut Vec<u32>,
-                                         config: &mut Config) {
-        let mut entries: Vec<Entry<'a>> = Vec::new();
+    fn build_tail<'a, I, T>(&mut self, keys: &Vec<T>, terminals: &mut Vec<I>,
+                            config: &mut Config)
+      where I: BuildIndex, T: Ord + IKey<'a> {
+        let mut entries: Vec<Entry<'a, I>> = Vec::new();
         entries.reserve(keys.len());
         for key in keys {
             entries.push(Entry::new(key.get_slice(), 0));
@@ -462,13 +842,15 @@ impl LoudsTrie {
         self.tail_ = Tail::build(&mut entries, terminals, config.tail_mode());
     }
 
-    fn build_next_trie_fwd<'a>(&mut self, keys: &mut Vec<Key<'a>>,
-                               terminals: &mut Vec<u32>,
-                               config: &mut Config, trie_id: usize) {
+    fn build_next_trie_fwd<'a, I: BuildIndex>(&mut self,
+                                              keys: &mut Vec<Key<'a, I>>,
+                                              terminals: &mut Vec<I>,
+                                              config: &mut Config,
+                                              trie_id: usize) {
         if trie_id == config.num_tries().get() as usize {
             self.build_tail(keys, terminals, config);
         } else {
-            let mut reverse_keys: Vec<ReverseKey> = Vec::new();
+            let mut reverse_keys: Vec<ReverseKey<I>> = Vec::new();
             reverse_keys.reserve(keys.len());
             for key in keys.iter_mut() {
                 reverse_keys.push(ReverseKey::from_key(key));
@@ -481,9 +863,9 @@ impl LoudsTrie {
         }
     }
 
-    fn build_next_trie_rev<'a>(&mut self, keys: &mut Vec<ReverseKey<'a>>,
-                               terminals: &mut Vec<u32>,
-                               config: &mut Config, trie_id: usize) {
+    fn build_next_trie_rev<'a, I: BuildIndex>(
+        &mut self, keys: &mut Vec<ReverseKey<'a, I>>, terminals: &mut Vec<I>,
+        config: &mut Config, trie_id: usize) {
         if trie_id == config.num_tries().get() as usize {
             self.build_tail(keys, terminals, config);
         } else {
@@ -493,13 +875,13 @@ impl LoudsTrie {
         }
     }
 
-    fn build_terminals<'a, T>(&mut self, keys: &Vec<T>,
-                              terminals: &mut Vec<u32>)
-      where T: IKey<'a> + Ord + From<&'a[u8]> {
-        let mut temp: Vec<u32> = Vec::new();
-        temp.resize(keys.len(), 0);
+    fn build_terminals<'a, I, T>(&mut self, keys: &Vec<T>,
+                                 terminals: &mut Vec<I>)
+      where I: BuildIndex, T: IKey<'a> + Ord + From<&'a[u8]> {
+        let mut temp: Vec<I> = Vec::new();
+        temp.resize(keys.len(), I::default());
         for key in keys {
-            temp[key.get_id()] = key.get_terminal() as u32;
+            temp[key.get_id()] = I::from_usize(key.get_terminal());
         }
         *terminals = temp;
     }
@@ -507,12 +889,19 @@ impl LoudsTrie {
     fn fill_cache(&mut self) {
         for item in (&mut self.cache_).iter_mut() {
             let node_id = item.child() as usize;
-            if node_id != 0 {
+            // Links of large dictionaries may not fit in an entry. Those
+            // nodes are left out of the cache, like unused entries are.
+            let extra = if node_id == 0 {
+                None
+            } else if self.link_flags_.at(node_id) {
+                let extra = self.extras_.at(self.link_flags_.rank1(node_id));
+                if extra < INVALID_EXTRA { Some(extra) } else { None }
+            } else {
+                Some(INVALID_EXTRA)
+            };
+            if let Some(extra) = extra {
                 item.set_base(self.bases_[node_id]);
-                let new_extra = if self.link_flags_.at(node_id)
-                    { self.extras_.at(self.link_flags_.rank1(node_id)) }
-                    else { INVALID_EXTRA };
-                item.set_extra(new_extra);
+                item.set_extra(extra);
             } else {
                 item.set_parent(std::u32::MAX);
                 item.set_child(std::u32::MAX);
@@ -520,35 +909,470 @@ impl LoudsTrie {
         }
     }
 
+    /// Start an incremental match at the root
+    pub fn cursor(&self) -> Cursor<L> {
+        Cursor::new(self)
+    }
+
+    /// Key ID of `key`, if it is a key and hasn't been deleted
+    pub fn lookup(&self, key: &[u8]) -> Option<usize> {
+        let mut cursor = self.cursor();
+        for &c in key {
+            if !cursor.push(c) {
+                return None;
+            }
+        }
+        cursor.key_id()
+    }
+
+    /// Delete a key, so that searches and iteration skip it. Its ID isn't
+    /// reused. Returns the ID, if `key` was a key that wasn't deleted yet.
+    pub fn delete(&mut self, key: &[u8]) -> Option<usize> {
+        let id = self.lookup(key);
+        if let Some(id) = id {
+            self.delete_id(id);
+        }
+        id
+    }
+
+    /// Delete a key by ID. Returns false if it was already deleted.
+    pub fn delete_id(&mut self, id: usize) -> bool {
+        assert!(id < self.len(), "MARISA_BOUND_ERROR");
+        if self.deleted_.is_empty() {
+            for _ in 0..self.len() {
+                self.deleted_.push(false);
+            }
+        }
+        if self.deleted_.at(id) {
+            return false;
+        }
+        self.deleted_.set(id, true);
+        self.count_deleted(id);
+        true
+    }
+
+    /// Add a newly deleted key to the deleted counts of the nodes from its
+    /// terminal up to the root
+    fn count_deleted(&mut self, id: usize) {
+        if self.config_.subtree_counts() != SubtreeCounts::On {
+            return;
+        }
+        if self.deleted_counts_.is_empty() {
+            for _ in 0..self.bases_.len() {
+                self.deleted_counts_.push(0);
+            }
+        }
+        let mut node_id = self.key_node(id);
+        loop {
+            let count = self.deleted_counts_.at(node_id);
+            self.deleted_counts_.set(node_id, count + 1);
+            if node_id == 0 {
+                break;
+            }
+            // parent_node_id
+            node_id = self.louds_.select1(node_id) - node_id - 1;
+        }
+    }
+
+    /// Number of keys in the subtree of `node_id` that haven't been deleted
+    fn live_count(&self, node_id: usize) -> usize {
+        let count = self.subtree_counts_.at(node_id);
+        if self.deleted_counts_.is_empty() {
+            count
+        } else {
+            count - self.deleted_counts_.at(node_id) as usize
+        }
+    }
+
+    /// Is `node_id` a terminal whose key hasn't been deleted?
+    fn is_live_terminal(&self, node_id: usize) -> bool {
+        self.terminal_flags_.at(node_id)
+        && !self.is_deleted(self.key_id(node_id))
+    }
+
+    pub fn is_deleted(&self, id: usize) -> bool {
+        !self.deleted_.is_empty() && self.deleted_.at(id)
+    }
+
+    pub fn num_deleted(&self) -> usize {
+        self.deleted_.num_1s()
+    }
+
+    /// Bring back all deleted keys
+    pub fn clear_deleted(&mut self) {
+        self.deleted_.clear();
+        self.deleted_counts_.clear();
+    }
+
+    /// Iterate over all keys and their IDs, depth-first. With
+    /// `NodeOrder::Label` keys come out in lexicographic order; otherwise they
+    /// follow node order, where siblings are sorted by descending weight.
+    ///
+    /// This is much cheaper than calling `id_lookup` for every ID, since the
+    /// restored bytes of each node are shared by all keys below it.
+    pub fn iter(&self) -> Keys<L> {
+        Keys::new(Nav::new(self))
+    }
+
+    /// All keys that start with `prefix` and their IDs, in the order `iter`
+    /// visits them. Deleted keys are skipped.
+    pub fn predictive_search(&self, prefix: &[u8]) -> Vec<(Vec<u8>, usize)> {
+        let mut out: Vec<(Vec<u8>, usize)> = Vec::new();
+        let mut cursor = self.cursor();
+        for &c in prefix {
+            if !cursor.push(c) {
+                return out;
+            }
+        }
+        let mut keys = cursor.completions();
+        while let Some((key, id)) = keys.next_key() {
+            out.push((key.to_vec(), id));
+        }
+        out
+    }
+
+    /// All keys that are prefixes of `query` and their IDs, shortest first.
+    /// Deleted keys are skipped.
+    pub fn common_prefix_search(&self, query: &[u8])
+      -> Vec<(Vec<u8>, usize)> {
+        let mut out: Vec<(Vec<u8>, usize)> = Vec::new();
+        let mut cursor = self.cursor();
+        for i in 0..query.len() + 1 {
+            if let Some(id) = cursor.key_id() {
+                out.push((query[..i].to_vec(), id));
+            }
+            if i == query.len() || !cursor.push(query[i]) {
+                break;
+            }
+        }
+        out
+    }
+
+    /// Number of keys that start with `prefix`, including `prefix` itself if
+    /// it is a key. Deleted keys aren't counted. Takes time proportional to
+    /// the length of `prefix`.
+    ///
+    /// Requires `SubtreeCounts::On`.
+    pub fn count_prefix(&self, prefix: &[u8]) -> usize {
+        assert!(self.config_.subtree_counts() == SubtreeCounts::On,
+                "MARISA_STATE_ERROR: subtree counts are not stored");
+        match self.find_prefix(prefix) {
+            Some((node_id, _)) => self.live_count(node_id.0),
+            None => 0,
+        }
+    }
+
+    /// Number of keys that sort before `key`. If `key` is in the trie, this is
+    /// its position in sorted order; otherwise it is where `key` would go.
+    /// Deleted keys aren't counted.
+    ///
+    /// Requires `NodeOrder::Label` and `SubtreeCounts::On`.
+    pub fn rank_of(&self, key: &[u8]) -> usize {
+        self.assert_sorted_counts();
+        let mut rank: usize = 0;
+        let mut node_id = NodeID(0);
+        let mut pos: usize = 0;
+        let mut label: Vec<u8> = Vec::new();
+        'descend: loop {
+            if pos == key.len() {
+                return rank;
+            }
+            // A key that ends here is a proper prefix of `key`
+            if self.is_live_terminal(node_id.0) {
+                rank += 1;
+            }
+            let (mut child_id, mut louds_pos) = match self.child_pos(node_id) {
+                Some(x) => x,
+                None => { return rank; }
+            };
+            loop {
+                let id = child_id.0;
+                label.clear();
+                self.label_into(id, &mut label);
+                if key[pos..].starts_with(&label) {
+                    node_id = child_id;
+                    pos += label.len();
+                    continue 'descend;
+                }
+                if &label[..] > &key[pos..] {
+                    return rank;
+                }
+                rank += self.live_count(id);
+                if !self.louds_.at(louds_pos.0 + 1) {
+                    return rank;
+                }
+                child_id = NodeID(child_id.0 + 1);
+                louds_pos = LoudsPos(louds_pos.0 + 1);
+            }
+        }
+    }
+
+    /// The key at position `n` in sorted order, and its ID. Deleted keys
+    /// don't take up positions, as in `rank_of`.
+    ///
+    /// Requires `NodeOrder::Label` and `SubtreeCounts::On`.
+    pub fn nth_key(&self, n: usize) -> (Vec<u8>, usize) {
+        self.assert_sorted_counts();
+        assert!(n < self.len() - self.num_deleted(), "MARISA_BOUND_ERROR");
+        let mut n = n;
+        let mut node_id = NodeID(0);
+        let mut key: Vec<u8> = Vec::new();
+        loop {
+            let id = node_id.0;
+            if self.is_live_terminal(id) {
+                if n == 0 {
+                    return (key, self.key_id(id));
+                }
+                n -= 1;
+            }
+            // n is less than this node's count, so the key is further down
+            let (mut child_id, mut louds_pos) =
+                self.child_pos(node_id).unwrap();
+            loop {
+                let count = self.live_count(child_id.0);
+                if n < count {
+                    break;
+                }
+                n -= count;
+                assert!(self.louds_.at(louds_pos.0 + 1),
+                        "MARISA_RANGE_ERROR");
+                child_id = NodeID(child_id.0 + 1);
+                louds_pos = LoudsPos(louds_pos.0 + 1);
+            }
+            node_id = child_id;
+            self.label_into(node_id.0, &mut key);
+        }
+    }
+
+    fn assert_sorted_counts(&self) {
+        assert!(self.config_.node_order() == NodeOrder::Label,
+                "MARISA_STATE_ERROR: nodes are not in label order");
+        assert!(self.config_.subtree_counts() == SubtreeCounts::On,
+                "MARISA_STATE_ERROR: subtree counts are not stored");
+    }
+
+    /// IDs of all keys that start with `prefix`.
+    ///
+    /// With `IdOrder::Label` this is always an `IdSet::Range`, found from the
+    /// smallest and largest keys below the prefix without visiting the rest.
+    /// Otherwise the keys below the prefix are enumerated, and the IDs are
+    /// packed into an `IdSet::Sorted` unless they happen to be contiguous.
+    ///
+    /// Deleted keys are left out, which can split a range.
+    pub fn id_range_for_prefix(&self, prefix: &[u8]) -> IdSet {
+        let (node_id, louds_pos) = match self.find_prefix(prefix) {
+            Some(x) if !self.is_empty() => x,
+            _ => { return IdSet::Range(0..0); }
+        };
+        match self.config_.id_order() {
+            IdOrder::Label => {
+                let mut label: Vec<u8> = Vec::new();
+                let first = self.key_id(self.min_terminal(node_id, &mut label));
+                let last = self.key_id(self.max_terminal(node_id, &mut label));
+                if self.num_deleted() == 0 {
+                    return IdSet::Range(first..last + 1);
+                }
+                let ids: Vec<usize> = (first..last + 1)
+                    .filter(|&id| !self.is_deleted(id)).collect();
+                IdSet::from_sorted(&ids)
+            },
+            IdOrder::Node => {
+                let nav = Nav::from_node(self, node_id, louds_pos, &[]);
+                let mut keys = Keys::new(nav);
+                let mut ids: Vec<usize> = Vec::new();
+                while let Some((_, id)) = keys.next_key() {
+                    ids.push(id);
+                }
+                ids.sort();
+                IdSet::from_sorted(&ids)
+            }
+        }
+    }
+
+    /// The terminal node holding the smallest key at or below `node_id`
+    fn min_terminal(&self, node_id: NodeID, label: &mut Vec<u8>) -> usize {
+        let mut node_id = node_id;
+        // A key that ends here is a prefix of everything below
+        while !self.terminal_flags_.at(node_id.0) {
+            node_id = self.extreme_child(node_id, false, label);
+        }
+        node_id.0
+    }
+
+    /// The terminal node holding the largest key at or below `node_id`
+    fn max_terminal(&self, node_id: NodeID, label: &mut Vec<u8>) -> usize {
+        let mut node_id = node_id;
+        // Leaves are always terminal
+        while self.has_child(node_id) {
+            node_id = self.extreme_child(node_id, true, label);
+        }
+        node_id.0
+    }
+
+    /// The child of `node_id` whose label sorts first, or last if `last` is
+    /// set. `label` is scratch space.
+    fn extreme_child(&self, node_id: NodeID, last: bool, label: &mut Vec<u8>)
+      -> NodeID {
+        let (mut child_id, mut louds_pos) = self.child_pos(node_id).unwrap();
+        if self.config_.node_order() == NodeOrder::Label {
+            // Siblings are already sorted
+            while last && self.louds_.at(louds_pos.0 + 1) {
+                child_id = NodeID(child_id.0 + 1);
+                louds_pos = LoudsPos(louds_pos.0 + 1);
+            }
+            return child_id;
+        }
+        // Siblings have distinct first bytes, so those are all we compare
+        let mut best = child_id;
+        let mut best_byte = self.label_front(child_id, label);
+        while self.louds_.at(louds_pos.0 + 1) {
+            child_id = NodeID(child_id.0 + 1);
+            louds_pos = LoudsPos(louds_pos.0 + 1);
+            let byte = self.label_front(child_id, label);
+            if (last && byte > best_byte) || (!last && byte < best_byte) {
+                best = child_id;
+                best_byte = byte;
+            }
+        }
+        best
+    }
+
+    /// First byte of a node's label. `label` is scratch space.
+    fn label_front(&self, node_id: NodeID, label: &mut Vec<u8>) -> u8 {
+        let node_id = node_id.0;
+        if self.link_flags_.at(node_id) {
+            label.clear();
+            self.restore(self.get_linked_node_id(node_id), label);
+            label[0]
+        } else {
+            self.bases_[node_id]
+        }
+    }
+
+    /// Find the node below which all keys starting with `prefix` are stored.
+    /// If `prefix` ends partway through a label, this is the node the label
+    /// belongs to.
+    fn find_prefix(&self, prefix: &[u8]) -> Option<(NodeID, LoudsPos)> {
+        let mut cursor = self.cursor();
+        for &c in prefix {
+            if !cursor.push(c) {
+                return None;
+            }
+        }
+        Some(cursor.node())
+    }
+
+    /// Depth-first walk over all keys in label order, even if nodes are in
+    /// weight order. Slower than `iter` in that case, since each node's
+    /// children have to be sorted.
+    pub fn sorted_iter(&self) -> SortedKeys<L> {
+        SortedKeys::new(self)
+    }
+
     pub fn id_lookup(&self, id: usize) -> Vec<u8> {
-        let mut v: Vec<u8> = Vec::new();
-        self.id_lookup_into_vec(id, &mut v);
-        v
+        let mut buf = KeyBuf::new();
+        self.id_lookup_into(id, &mut buf);
+        buf.key_
     }
 
-    pub fn id_lookup_into_vec(&self, id: usize, key_out: &mut Vec<u8>) {
+    /// Restore the key with the given ID into `buf`, front to back. Once `buf`
+    /// has grown to fit the longest key, this doesn't allocate.
+    pub fn id_lookup_into(&self, id: usize, buf: &mut KeyBuf) {
         assert!(id < self.len());
-        key_out.clear();
+        buf.clear();
 
-        let mut node_id = self.terminal_flags_.select1(id);
+        // Labels come out front to back, but the path is found bottom-up, so
+        // collect it first.
+        let mut node_id = self.key_node(id);
         if node_id == 0 {
             return;
         }
         loop {
-            if self.link_flags_.at(node_id) {
-                let prev_key_pos = key_out.len();
-                self.restore(self.get_linked_node_id(node_id), key_out);
-                key_out[prev_key_pos..].reverse();
-            } else {
-                key_out.push(self.bases_[node_id]);
-            }
+            buf.path_.push(node_id);
             if node_id <= self.num_l1_nodes_ {
-                key_out.reverse();
-                return;
+                break;
             }
             // parent_node_id
             node_id = self.louds_.select1(node_id) - node_id - 1;
         }
+        for &node_id in buf.path_.iter().rev() {
+            self.label_into(node_id, &mut buf.key_);
+        }
+    }
+
+    /// Restore the key with the given ID into `key_out`. Allocates a path
+    /// buffer on every call, which `id_lookup_into` reuses.
+    #[deprecated(note = "use id_lookup_into with a KeyBuf")]
+    pub fn id_lookup_into_vec(&self, id: usize, key_out: &mut Vec<u8>) {
+        let mut buf = KeyBuf::new();
+        std::mem::swap(&mut buf.key_, key_out);
+        self.id_lookup_into(id, &mut buf);
+        std::mem::swap(&mut buf.key_, key_out);
+    }
+
+    /// Restore the keys for all of `ids` into `out`, in the same order as
+    /// `ids`. Any previous contents of `out` are discarded.
+    ///
+    /// The IDs are looked up in ascending order, so that neighbouring
+    /// `select1` calls touch the same parts of `terminal_flags_` and `louds_`,
+    /// and repeated IDs are only restored once. Reusing `out` across batches
+    /// avoids allocation once its buffers are large enough.
+    pub fn id_lookup_batch(&self, ids: &[usize], out: &mut KeyArena) {
+        out.clear();
+        out.order_.clear();
+        out.order_.extend(0..ids.len());
+        out.order_.sort_unstable_by_key(|&i| ids[i]);
+        out.spans_.clear();
+        out.spans_.resize(ids.len(), (0, 0));
+        out.sorted_buf_.clear();
+
+        let mut prev: Option<usize> = None;
+        for &i in out.order_.iter() {
+            if let Some(j) = prev {
+                if ids[j] == ids[i] {
+                    out.spans_[i] = out.spans_[j];
+                    continue;
+                }
+            }
+            self.id_lookup_into(ids[i], &mut out.key_buf_);
+            let begin = out.sorted_buf_.len();
+            out.sorted_buf_.extend_from_slice(out.key_buf_.get_u8());
+            out.spans_[i] = (begin, out.sorted_buf_.len());
+            prev = Some(i);
+        }
+
+        // Put the keys back in the caller's order
+        for &(begin, end) in out.spans_.iter() {
+            out.buf_.extend_from_slice(&out.sorted_buf_[begin..end]);
+            out.offsets_.push(out.buf_.len());
+        }
+    }
+
+    /// Key ID of a terminal node
+    fn key_id(&self, node_id: usize) -> usize {
+        let terminal = self.terminal_flags_.rank1(node_id);
+        match self.config_.id_order() {
+            IdOrder::Node => terminal,
+            IdOrder::Label => self.terminal_ids_.at(terminal),
+        }
+    }
+
+    /// Terminal node of a key ID
+    fn key_node(&self, id: usize) -> usize {
+        let terminal = match self.config_.id_order() {
+            IdOrder::Node => id,
+            IdOrder::Label => self.id_terminals_.at(id),
+        };
+        self.terminal_flags_.select1(terminal)
+    }
+
+    /// Append the label of a root trie node to `key_out`
+    fn label_into(&self, node_id: usize, key_out: &mut Vec<u8>) {
+        if self.link_flags_.at(node_id) {
+            self.restore(self.get_linked_node_id(node_id), key_out);
+        } else {
+            key_out.push(self.bases_[node_id]);
+        }
     }
 
     fn restore(&self, link: usize, key_out: &mut Vec<u8>) {
@@ -568,7 +1392,8 @@ impl LoudsTrie {
         let mut node_id = node_id;
         loop {
             let cache_id = self.get_cache_id(node_id);
-            if node_id == self.cache_[cache_id].child() as usize {
+            if Cache::fits(node_id)
+            && node_id == self.cache_[cache_id].child() as usize {
                 if self.cache_[cache_id].extra() != INVALID_EXTRA {
                     self.restore(self.cache_[cache_id].link() as usize,
                                  key_out);
@@ -615,6 +1440,7 @@ impl LoudsTrie {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+    /// Number of key IDs, including deleted keys
     pub fn len(&self) -> usize {
         self.terminal_flags_.num_1s()
     }
@@ -637,49 +1463,314 @@ impl LoudsTrie {
     }
 
     fn get_linked_node_id_2(&self, node_id: usize, link_id: usize) -> usize {
-        ((self.bases_[node_id] as u32) | (self.extras_.at(link_id) * 256))
-            as usize
+        (self.bases_[node_id] as usize)
+        | ((self.extras_.at(link_id) as usize) << 8)
     }
 
     fn get_linked_ids(&self, node_id: usize) -> (NodeID, LinkID) {
         let link_id = self.get_link_id(node_id);
         let node_id = self.get_linked_node_id_2(node_id, link_id);
-        assert!(link_id <= std::u32::MAX as usize);
-        assert!(node_id <= std::u32::MAX as usize);
-        (NodeID(node_id as u32), LinkID(link_id as u32))
+        (NodeID(node_id), LinkID(link_id))
     }
 
     fn update_link_id(&self, link_id: usize, node_id: usize) -> usize {
-        if link_id == INVALID_LINK_ID.0 as usize {
+        if link_id == INVALID_LINK_ID.0 {
             self.get_link_id(node_id)
         } else {
             link_id + 1
         }
     }
-}
 
-/*
-    fn total_size() usize {
-        louds_.total_size()
-        + terminal_flags_.total_size()
-        + link_flags_.total_size()
-        + bases_.total_size()
-        + extras_.total_size()
-        + tail_.total_size()
-        + if (next_trie_.get() != NULL { next_trie_->total_size() } else { 0 }
-        + cache_.total_size()
+    /// Build like `build`, and map the key IDs of `previous` to the IDs of
+    /// the same keys in the new trie
+    pub fn build_remapped<'a, I: BuildIndex>(keys: &mut Vec<Key<'a, I>>,
+                                             config: &Config,
+                                             previous: &LoudsTrie<L>)
+      -> (LoudsTrie<L>, IdMap) {
+        let trie = LoudsTrie::build_with_louds(keys, config);
+        let id_map = previous.id_map_to(&trie);
+        (trie, id_map)
     }
 
-    fn io_size() -> usize {
-        Header().io_size()
-        + louds_.io_size()
-        + terminal_flags_.io_size()
-        + link_flags_.io_size()
-        + bases_.io_size()
-        + extras_.io_size()
-        + tail_.io_size()
-//match
-        + if next_trie_.get() != NULL { 
+    /// Map the key IDs of this trie to the IDs of the same keys in `new`.
+    /// Deleted keys on either side are left unmapped.
+    pub fn id_map_to(&self, new: &LoudsTrie<L>) -> IdMap {
+        let mut old_to_new: Vec<Option<usize>> = vec![None; self.len()];
+        let mut diff = self.diff(new);
+        while let Some(entry) = diff.next_entry() {
+            if let DiffEntry::Kept { old_id, new_id, .. } = entry {
+                old_to_new[old_id] = Some(new_id);
+            }
+        }
+        IdMap::from_old_to_new(&old_to_new, new.len(), new.dict_size())
+    }
+
+    /// Build from `arena`, where key `i` had the ID `old_ids[i]` out of
+    /// `num_old` IDs, and map the old IDs to the new ones
+    fn build_mapped(arena: &KeyArena, old_ids: &[usize], num_old: usize,
+                    config: &Config) -> (LoudsTrie<L>, IdMap) {
+        let trie = LoudsTrie::build_from_arena_with_louds(arena, config);
+        let mut old_to_new: Vec<Option<usize>> = vec![None; num_old];
+        for (i, &old_id) in old_ids.iter().enumerate() {
+            old_to_new[old_id] = trie.lookup(arena.get(i));
+        }
+        let id_map = IdMap::from_old_to_new(&old_to_new, trie.len(),
+                                            trie.dict_size());
+        (trie, id_map)
+    }
+
+    /// Build a trie from the keys that start with `prefix`, with the prefix
+    /// cut off if `strip_prefix` is set. Also returns how the IDs of this trie
+    /// map to the IDs of the new one; keys outside the prefix and deleted keys
+    /// are left unmapped.
+    pub fn extract_prefix(&self, prefix: &[u8], strip_prefix: bool,
+                          config: &Config) -> (LoudsTrie<L>, IdMap) {
+        let mut arena = KeyArena::new();
+        let mut old_ids: Vec<usize> = Vec::new();
+        let mut cursor = self.cursor();
+        for &c in prefix {
+            if !cursor.push(c) {
+                break;
+            }
+        }
+        let skip = if strip_prefix { prefix.len() } else { 0 };
+        let mut keys = cursor.completions();
+        while let Some((key, id)) = keys.next_key() {
+            arena.push(&key[skip..]);
+            old_ids.push(id);
+        }
+        LoudsTrie::build_mapped(&arena, &old_ids, self.len(), config)
+    }
+
+    /// Build a new trie from the keys restored from this one, for when the
+    /// original key list is gone. Deleted keys are dropped. Also returns how
+    /// the IDs of this trie map to the new ones; with `IdOrder::Label` on both
+    /// sides the IDs stay the same.
+    ///
+    /// Weights aren't stored in a trie, so with `NodeOrder::Weight` the new
+    /// trie orders siblings as if every key had the same weight.
+    pub fn rebuild_with(&self, config: &Config) -> (LoudsTrie<L>, IdMap) {
+        let mut arena = KeyArena::new();
+        let mut old_ids: Vec<usize> = Vec::with_capacity(self.len());
+        let mut keys = self.iter();
+        while let Some((key, id)) = keys.next_key() {
+            arena.push(key);
+            old_ids.push(id);
+        }
+        LoudsTrie::build_mapped(&arena, &old_ids, self.len(), config)
+    }
+
+    /// Like `rebuild_with`, but the IDs of this trie keep working as the
+    /// stable IDs of a `PinnedTrie`
+    pub fn rebuild_keeping_ids(&self, config: &Config) -> PinnedTrie<L> {
+        let (trie, id_map) = self.rebuild_with(config);
+        PinnedTrie::with_ids(trie, id_map)
+    }
+
+    /// `build_from_arena` for any `L`
+    pub fn build_from_arena_with_louds(arena: &KeyArena, config: &Config)
+      -> LoudsTrie<L> {
+        if arena.len() < std::u32::MAX as usize {
+            let mut keys: Vec<Key> = (0..arena.len())
+                                     .map(|i| Key::new(arena.get(i))).collect();
+            LoudsTrie::build_with_louds(&mut keys, config)
+        } else {
+            let mut keys: Vec<Key<usize>> =
+                (0..arena.len()).map(|i| Key::new(arena.get(i))).collect();
+            LoudsTrie::build_with_louds(&mut keys, config)
+        }
+    }
+
+    /// Keys in either trie, in label order
+    pub fn union<'a>(&'a self, other: &'a LoudsTrie<L>) -> SetOpKeys<'a, L> {
+        SetOpKeys::new(self, other, SetOp::Union)
+    }
+
+    /// Keys in both tries, in label order
+    pub fn intersection<'a>(&'a self, other: &'a LoudsTrie<L>)
+      -> SetOpKeys<'a, L> {
+        SetOpKeys::new(self, other, SetOp::Intersection)
+    }
+
+    /// Keys in this trie but not in `other`, in label order
+    pub fn difference<'a>(&'a self, other: &'a LoudsTrie<L>)
+      -> SetOpKeys<'a, L> {
+        SetOpKeys::new(self, other, SetOp::Difference)
+    }
+
+    /// Compare against a newer version of the dictionary, key by key in
+    /// label order
+    pub fn diff<'a>(&'a self, new: &'a LoudsTrie<L>) -> Diff<'a, L> {
+        Diff::new(self, new)
+    }
+
+    /// `read` for any `L`
+    pub fn read_with_louds(reader: &mut Read) -> io::Result<LoudsTrie<L>> {
+        let mut reader = Reader::new(reader);
+        let header = try!(Header::read(&mut reader));
+        let mut out = try!(LoudsTrie::read_(&mut reader, header.dict_size()));
+        if header.dict_size() == DictSize::Standard && out.needs_large() {
+            return Err(format_error("large dictionary with standard header"));
+        }
+        out.config_.set_dict_size(header.dict_size());
+        Ok(out)
+    }
+
+    /// Write the dictionary in marisa-trie's format. Dictionaries built with
+    /// `SubtreeCounts::On` or `IdOrder::Label` carry extra sections that
+    /// marisa-trie itself can't read. Large dictionaries get a header of their
+    /// own, which marisa-trie rejects.
+    pub fn write(&self, writer: &mut Write) -> io::Result<()> {
+        let mut writer = Writer::new(writer);
+        try!(Header::new(self.dict_size()).write(&mut writer));
+        self.write_(&mut writer, self.dict_size())
+    }
+
+    /// `load` for any `L`
+    pub fn load_with_louds<P: AsRef<Path>>(path: P)
+      -> io::Result<LoudsTrie<L>> {
+        let mut file = io::BufReader::new(try!(File::open(path)));
+        LoudsTrie::read_with_louds(&mut file)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = io::BufWriter::new(try!(File::create(path)));
+        try!(self.write(&mut file));
+        file.flush()
+    }
+
+    /// Read a trie and the ones after it. Key IDs and counts are as wide as
+    /// `dict_size` has them.
+    fn read_(reader: &mut Reader, dict_size: DictSize)
+      -> io::Result<LoudsTrie<L>> {
+        let mut out = LoudsTrie::new();
+        out.louds_ = try!(L::read_for(reader, dict_size));
+        out.terminal_flags_ = try!(L::read_for(reader, dict_size));
+        out.link_flags_ = try!(L::read_for(reader, dict_size));
+        out.bases_ = try!(reader.read_vec::<u8>());
+        out.extras_ = try!(FlatVec::read(reader));
+        out.tail_ = try!(Tail::read(reader));
+        if out.link_flags_.num_1s() != 0 && out.tail_.is_empty() {
+            out.next_trie_ = Some(Box::new(try!(LoudsTrie::read_(reader,
+                                                                 dict_size))));
+        }
+        out.cache_ = try!(reader.read_vec::<Cache>());
+        // Empty for a trie that was never built
+        if !out.cache_.is_empty() {
+            if !out.cache_.len().is_power_of_two() {
+                return Err(format_error("cache size"));
+            }
+            out.cache_mask_ = out.cache_.len() - 1;
+        }
+        out.num_l1_nodes_ = try!(reader.read_u32()) as usize;
+        let flags = try!(reader.read_u32());
+        if flags & !(CONFIG_MASK | DELETED_FLAG) != 0 {
+            return Err(format_error("config flags"));
+        }
+        out.config_ = Config::parse(flags & CONFIG_MASK);
+        if out.config_.subtree_counts() == SubtreeCounts::On {
+            out.subtree_counts_ = try!(IdVec::read(reader, dict_size));
+        }
+        if out.config_.id_order() == IdOrder::Label {
+            out.terminal_ids_ = try!(IdVec::read(reader, dict_size));
+            out.id_terminals_ = try!(IdVec::read(reader, dict_size));
+        }
+        if flags & DELETED_FLAG != 0 {
+            out.deleted_ = try!(BitVec::read(reader));
+            if out.deleted_.len() != out.len()
+            || out.deleted_.is_rank_enabled() {
+                return Err(format_error("deleted keys"));
+            }
+            let ids: Vec<usize> = out.deleted_.ones().collect();
+            for id in ids {
+                out.count_deleted(id);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Write a trie and the ones after it, in the format of `dict_size`
+    fn write_(&self, writer: &mut Writer, dict_size: DictSize)
+      -> io::Result<()> {
+        try!(self.louds_.write_for(writer, dict_size));
+        try!(self.terminal_flags_.write_for(writer, dict_size));
+        try!(self.link_flags_.write_for(writer, dict_size));
+        try!(writer.write_vec(&self.bases_));
+        try!(self.extras_.write(writer));
+        try!(self.tail_.write(writer));
+        if let Some(ref next) = self.next_trie_ {
+            try!(next.write_(writer, dict_size));
+        }
+        try!(writer.write_vec(&self.cache_));
+        assert!(self.num_l1_nodes_ <= std::u32::MAX as usize);
+        try!(writer.write_u32(self.num_l1_nodes_ as u32));
+        let has_deleted = self.num_deleted() != 0;
+        let flags = self.config_.flags();
+        try!(writer.write_u32(if has_deleted { flags | DELETED_FLAG }
+                              else { flags }));
+        if self.config_.subtree_counts() == SubtreeCounts::On {
+            try!(self.subtree_counts_.write(writer));
+        }
+        if self.config_.id_order() == IdOrder::Label {
+            try!(self.terminal_ids_.write(writer));
+            try!(self.id_terminals_.write(writer));
+        }
+        if has_deleted {
+            try!(self.deleted_.write(writer));
+        }
+        Ok(())
+    }
+}
+
+/// The constructors for the default `L`, which can't be inferred from their
+/// arguments
+impl LoudsTrie {
+    /// Build a trie from `keys`, and set each key's ID. Use `Key<usize>` for
+    /// more than 2^32 keys.
+    pub fn build<'a, I: BuildIndex>(keys: &mut Vec<Key<'a, I>>,
+                                    config: &Config) -> LoudsTrie {
+        LoudsTrie::build_with_louds(keys, config)
+    }
+
+    /// Build from keys stored in a `KeyArena`. Since the trie is built
+    /// breadth-first, all keys have to be at hand, but this way they take one
+    /// buffer instead of an allocation each.
+    pub fn build_from_arena(arena: &KeyArena, config: &Config) -> LoudsTrie {
+        LoudsTrie::build_from_arena_with_louds(arena, config)
+    }
+
+    /// Read a dictionary written by `write`
+    pub fn read(reader: &mut Read) -> io::Result<LoudsTrie> {
+        LoudsTrie::read_with_louds(reader)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<LoudsTrie> {
+        LoudsTrie::load_with_louds(path)
+    }
+}
+
+/*
+    fn total_size() usize {
+        louds_.total_size()
+        + terminal_flags_.total_size()
+        + link_flags_.total_size()
+        + bases_.total_size()
+        + extras_.total_size()
+        + tail_.total_size()
+        + if (next_trie_.get() != NULL { next_trie_->total_size() } else { 0 }
+        + cache_.total_size()
+    }
+
+    fn io_size() -> usize {
+        Header().io_size()
+        + louds_.io_size()
+        + terminal_flags_.io_size()
+        + link_flags_.io_size()
+        + bases_.io_size()
+        + extras_.io_size()
+        + tail_.io_size()
+//match
+        + if next_trie_.get() != NULL { 
              next_trie_->io_size() - Header().io_size() } else { 0 }
         + cache_.io_size()
         + (sizeof(u32) * 2)
@@ -694,21 +1785,7 @@ impl LoudsTrie {
         temp
     }
 
-    fn read(Reader &reader) -> LoudsTrie {
-        Header().read(reader);
-        let temp: LoudsTrie;
-        temp.read_(reader);
-        temp
-    }
-
-    fn write(&self, writer: &mut Writer) {
-        Header().write(writer);
-        write_(writer);
-    }
-
     void map_(Mapper &mapper);
-    void read_(Reader &reader);
-    void write_(Writer &writer) const;
  
 void LoudsTrie::map_(Mapper &mapper) {
   louds_.map(mapper);
@@ -735,59 +1812,23 @@ void LoudsTrie::map_(Mapper &mapper) {
     config_.parse((int)temp_config_flags);
   }
 }
-
-void LoudsTrie::read_(Reader &reader) {
-  louds_.read(reader);
-  terminal_flags_.read(reader);
-  link_flags_.read(reader);
-  bases_.read(reader);
-  extras_.read(reader);
-  tail_.read(reader);
-  if ((link_flags_.num_1s() != 0) && tail_.empty()) {
-    next_trie_.reset(new (std::nothrow) LoudsTrie);
-    MARISA_THROW_IF(next_trie_.get() == NULL, MARISA_MEMORY_ERROR);
-    next_trie_->read_(reader);
-  }
-  cache_.read(reader);
-  cache_mask_ = cache_.size() - 1;
-  {
-    u32 temp_num_l1_nodes;
-    reader.read(&temp_num_l1_nodes);
-    num_l1_nodes_ = temp_num_l1_nodes;
-  }
-  {
-    u32 temp_config_flags;
-    reader.read(&temp_config_flags);
-    config_.parse((int)temp_config_flags);
-  }
-}
-
-void LoudsTrie::write_(Writer &writer) const {
-  louds_.write(writer);
-  terminal_flags_.write(writer);
-  link_flags_.write(writer);
-  bases_.write(writer);
-  extras_.write(writer);
-  tail_.write(writer);
-  if (next_trie_.get() != NULL) {
-    next_trie_->write_(writer);
-  }
-  cache_.write(writer);
-  writer.write((u32)num_l1_nodes_);
-  writer.write((u32)config_.flags());
-}
 */
 
 #[cfg(test)]
 mod test {
     use env_logger;
-    use config::{Config, MAX_NUM_TRIES, MIN_NUM_TRIES, NumTries};
-    use key::Key;
+    use config::{Config, DictSize, IdOrder, MAX_NUM_TRIES, MIN_NUM_TRIES,
+                 NodeOrder, NumTries, SubtreeCounts, TailMode};
+    use key::{Key, ReverseKey};
     use key::IKey;
     use quickcheck as qc;
+    use range::Range;
     use std;
     use std::default::Default;
-    use super::LoudsTrie;
+    use super::{KeyArena, KeyBuf, LoudsTrie};
+    use super::id_set::IdSet;
+    use vector::bit_vec::{BitVec, RankBlock, RankLayout, SelectInterval};
+    use vector::sparse_bit_vec::SparseBitVec;
 
     fn build_prop(v: Vec<String>, num_tries: NumTries) -> qc::TestResult {
         if v.iter().any(|x| x.is_empty()) {
@@ -805,7 +1846,9 @@ mod test {
                 return qc::TestResult::failed();
             }
         }
+        // Duplicate keys share an ID
         ids_seen.sort();
+        ids_seen.dedup();
         for (&a, b) in ids_seen.iter().zip(0..ids_seen.len()) {
             if a != b { return qc::TestResult::failed(); }
         }
@@ -819,13 +1862,1180 @@ mod test {
                        -> qc::TestResult);
     }
 
+    fn iter_prop(v: Vec<String>, num_tries: NumTries, label_order: bool)
+      -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let node_order = if label_order { NodeOrder::Label }
+                                   else { NodeOrder::Weight };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_node_order(node_order);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut expected: Vec<Vec<u8>> = v.iter()
+            .map(|s| s.as_bytes().to_vec()).collect();
+        expected.sort();
+        expected.dedup();
+
+        let mut found: Vec<Vec<u8>> = Vec::new();
+        let mut ids_seen: Vec<usize> = Vec::new();
+        let mut iter = trie.iter();
+        while let Some((key, id)) = iter.next_key() {
+            if trie.id_lookup(id) != key {
+                return qc::TestResult::failed();
+            }
+            found.push(key.to_vec());
+            ids_seen.push(id);
+        }
+        if !label_order {
+            found.sort();
+        }
+        ids_seen.sort();
+        qc::TestResult::from_bool(
+            found == expected && ids_seen.iter().cloned().eq(0..trie.len()))
+    }
+
+    #[test]
+    fn louds_trie_iter_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(iter_prop as fn(Vec<String>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
     #[test]
-    fn louds_trie_build_manual() {
+    fn louds_trie_iter_manual() {
         let _ = env_logger::init();
-        let n = NumTries::default();
-        assert!(!build_prop(vec!["\u{80}".to_string()], n).is_failure());
-        assert!(!build_prop(vec!["\u{4b8ca}".to_string(),
-                                 "\u{d2c4a}".to_string()], n).is_failure());
+        let v: Vec<String> = vec!["b", "ab", "abc", "a", "ba", "\u{0}"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            assert!(!iter_prop(v.clone(), NumTries::new(n), true)
+                     .is_failure());
+            assert!(!iter_prop(v.clone(), NumTries::new(n), false)
+                     .is_failure());
+        }
+    }
+
+    #[allow(deprecated)]
+    fn id_lookup_into_prop(v: Vec<String>, num_tries: NumTries)
+      -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut buf = KeyBuf::new();
+        for key in keys.iter() {
+            trie.id_lookup_into(key.get_id(), &mut buf);
+            if buf.get_u8() != key.get_slice() {
+                return qc::TestResult::failed();
+            }
+        }
+        // Everything fits now, so a second pass mustn't reallocate
+        let capacities = (buf.key_.capacity(), buf.path_.capacity());
+        for key in keys.iter().rev() {
+            trie.id_lookup_into(key.get_id(), &mut buf);
+            if buf.get_u8() != key.get_slice()
+            || capacities != (buf.key_.capacity(), buf.path_.capacity()) {
+                return qc::TestResult::failed();
+            }
+        }
+        let mut key_out = b"stale".to_vec();
+        for key in keys.iter() {
+            trie.id_lookup_into_vec(key.get_id(), &mut key_out);
+            if &key_out[..] != key.get_slice() {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    fn sorted_iter_prop(v: Vec<String>, num_tries: NumTries,
+                        label_order: bool) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let node_order = if label_order { NodeOrder::Label }
+                         else { NodeOrder::Weight };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_node_order(node_order);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut expected: Vec<&[u8]> = v.iter().map(|x| x.as_bytes())
+                                       .collect();
+        expected.sort();
+        expected.dedup();
+        let found: Vec<(Vec<u8>, usize)> = trie.sorted_iter().collect();
+        if found.len() != expected.len() {
+            return qc::TestResult::failed();
+        }
+        for (&(ref key, id), &x) in found.iter().zip(expected.iter()) {
+            if &key[..] != x || trie.id_lookup(id) != x {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_sorted_iter_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(sorted_iter_prop as fn(Vec<String>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_id_lookup_into_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(id_lookup_into_prop as fn(Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    fn id_lookup_batch_prop(v: Vec<String>, ids: Vec<usize>,
+                            num_tries: NumTries) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let trie = LoudsTrie::build(&mut keys, &config);
+        let ids: Vec<usize> = if trie.len() == 0 {
+            Vec::new()
+        } else {
+            ids.iter().map(|&id| id % trie.len()).collect()
+        };
+
+        let mut arena = KeyArena::new();
+        // Twice, to check that a reused arena is cleared properly
+        for _ in 0..2 {
+            trie.id_lookup_batch(&ids, &mut arena);
+            if arena.len() != ids.len()
+            || arena.offsets().last() != Some(&arena.bytes().len()) {
+                return qc::TestResult::failed();
+            }
+            for (i, &id) in ids.iter().enumerate() {
+                if arena.get(i) != &trie.id_lookup(id)[..] {
+                    return qc::TestResult::failed();
+                }
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_id_lookup_batch_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(id_lookup_batch_prop
+                       as fn(Vec<String>, Vec<usize>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_id_lookup_batch_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "banana", "applesauce", "cherry"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let ids = vec![3, 0, 3, 1, 2, 0];
+        for &n in [1, 2, 3].iter() {
+            assert!(!id_lookup_batch_prop(v.clone(), ids.clone(),
+                                          NumTries::new(n)).is_failure());
+        }
+    }
+
+    fn count_prefix_prop(v: Vec<String>, prefixes: Vec<String>,
+                         num_tries: NumTries) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_subtree_counts(SubtreeCounts::On);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut distinct: Vec<&[u8]> = v.iter().map(|x| x.as_bytes())
+                                       .collect();
+        distinct.sort();
+        distinct.dedup();
+        let expected = |prefix: &[u8]| {
+            distinct.iter().filter(|x| x.starts_with(prefix)).count()
+        };
+        for s in v.iter() {
+            // Checking every prefix of every key is slow for long keys
+            let lens = [0, 1, 2, s.len() / 2, s.len()];
+            for &i in lens.iter().filter(|&&i| i <= s.len()) {
+                let prefix = &s.as_bytes()[..i];
+                if trie.count_prefix(prefix) != expected(prefix) {
+                    return qc::TestResult::failed();
+                }
+            }
+        }
+        for s in prefixes.iter() {
+            if trie.count_prefix(s.as_bytes()) != expected(s.as_bytes()) {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_count_prefix_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(count_prefix_prop
+                       as fn(Vec<String>, Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_count_prefix_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "applesauce", "apply", "banana",
+                                  "band", "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let prefixes: Vec<String> = vec!["", "a", "appl", "apples", "b",
+                                         "ban", "band", "bandanas", "c",
+                                         "d"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            assert!(!count_prefix_prop(v.clone(), prefixes.clone(),
+                                       NumTries::new(n)).is_failure());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn louds_trie_count_prefix_disabled() {
+        let mut keys: Vec<Key> = vec![Key::new(b"apple")];
+        let trie = LoudsTrie::build(&mut keys, &Config::new());
+        trie.count_prefix(b"a");
+    }
+
+    fn rank_prop(v: Vec<String>, others: Vec<String>, num_tries: NumTries)
+      -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_node_order(NodeOrder::Label)
+                                  .with_subtree_counts(SubtreeCounts::On);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut sorted: Vec<&[u8]> = v.iter().map(|x| x.as_bytes()).collect();
+        sorted.sort();
+        sorted.dedup();
+        for (n, &s) in sorted.iter().enumerate() {
+            let (key, id) = trie.nth_key(n);
+            if &key[..] != s || trie.id_lookup(id) != key
+            || trie.rank_of(s) != n {
+                return qc::TestResult::failed();
+            }
+        }
+        for s in others.iter() {
+            let expected = sorted.iter().filter(|&&x| x < s.as_bytes())
+                           .count();
+            if trie.rank_of(s.as_bytes()) != expected {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_rank_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(rank_prop as fn(Vec<String>, Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_rank_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["banana", "apple", "applesauce", "band",
+                                  "apply", "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let others: Vec<String> = vec!["", "a", "applf", "apples", "b",
+                                       "bandanas", "bane", "c", "d"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            assert!(!rank_prop(v.clone(), others.clone(), NumTries::new(n))
+                    .is_failure());
+        }
+    }
+
+    fn label_ids_prop(v: Vec<String>, num_tries: NumTries, label_order: bool)
+      -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let node_order = if label_order { NodeOrder::Label }
+                         else { NodeOrder::Weight };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_node_order(node_order)
+                                  .with_id_order(IdOrder::Label);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut sorted: Vec<&[u8]> = v.iter().map(|x| x.as_bytes()).collect();
+        sorted.sort();
+        sorted.dedup();
+        for key in keys.iter() {
+            let id = key.get_id();
+            if sorted.binary_search(&key.get_slice()) != Ok(id)
+            || trie.id_lookup(id) != key.get_slice() {
+                return qc::TestResult::failed();
+            }
+            let mut cursor = trie.cursor();
+            for &c in key.get_slice() {
+                cursor.push(c);
+            }
+            if cursor.key_id() != Some(id) {
+                return qc::TestResult::failed();
+            }
+        }
+        for (key, id) in trie.iter() {
+            if sorted.get(id) != Some(&&key[..]) {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_label_ids_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(label_ids_prop as fn(Vec<String>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_label_ids_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["banana", "apple", "applesauce", "band",
+                                  "apple", "apply", "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &label_order in [false, true].iter() {
+                assert!(!label_ids_prop(v.clone(), NumTries::new(n),
+                                        label_order).is_failure());
+            }
+        }
+    }
+
+    fn id_range_prop(v: Vec<String>, prefixes: Vec<String>,
+                     num_tries: NumTries, label_order: bool, label_ids: bool)
+      -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let node_order = if label_order { NodeOrder::Label }
+                         else { NodeOrder::Weight };
+        let id_order = if label_ids { IdOrder::Label } else { IdOrder::Node };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_node_order(node_order)
+                                  .with_id_order(id_order);
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let check = |prefix: &[u8]| {
+            let mut expected: Vec<usize> = keys.iter()
+                .filter(|k| k.get_slice().starts_with(prefix))
+                .map(|k| k.get_id()).collect();
+            expected.sort();
+            expected.dedup();
+            let set = trie.id_range_for_prefix(prefix);
+            let is_range = match set { IdSet::Range(_) => true, _ => false };
+            set.iter().eq(expected.iter().cloned())
+            && set.len() == expected.len()
+            && (is_range || !label_ids)
+        };
+        for s in v.iter() {
+            let lens = [0, 1, s.len() / 2, s.len()];
+            for &i in lens.iter().filter(|&&i| i <= s.len()) {
+                if !check(&s.as_bytes()[..i]) {
+                    return qc::TestResult::failed();
+                }
+            }
+        }
+        for s in prefixes.iter() {
+            if !check(s.as_bytes()) {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_id_range_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(id_range_prop
+                       as fn(Vec<String>, Vec<String>, NumTries, bool, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_id_range_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["banana", "apple", "applesauce", "band",
+                                  "apply", "bandana", "can", "ca"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let prefixes: Vec<String> = vec!["", "a", "appl", "apples", "b",
+                                         "bandanas", "c", "d"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &label_order in [false, true].iter() {
+                for &label_ids in [false, true].iter() {
+                    assert!(!id_range_prop(v.clone(), prefixes.clone(),
+                                           NumTries::new(n), label_order,
+                                           label_ids).is_failure());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn louds_trie_build_manual() {
+        let _ = env_logger::init();
+        let n = NumTries::default();
+        assert!(!build_prop(vec!["\u{80}".to_string()], n).is_failure());
+        assert!(!build_prop(vec!["\u{4b8ca}".to_string(),
+                                 "\u{d2c4a}".to_string()], n).is_failure());
+    }
+
+    fn io_prop(v: Vec<String>, num_tries: NumTries, binary_tail: bool,
+               extras: bool) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let tail_mode = if binary_tail { TailMode::Binary }
+                        else { TailMode::Text };
+        let mut config = Config::new().with_num_tries(num_tries)
+                                      .with_tail_mode(tail_mode);
+        if extras {
+            config = config.with_node_order(NodeOrder::Label)
+                           .with_subtree_counts(SubtreeCounts::On)
+                           .with_id_order(IdOrder::Label)
+                           .with_dict_size(DictSize::Large);
+        }
+        let trie = LoudsTrie::build(&mut keys, &config);
+
+        let mut buf: Vec<u8> = Vec::new();
+        trie.write(&mut buf).unwrap();
+        if buf.len() % 8 != 0 {
+            return qc::TestResult::failed();
+        }
+        let mut slice = &buf[..];
+        let read = LoudsTrie::read(&mut slice).unwrap();
+        if !slice.is_empty() {
+            return qc::TestResult::failed();
+        }
+        let mut rewritten: Vec<u8> = Vec::new();
+        read.write(&mut rewritten).unwrap();
+        if rewritten != buf || read.config_.flags() != trie.config_.flags()
+        || read.dict_size() != config.dict_size() {
+            return qc::TestResult::failed();
+        }
+        // Large dictionaries keep IDs and counts at full width
+        if extras && [&read.subtree_counts_, &read.terminal_ids_,
+                      &read.id_terminals_].iter()
+                     .any(|x| x.dict_size() != DictSize::Large) {
+            return qc::TestResult::failed();
+        }
+        for key in keys.iter() {
+            let id = key.get_id();
+            if read.id_lookup(id) != key.get_slice() {
+                return qc::TestResult::failed();
+            }
+            let mut cursor = read.cursor();
+            for &c in key.get_slice() {
+                cursor.push(c);
+            }
+            if cursor.key_id() != Some(id) {
+                return qc::TestResult::failed();
+            }
+            if extras && (read.count_prefix(key.get_slice())
+                          != trie.count_prefix(key.get_slice())) {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::from_bool(read.sorted_iter().eq(trie.sorted_iter()))
+    }
+
+    fn delete_prop(v: Vec<String>, deleted: Vec<usize>, num_tries: NumTries,
+                   label_ids: bool) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let id_order = if label_ids { IdOrder::Label } else { IdOrder::Node };
+        let node_order = if label_ids { NodeOrder::Label }
+                         else { NodeOrder::Weight };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_id_order(id_order)
+                                  .with_node_order(node_order)
+                                  .with_subtree_counts(SubtreeCounts::On);
+        let mut trie = LoudsTrie::build(&mut keys, &config);
+        if trie.is_empty() {
+            return qc::TestResult::discard();
+        }
+        let mut expected: Vec<Option<usize>> =
+            (0..trie.len()).map(|id| Some(id)).collect();
+        for &id in deleted.iter() {
+            let id = id % trie.len();
+            let key = trie.id_lookup(id);
+            if trie.delete(&key) != expected[id] {
+                return qc::TestResult::failed();
+            }
+            expected[id] = None;
+        }
+        if trie.num_deleted() != expected.iter().filter(|x| x.is_none())
+                                 .count() {
+            return qc::TestResult::failed();
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        trie.write(&mut buf).unwrap();
+        let read = LoudsTrie::read(&mut &buf[..]).unwrap();
+        for trie in [&trie, &read].iter() {
+            for key in keys.iter() {
+                let id = key.get_id();
+                if trie.lookup(key.get_slice()) != expected[id]
+                || trie.is_deleted(id) != expected[id].is_none() {
+                    return qc::TestResult::failed();
+                }
+            }
+            let mut live: Vec<(Vec<u8>, usize)> = trie.iter().collect();
+            live.sort();
+            if !live.iter().all(|&(_, id)| expected[id].is_some())
+            || live.len() != trie.len() - trie.num_deleted()
+            || !trie.sorted_iter().eq(live.iter().cloned()) {
+                return qc::TestResult::failed();
+            }
+            let prefix = &keys[0].get_slice()[..1];
+            let ids: Vec<usize> = trie.id_range_for_prefix(prefix).iter()
+                                  .collect();
+            let mut expected_ids: Vec<usize> = live.iter()
+                .filter(|&&(ref key, _)| key.starts_with(prefix))
+                .map(|&(_, id)| id).collect();
+            expected_ids.sort();
+            if ids != expected_ids
+            || trie.count_prefix(prefix) != expected_ids.len() {
+                return qc::TestResult::failed();
+            }
+            let mut found = trie.predictive_search(prefix);
+            found.sort();
+            if !found.iter().eq(live.iter()
+                                .filter(|&&(ref key, _)| {
+                                    key.starts_with(prefix)
+                                })) {
+                return qc::TestResult::failed();
+            }
+            for key in keys.iter() {
+                let query = key.get_slice();
+                if !trie.common_prefix_search(query).iter()
+                        .eq(live.iter().filter(|&&(ref key, _)| {
+                            query.starts_with(key)
+                        })) {
+                    return qc::TestResult::failed();
+                }
+            }
+            if label_ids {
+                for (n, &(ref key, id)) in live.iter().enumerate() {
+                    if trie.rank_of(key) != n
+                    || trie.nth_key(n) != (key.clone(), id) {
+                        return qc::TestResult::failed();
+                    }
+                }
+            }
+        }
+
+        trie.clear_deleted();
+        qc::TestResult::from_bool(trie.num_deleted() == 0
+                                  && trie.iter().count() == trie.len()
+                                  && trie.count_prefix(&[]) == trie.len())
+    }
+
+    #[test]
+    fn louds_trie_delete_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(delete_prop
+                       as fn(Vec<String>, Vec<usize>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_delete_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "applesauce", "banana", "band",
+                                  "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &label_ids in [false, true].iter() {
+                assert!(!delete_prop(v.clone(), vec![0, 3, 3, 5],
+                                     NumTries::new(n), label_ids)
+                        .is_failure());
+            }
+        }
+
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let mut trie = LoudsTrie::build(&mut keys, &Config::new());
+        let id = trie.delete(b"apple").unwrap();
+        assert!(trie.delete(b"apple") == None);
+        assert!(trie.delete(b"app") == None);
+        assert!(!trie.delete_id(id));
+        let mut cursor = trie.cursor();
+        for &c in b"apple" {
+            assert!(cursor.push(c));
+        }
+        assert!(!cursor.is_key());
+        assert!(cursor.completions().map(|(key, _)| key)
+                .eq(vec![b"applesauce".to_vec()].into_iter()));
+    }
+
+    fn extract_prefix_prop(v: Vec<String>, prefix_len: usize,
+                           strip_prefix: bool, num_tries: NumTries)
+      -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let trie = LoudsTrie::build(&mut keys, &config);
+        // Mostly prefixes of keys, so that there's something to extract
+        let prefix: &[u8] = match keys.first() {
+            Some(key) => {
+                let key = key.get_slice();
+                &key[..std::cmp::min(prefix_len % 4, key.len())]
+            },
+            None => b"x",
+        };
+        let (extracted, id_map) = trie.extract_prefix(prefix, strip_prefix,
+                                                      &config);
+        let skip = if strip_prefix { prefix.len() } else { 0 };
+        let mut expected: Vec<(Vec<u8>, usize)> = trie.iter()
+            .filter(|&(ref key, _)| key.starts_with(prefix))
+            .map(|(key, id)| (key[skip..].to_vec(), id)).collect();
+        expected.sort();
+        if !extracted.sorted_iter().map(|(key, _)| key)
+                     .eq(expected.iter().map(|&(ref key, _)| key.clone()))
+        || id_map.num_old() != trie.len()
+        || id_map.num_new() != extracted.len() {
+            return qc::TestResult::failed();
+        }
+        for &(ref key, old_id) in expected.iter() {
+            let new_id = extracted.lookup(key);
+            if new_id.is_none() || id_map.new_id(old_id) != new_id {
+                return qc::TestResult::failed();
+            }
+        }
+        let num_mapped = (0..trie.len())
+                         .filter(|&id| id_map.new_id(id).is_some()).count();
+        qc::TestResult::from_bool(num_mapped == expected.len())
+    }
+
+    #[test]
+    fn louds_trie_extract_prefix_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(extract_prefix_prop
+                       as fn(Vec<String>, usize, bool, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_extract_prefix_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["en/apple", "en/band", "en", "de/apfel",
+                                  "enough"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &len in [0, 1, 2, 3].iter() {
+                for &strip_prefix in [false, true].iter() {
+                    assert!(!extract_prefix_prop(v.clone(), len, strip_prefix,
+                                                 NumTries::new(n))
+                            .is_failure());
+                }
+            }
+        }
+
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let trie = LoudsTrie::build(&mut keys, &Config::new());
+        let (en, id_map) = trie.extract_prefix(b"en/", true, &Config::new());
+        assert!(en.sorted_iter().map(|(key, _)| key)
+                .eq(vec![b"apple".to_vec(), b"band".to_vec()].into_iter()));
+        assert!(id_map.new_id(trie.lookup(b"en/band").unwrap())
+                == en.lookup(b"band"));
+        assert!(id_map.new_id(trie.lookup(b"enough").unwrap()) == None);
+        let (none, _) = trie.extract_prefix(b"fr/", false, &Config::new());
+        assert!(none.is_empty());
+    }
+
+    fn rebuild_prop(v: Vec<String>, from: (NumTries, bool, bool),
+                    to: (NumTries, bool, bool)) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let config = |(num_tries, binary_tail, label_order)| {
+            let tail_mode = if binary_tail { TailMode::Binary }
+                            else { TailMode::Text };
+            let node_order = if label_order { NodeOrder::Label }
+                             else { NodeOrder::Weight };
+            Config::new().with_num_tries(num_tries).with_tail_mode(tail_mode)
+                         .with_node_order(node_order)
+        };
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let trie = LoudsTrie::build(&mut keys, &config(from));
+        let (rebuilt, id_map) = trie.rebuild_with(&config(to));
+        if rebuilt.node_order() != config(to).node_order()
+        || !rebuilt.sorted_iter().map(|(key, _)| key)
+                   .eq(trie.sorted_iter().map(|(key, _)| key)) {
+            return qc::TestResult::failed();
+        }
+        let pinned = trie.rebuild_keeping_ids(&config(to));
+        for key in keys.iter() {
+            let id = key.get_id();
+            if id_map.new_id(id) != rebuilt.lookup(key.get_slice())
+            || pinned.lookup(key.get_slice()) != Some(id) {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_rebuild_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(rebuild_prop
+                       as fn(Vec<String>, (NumTries, bool, bool),
+                             (NumTries, bool, bool)) -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_rebuild_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "applesauce", "banana", "band",
+                                  "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &label_order in [false, true].iter() {
+                let from = (NumTries::new(n), false, label_order);
+                let to = (NumTries::new(4 - n), true, !label_order);
+                assert!(!rebuild_prop(v.clone(), from, to).is_failure());
+            }
+        }
+
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_id_order(IdOrder::Label);
+        let mut trie = LoudsTrie::build(&mut keys, &config);
+        let band = trie.delete(b"band").unwrap();
+        let (rebuilt, id_map) = trie.rebuild_with(&config);
+        assert!(rebuilt.len() == 5 && rebuilt.num_deleted() == 0);
+        assert!(rebuilt.lookup(b"band") == None);
+        assert!(id_map.new_id(band) == None);
+        // Label order IDs only shift past the deleted key
+        assert!(rebuilt.lookup(b"apple") == trie.lookup(b"apple"));
+        let can = trie.lookup(b"can").unwrap();
+        assert!(rebuilt.lookup(b"can") == Some(can - 1));
+    }
+
+    #[test]
+    fn louds_trie_build_remapped_manual() {
+        let _ = env_logger::init();
+        let old: Vec<&str> = vec!["apple", "banana", "band", "can"];
+        let new: Vec<&str> = vec!["aardvark", "apple", "band", "can", "dog"];
+        let mut keys: Vec<Key> = old.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let mut previous = LoudsTrie::build(&mut keys, &Config::new());
+        let old_ids: Vec<(&[u8], usize)> =
+            keys.iter().map(|k| (k.get_slice(), k.get_id())).collect();
+        previous.delete(b"can");
+        let mut keys: Vec<Key> = new.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let (trie, id_map) = LoudsTrie::build_remapped(&mut keys,
+                                                       &Config::new(),
+                                                       &previous);
+        assert!(id_map.num_old() == 4 && id_map.num_new() == 5);
+        for &(key, old_id) in old_ids.iter() {
+            let new_id = trie.lookup(key);
+            match key {
+                b"banana" | b"can" => {
+                    assert!(id_map.new_id(old_id) == None);
+                },
+                _ => {
+                    assert!(id_map.new_id(old_id) == new_id);
+                    assert!(id_map.old_id(new_id.unwrap()) == Some(old_id));
+                },
+            }
+        }
+        for key in [&b"aardvark"[..], b"dog"].iter() {
+            assert!(id_map.old_id(trie.lookup(key).unwrap()) == None);
+        }
+    }
+
+    #[test]
+    fn louds_trie_io_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(io_prop as fn(Vec<String>, NumTries, bool, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_io_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = vec!["apple", "applesauce", "banana", "band",
+                                  "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &binary_tail in [false, true].iter() {
+                for &extras in [false, true].iter() {
+                    assert!(!io_prop(v.clone(), NumTries::new(n), binary_tail,
+                                     extras).is_failure());
+                    assert!(!io_prop(vec![], NumTries::new(n), binary_tail,
+                                     extras).is_failure());
+                }
+            }
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        LoudsTrie::<BitVec>::new().write(&mut buf).unwrap();
+        // A trie that was never built has no cache
+        assert!(LoudsTrie::read(&mut &buf[..]).unwrap().is_empty());
+        let mut slice = &buf[..buf.len() - 1];
+        assert!(LoudsTrie::read(&mut slice).is_err());
+        buf[0] = b'w';
+        let mut slice = &buf[..];
+        assert!(LoudsTrie::read(&mut slice).is_err());
+    }
+
+    #[test]
+    fn louds_trie_sparse_flags_manual() {
+        let _ = env_logger::init();
+        // Thousands of one-byte labels and a single link
+        let mut v: Vec<Vec<u8>> = Vec::new();
+        for a in b'a'..b'z' + 1 {
+            for b in b'a'..b'z' + 1 {
+                for c in b'a'..b'z' + 1 {
+                    v.push(vec![a, b, c]);
+                }
+            }
+        }
+        v.push(b"qqqqqqqqqqqqqqqq".to_vec());
+        for &n in [1, 2, 3].iter() {
+            let mut keys: Vec<Key> = v.iter().map(|k| Key::new(&k[..]))
+                                     .collect();
+            let config = Config::new().with_num_tries(NumTries::new(n));
+            let trie = LoudsTrie::build(&mut keys, &config);
+            assert!(trie.link_flags_.is_sparse());
+            assert!(!trie.terminal_flags_.is_sparse());
+            let mut buf: Vec<u8> = Vec::new();
+            trie.write(&mut buf).unwrap();
+            let read = LoudsTrie::read(&mut &buf[..]).unwrap();
+            assert!(read.link_flags_.is_sparse());
+            let mut rewritten: Vec<u8> = Vec::new();
+            read.write(&mut rewritten).unwrap();
+            assert!(rewritten == buf);
+
+            // Large dictionaries keep the sparse form on disk too
+            let config = config.with_dict_size(DictSize::Large);
+            let large = LoudsTrie::build(&mut keys, &config);
+            let mut large_buf: Vec<u8> = Vec::new();
+            large.write(&mut large_buf).unwrap();
+            assert!(large_buf.len() < buf.len());
+            let large_read = LoudsTrie::read(&mut &large_buf[..]).unwrap();
+            assert!(large_read.link_flags_.is_sparse());
+            let mut rewritten: Vec<u8> = Vec::new();
+            large_read.write(&mut rewritten).unwrap();
+            assert!(rewritten == large_buf);
+            // Whatever bit vectors the reader holds them in
+            let plain = LoudsTrie::<BitVec>::read_with_louds(
+                &mut &large_buf[..]).unwrap();
+            for t in [&trie, &read, &large_read].iter() {
+                for key in keys.iter() {
+                    assert!(t.lookup(key.get_slice()) == Some(key.get_id()));
+                    assert!(t.id_lookup(key.get_id()) == key.get_slice());
+                }
+            }
+            for key in keys.iter() {
+                assert!(plain.lookup(key.get_slice()) == Some(key.get_id()));
+            }
+        }
+    }
+
+    #[test]
+    fn louds_trie_large_manual() {
+        let _ = env_logger::init();
+        let v: Vec<&str> = vec!["apple", "applesauce", "banana", "band"];
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let standard = LoudsTrie::build(&mut keys, &Config::new());
+        assert!(standard.dict_size() == DictSize::Standard);
+        let config = Config::new().with_dict_size(DictSize::Large);
+        let large = LoudsTrie::build(&mut keys, &config);
+        assert!(large.dict_size() == DictSize::Large);
+
+        let mut standard_buf: Vec<u8> = Vec::new();
+        standard.write(&mut standard_buf).unwrap();
+        let mut large_buf: Vec<u8> = Vec::new();
+        large.write(&mut large_buf).unwrap();
+        assert!(&standard_buf[..16] == b"We love Marisa.\0");
+        assert!(&large_buf[..16] == b"We love Marisa64");
+        // Only a tag saying which form each bit vector is in differs while
+        // everything is small
+        assert!(large_buf.len() == standard_buf.len() + 8 * 3 * 3);
+        let read = LoudsTrie::read(&mut &large_buf[..]).unwrap();
+        assert!(read.dict_size() == DictSize::Large);
+        for key in keys.iter() {
+            assert!(read.lookup(key.get_slice()) == Some(key.get_id()));
+        }
+
+        // Wide bit vectors are only readable as a large dictionary
+        let config = Config::new().with_rank_layout(RankLayout::Wide);
+        let wide = LoudsTrie::build(&mut keys, &config);
+        assert!(wide.dict_size() == DictSize::Large
+                && wide.louds_.layout() == RankLayout::Wide);
+        let mut wide_buf: Vec<u8> = Vec::new();
+        wide.write(&mut wide_buf).unwrap();
+        assert!(&wide_buf[..16] == b"We love Marisa64"
+                && wide_buf.len() > large_buf.len());
+        let read = LoudsTrie::read(&mut &wide_buf[..]).unwrap();
+        for key in keys.iter() {
+            assert!(read.lookup(key.get_slice()) == Some(key.get_id())
+                    && read.id_lookup(key.get_id()) == key.get_slice());
+        }
+    }
+
+    /// The build-time types of large dictionaries hold values past 32 bits
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn louds_trie_wide_keys_manual() {
+        let big = (1usize << 40) + 3;
+        let mut key: Key<usize> = Key::new(b"apple");
+        key.set_terminal(big);
+        key.set_id(big + 1);
+        assert!(key.get_terminal() == big && key.get_id() == big + 1);
+        let mut reverse: ReverseKey<usize> = ReverseKey::from_key(&key);
+        reverse.set_terminal(big);
+        assert!(reverse.get_terminal() == big && reverse.at(0) == b'e');
+        let mut weighted: Key<usize> = Key::new(b"apple").with_weight(2.5);
+        assert!(weighted.get_weight() == 2.5);
+        weighted.set_id(big);
+        assert!(weighted.get_weight() == 2.5);
+        let range: Range<usize> = Range::new(big, big + 2, 5);
+        assert!(range.begin() == big && range.end() == big + 2
+                && range.key_pos() == 5);
+
+        // Keys with wide IDs build like any others
+        let mut keys: Vec<Key<usize>> = ["apple", "band", "bandana"].iter()
+            .map(|s| Key::new(s.as_bytes())).collect();
+        let config = Config::new().with_dict_size(DictSize::Large);
+        let trie = LoudsTrie::build(&mut keys, &config);
+        for key in keys.iter() {
+            assert!(trie.lookup(key.get_slice()) == Some(key.get_id()));
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[should_panic(expected = "MARISA_SIZE_ERROR")]
+    fn louds_trie_narrow_keys_manual() {
+        let mut key: Key = Key::new(b"apple");
+        key.set_terminal(std::u32::MAX as usize + 1);
+    }
+
+    /// A trie with its bit vectors in a `SparseBitVec` must behave like the
+    /// default one, and be written the same way
+    fn sparse_louds_prop(v: Vec<String>, others: Vec<String>,
+                         num_tries: NumTries) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let trie = LoudsTrie::build(&mut keys, &config);
+        let sparse: LoudsTrie<SparseBitVec> =
+            LoudsTrie::build_with_louds(&mut keys, &config);
+        for s in v.iter().chain(others.iter()) {
+            if sparse.lookup(s.as_bytes()) != trie.lookup(s.as_bytes()) {
+                return qc::TestResult::failed();
+            }
+        }
+        if (0..trie.len()).any(|id| sparse.id_lookup(id) != trie.id_lookup(id))
+        || !sparse.iter().eq(trie.iter())
+        || !sparse.sorted_iter().eq(trie.sorted_iter()) {
+            return qc::TestResult::failed();
+        }
+        let (mut buf, mut sparse_buf) = (Vec::new(), Vec::new());
+        trie.write(&mut buf).unwrap();
+        sparse.write(&mut sparse_buf).unwrap();
+        let read: LoudsTrie<SparseBitVec> =
+            LoudsTrie::read_with_louds(&mut &buf[..]).unwrap();
+        let plain: LoudsTrie<BitVec> =
+            LoudsTrie::read_with_louds(&mut &buf[..]).unwrap();
+        let (rebuilt, _) = read.rebuild_with(&config);
+        if sparse_buf != buf || !read.iter().eq(trie.iter())
+        || !plain.iter().eq(trie.iter())
+        || !sparse.union(&read).eq(trie.union(&trie))
+        || !sparse.difference(&rebuilt).eq(trie.difference(&trie))
+        || sparse.diff(&read).next_entry() != trie.diff(&trie).next_entry() {
+            return qc::TestResult::failed();
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_sparse_louds_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(sparse_louds_prop
+                       as fn(Vec<String>, Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    /// Select intervals only change how fast the bit vectors are, so tries
+    /// built with any of them must answer the same, before and after a
+    /// round trip through `write` and `read`
+    fn select_interval_prop(v: Vec<String>, others: Vec<String>,
+                            num_tries: NumTries) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let trie = LoudsTrie::build(&mut keys, &config);
+        for &interval in [64, 4096, 1 << 16].iter() {
+            let config = config
+                         .with_select_interval(SelectInterval::new(interval));
+            let built = LoudsTrie::build(&mut keys, &config);
+            let mut buf: Vec<u8> = Vec::new();
+            built.write(&mut buf).unwrap();
+            let read = LoudsTrie::read(&mut &buf[..]).unwrap();
+            // marisa-trie would misread the samples, so the standard header
+            // is refused unless there are too few to tell the intervals apart
+            buf[14..16].copy_from_slice(b".\0");
+            let standard = LoudsTrie::read(&mut &buf[..]);
+            if trie.dict_size() != DictSize::Standard
+            || built.dict_size() != DictSize::Large
+            || read.dict_size() != DictSize::Large {
+                return qc::TestResult::failed();
+            }
+            let mut tries = vec![&built, &read];
+            tries.extend(standard.as_ref().ok());
+            for x in tries.iter() {
+                if v.iter().chain(others.iter()).any(|s| {
+                    x.lookup(s.as_bytes()) != trie.lookup(s.as_bytes())
+                })
+                || (0..trie.len()).any(|id| {
+                    x.id_lookup(id) != trie.id_lookup(id)
+                })
+                || !x.iter().eq(trie.iter()) {
+                    return qc::TestResult::failed();
+                }
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_select_interval_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(select_interval_prop
+                       as fn(Vec<String>, Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_select_interval_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = (0..1000).map(|i| format!("{}", i * 7)).collect();
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new()
+                     .with_select_interval(SelectInterval::new(64));
+        let trie = LoudsTrie::build(&mut keys, &config);
+        assert!(trie.dict_size() == DictSize::Large);
+        let mut buf: Vec<u8> = Vec::new();
+        trie.write(&mut buf).unwrap();
+        assert!(LoudsTrie::read(&mut &buf[..]).is_ok());
+        buf[14..16].copy_from_slice(b".\0");
+        assert!(LoudsTrie::read(&mut &buf[..]).is_err());
+        assert!(!select_interval_prop(v, vec![], Default::default())
+                .is_failure());
+    }
+
+    /// Rank blocks only change how fast and big the bit vectors are, so tries
+    /// built with any of them must answer the same, before and after a round
+    /// trip through `write` and `read`. Only the default fits marisa-trie's
+    /// format.
+    fn rank_block_prop(v: Vec<String>, others: Vec<String>,
+                       num_tries: NumTries) -> qc::TestResult {
+        if v.iter().any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let config = Config::new().with_num_tries(num_tries);
+        let trie = LoudsTrie::build(&mut keys, &config);
+        for &bits in [256, 1024].iter() {
+            let config = config.with_rank_block(RankBlock::new(bits));
+            let built = LoudsTrie::build(&mut keys, &config);
+            let mut buf: Vec<u8> = Vec::new();
+            built.write(&mut buf).unwrap();
+            let read = LoudsTrie::read(&mut &buf[..]).unwrap();
+            let plain = LoudsTrie::<BitVec>::read_with_louds(&mut &buf[..])
+                        .unwrap();
+            if built.dict_size() != DictSize::Large
+            || read.dict_size() != DictSize::Large
+            || plain.louds_.rank_block() != RankBlock::new(bits)
+            || plain.terminal_flags_.rank_block() != RankBlock::new(bits) {
+                return qc::TestResult::failed();
+            }
+            for x in [&built, &read].iter() {
+                if v.iter().chain(others.iter()).any(|s| {
+                    x.lookup(s.as_bytes()) != trie.lookup(s.as_bytes())
+                })
+                || (0..trie.len()).any(|id| {
+                    x.id_lookup(id) != trie.id_lookup(id)
+                })
+                || !x.iter().eq(trie.iter()) {
+                    return qc::TestResult::failed();
+                }
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn louds_trie_rank_block_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(rank_block_prop
+                       as fn(Vec<String>, Vec<String>, NumTries)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn louds_trie_rank_block_manual() {
+        let _ = env_logger::init();
+        let v: Vec<String> = (0..1000).map(|i| format!("{}", i * 7)).collect();
+        assert!(!rank_block_prop(v, vec![], Default::default()).is_failure());
     }
 
 /*
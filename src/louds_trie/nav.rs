@@ -1,10 +1,11 @@
 use std;
 use base::*;
-use super::{LoudsTrie, NodeID, LoudsPos, LinkID, INVALID_LINK_ID};
+use config::NodeOrder;
+use super::{LoudsTrie, LoudsBits, NodeID, LoudsPos, LinkID, INVALID_LINK_ID};
+use vector::bit_flags::BitFlags;
 
-#[derive(Copy, Clone)]
-struct State<'a> {
-    trie_: &'a LoudsTrie,
+struct State<'a, L: 'a = BitFlags> {
+    trie_: &'a LoudsTrie<L>,
     node_id_: NodeID,
     louds_pos_: LoudsPos,
     link_id_: LinkID,
@@ -12,7 +13,15 @@ struct State<'a> {
     //key_id_: u32,
 }
 
-impl<'a> std::fmt::Debug for State<'a> {
+impl<'a, L> Clone for State<'a, L> {
+    fn clone(&self) -> State<'a, L> {
+        *self
+    }
+}
+
+impl<'a, L> Copy for State<'a, L> {}
+
+impl<'a, L> std::fmt::Debug for State<'a, L> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         fmt.debug_struct("State")
             .field("node_id_", &self.node_id_)
@@ -23,18 +32,18 @@ impl<'a> std::fmt::Debug for State<'a> {
     }
 }
 
-impl<'a> State<'a> {
-    fn new(trie: &'a LoudsTrie, node_id: NodeID, louds_pos: LoudsPos,
-           link_id: LinkID, key_pos: u32) -> State<'a> {
+impl<'a, L> State<'a, L> {
+    fn new(trie: &'a LoudsTrie<L>, node_id: NodeID, louds_pos: LoudsPos,
+           link_id: LinkID, key_pos: u32) -> State<'a, L> {
         State { trie_: trie, node_id_: node_id, louds_pos_: louds_pos,
                 link_id_: link_id, key_pos_: key_pos }
     }
 }
 
 #[derive(Debug)]
-pub struct Nav<'a> {
-    trie_: &'a LoudsTrie,
-    history_: Vec<State<'a> >,
+pub struct Nav<'a, L: 'a = BitFlags> {
+    trie_: &'a LoudsTrie<L>,
+    history_: Vec<State<'a, L> >,
     key_buf_: Vec<u8>,
 }
 
@@ -42,11 +51,21 @@ pub struct Nav<'a> {
 // We can't do that here. May want to remove or rethink the cache
 // implementation in light of this.
 
-impl<'a> Nav<'a> {
-    fn new(trie: &'a LoudsTrie) -> Nav<'a> {
+impl<'a, L: 'a + LoudsBits> Nav<'a, L> {
+    pub fn new(trie: &'a LoudsTrie<L>) -> Nav<'a, L> {
+        Nav::from_node(trie, NodeID(0), LoudsPos(0), &[])
+    }
+
+    /// Start navigating at an arbitrary node of the root trie. `key` is the
+    /// string spelled out by the path from the root down to (and including)
+    /// that node. The starting node acts as the root of the navigation: it
+    /// has no parent or siblings as far as the `Nav` is concerned.
+    pub fn from_node(trie: &'a LoudsTrie<L>, node_id: NodeID,
+                     louds_pos: LoudsPos, key: &[u8]) -> Nav<'a, L> {
         let mut out = Nav { trie_: trie, history_: Vec::new(),
                             key_buf_: Vec::new() };
-        out.history_.push(State::new(trie, NodeID(0), LoudsPos(0),
+        out.key_buf_.extend(key);
+        out.history_.push(State::new(trie, node_id, louds_pos,
                           INVALID_LINK_ID, 0));
         out
     }
@@ -55,61 +74,21 @@ impl<'a> Nav<'a> {
         self.history_.last().unwrap().link_id_
     }
 
-    fn push_str<'b>(&mut self, key: &'b[u8], trie: &'a LoudsTrie, node_id: NodeID,
-                    louds_pos: LoudsPos, link_id: LinkID) {
-
-        debug!("push_str(key: {:?})", key);
-
+    fn push(&mut self, node_id: NodeID, louds_pos: LoudsPos) {
+        debug!("push (node_id: {:?}, louds_pos: {:?})", node_id, louds_pos);
+        let trie = self.trie_;
+        let link_id = if trie.link_flags_.at(node_id.0) {
+            trie.get_linked_ids(node_id.0).1
+        } else {
+            INVALID_LINK_ID
+        };
         let old_len = self.key_buf_.len();
-        self.key_buf_.extend(key);
         assert!(old_len <= std::u32::MAX as usize);
+        trie.label_into(node_id.0, &mut self.key_buf_);
+        debug!("  label: {:?}", &self.key_buf_[old_len..]);
         self.history_.push(State::new(trie, node_id, louds_pos, link_id,
                                       old_len as u32));
     }
-
-    fn push(&mut self, mut node_id: NodeID, louds_pos: LoudsPos) {
-        debug!("push (node_id: {:?}, louds_pos: {:?})", node_id, louds_pos);
-        let mut trie = self.history_.last().unwrap().trie_;
-        loop {
-            debug!("push loop");
-            if self.trie_.link_flags_.at(node_id.0 as usize) {
-                debug!("Link flags TRUE");
-                let (linked_node_id, link_id) =
-                    self.trie_.get_linked_ids(node_id.0 as usize);
-                debug!("linked_node_id: {:?}, link_id: {:?}",
-                       linked_node_id, link_id);
-                // Proceed either to next trie or tail
-                match &trie.next_trie_ {
-                    &Some(ref next_trie) => {
-                        debug!("Link TRUE--next trie");
-                        trie = &**next_trie;
-                        node_id = linked_node_id; // not sure about this
-                        continue;
-                    },
-                    &None => {
-                        debug!("Link TRUE--tail");
-                        // FIXME: Shouldn't need this temporary vector.
-                        //        'restore' should return an iterator, and
-                        //        state.push should consume it.
-                        let mut v = Vec::new();
-                        trie.tail_.restore(linked_node_id.0 as usize, &mut v);
-
-                        // Not sure if these values are correct/useful.
-                        // If some stuff is only needed for some nodes...
-                        // should reflect that in the types we use
-                        self.push_str(&v, trie, node_id, louds_pos, link_id);
-                    }
-                }
-            } else {
-                debug!("Link flags FALSE");
-                let node_char = [ trie.bases_[node_id.0 as usize] ];
-                self.push_str(&node_char, trie, node_id, louds_pos,
-                              INVALID_LINK_ID);
-            }
-            break;
-        }
-        debug!("done with push");
-    }
     pub fn has_child(&self) -> bool {
         self.history_.last().map(|s| s.trie_.has_child(s.node_id_))
             .unwrap_or(false)
@@ -133,7 +112,7 @@ impl<'a> Nav<'a> {
     pub fn has_prev_sibling(&self) -> bool {
         // FIXME: Is this all...?
         self.history_.last().map(|h| {
-            h.trie_.louds_.at(h.louds_pos_.0 as usize - 1)
+            h.trie_.louds_.at(h.louds_pos_.0 - 1)
         }).unwrap_or(false)
     }
     pub fn go_to_prev_sibling(&mut self) -> bool {
@@ -146,16 +125,21 @@ impl<'a> Nav<'a> {
     }
     pub fn has_sibling(&self) -> bool {
         self.history_.last().map(|h| {
-            h.trie_.louds_.at(h.louds_pos_.0 as usize + 1)
+            h.trie_.louds_.at(h.louds_pos_.0 + 1)
         }).unwrap_or(false)
     }
     pub fn go_to_sibling(&mut self) -> bool {
         debug!("go_to_sibling");
+        if self.history_.len() <= 1 {
+            // Never leave the starting node
+            debug!("  at starting node");
+            return false;
+        }
         if let Some(&s) = self.history_.last() {
             let cur_len = self.key_buf_.len();
             assert!((s.key_pos_ as usize) <= cur_len);
             self.key_buf_.truncate(s.key_pos_ as usize);
-            if s.trie_.louds_.at(s.louds_pos_.0 as usize + 1) {
+            if s.trie_.louds_.at(s.louds_pos_.0 + 1) {
                 debug!("  (node_id: {:?} louds_pos: {:?})",
                        s.node_id_.0 + 1, s.louds_pos_.0 + 1);
                 self.history_.pop();
@@ -179,6 +163,10 @@ impl<'a> Nav<'a> {
         // Could use LOUDS-trie select1(rank0(m)) to navigate upward (within a
         // single trie), but it's probably more efficient just to keep a stack
         // and pop to go up
+        if self.history_.len() <= 1 {
+            debug!("  at starting node -> false");
+            return false;
+        }
         if let Some(s) = self.history_.pop() {
             let cur_len = self.key_buf_.len();
             assert!((s.key_pos_ as usize) <= cur_len);
@@ -198,9 +186,14 @@ impl<'a> Nav<'a> {
     pub fn is_leaf(&self) -> bool {
         self.history_.last().map(|s| {
             // Use root trie
-            self.trie_.terminal_flags_.at(s.node_id_.0 as usize)
+            self.trie_.terminal_flags_.at(s.node_id_.0)
         }).unwrap_or(false)
     }
+    /// Key ID of the current node. Only meaningful if `is_leaf` is true.
+    pub fn key_id(&self) -> usize {
+        let node_id = self.history_.last().unwrap().node_id_;
+        self.trie_.key_id(node_id.0)
+    }
     //pub fn get_string(&self) -> &str {
     //    panic!("not implemented")
     //}
@@ -224,7 +217,10 @@ impl DFT {
     fn new() -> DFT {
         DFT::ToChild
     }
-    fn depth_first_traversal_step<'a>(&mut self, nav: &mut Nav<'a>) -> bool {
+    fn depth_first_traversal_step<'a, L>(&mut self, nav: &mut Nav<'a, L>)
+      -> bool
+        where L: 'a + LoudsBits
+    {
         match *self {
             DFT::ToChild => {
                 if nav.go_to_child() {
@@ -259,20 +255,188 @@ impl DFT {
             }
         }
     }
-    fn next_terminal<'a, 'b>(&mut self, nav: &'b mut Nav<'a>)
-      -> Option<&'b[u8]> {
+    fn next_terminal<'a, 'b, L>(&mut self, nav: &'b mut Nav<'a, L>)
+      -> Option<&'b[u8]>
+        where L: 'a + LoudsBits
+    {
+        if self.to_next_terminal(nav) {
+            Some(nav.get_u8())
+        } else {
+            None
+        }
+    }
+    fn to_next_terminal<'a, L>(&mut self, nav: &mut Nav<'a, L>) -> bool
+        where L: 'a + LoudsBits
+    {
         loop {
             match *self {
-                DFT::End => { return None; },
+                DFT::End => { return false; },
                 _ => (),
             }
             if self.depth_first_traversal_step(nav) && nav.is_leaf() {
-                return Some(nav.get_u8());
+                return true;
             }
         }
     }
 }
 
+/// Depth-first walk over the keys at and below the starting node of a `Nav`.
+/// Keys come out in node order, which is label order if the trie was built
+/// with `NodeOrder::Label`.
+///
+/// The key buffer is shared along the current path, so `next_key` doesn't
+/// allocate. The `Iterator` implementation copies each key out.
+#[derive(Debug)]
+pub struct Keys<'a, L: 'a = BitFlags> {
+    nav_: Nav<'a, L>,
+    dft_: DFT,
+    at_start_: bool,
+}
+
+impl<'a, L: 'a + LoudsBits> Keys<'a, L> {
+    pub fn new(nav: Nav<'a, L>) -> Keys<'a, L> {
+        Keys { nav_: nav, dft_: DFT::new(), at_start_: true }
+    }
+
+    /// A walk that yields nothing
+    pub fn empty(trie: &'a LoudsTrie<L>) -> Keys<'a, L> {
+        Keys { nav_: Nav::new(trie), dft_: DFT::End, at_start_: false }
+    }
+
+    /// Advance to the next key that hasn't been deleted, returning it along
+    /// with its key ID
+    pub fn next_key(&mut self) -> Option<(&[u8], usize)> {
+        loop {
+            let found = if self.at_start_ {
+                self.at_start_ = false;
+                self.nav_.is_leaf()
+                || self.dft_.to_next_terminal(&mut self.nav_)
+            } else {
+                self.dft_.to_next_terminal(&mut self.nav_)
+            };
+            if !found {
+                return None;
+            }
+            if !self.nav_.trie_.is_deleted(self.nav_.key_id()) {
+                break;
+            }
+        }
+        Some((self.nav_.get_u8(), self.nav_.key_id()))
+    }
+}
+
+impl<'a, L: 'a + LoudsBits> Iterator for Keys<'a, L> {
+    type Item = (Vec<u8>, usize);
+    fn next(&mut self) -> Option<(Vec<u8>, usize)> {
+        self.next_key().map(|(key, id)| (key.to_vec(), id))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SortedFrame {
+    /// This node's children are `children_[begin_..end_]`, and the ones not
+    /// visited yet start at `next_`
+    begin_: usize,
+    next_: usize,
+    end_: usize,
+    /// Length of this node's key
+    key_len_: usize,
+}
+
+/// Depth-first walk over all keys in label order, whatever the trie's
+/// `NodeOrder`. With `NodeOrder::Weight`, each node's children are sorted by
+/// the first byte of their labels as the walk reaches them.
+///
+/// Like `Keys`, `next_key` shares one key buffer along the current path.
+#[derive(Debug)]
+pub struct SortedKeys<'a, L: 'a = BitFlags> {
+    trie_: &'a LoudsTrie<L>,
+    stack_: Vec<SortedFrame>,
+    /// Children of every node on the stack, each node's run sorted
+    children_: Vec<(u8, NodeID)>,
+    key_buf_: Vec<u8>,
+    label_buf_: Vec<u8>,
+    at_start_: bool,
+}
+
+impl<'a, L: 'a + LoudsBits> SortedKeys<'a, L> {
+    pub fn new(trie: &'a LoudsTrie<L>) -> SortedKeys<'a, L> {
+        SortedKeys { trie_: trie, stack_: Vec::new(), children_: Vec::new(),
+                     key_buf_: Vec::new(), label_buf_: Vec::new(),
+                     at_start_: true }
+    }
+
+    /// Advance to the next key, returning it along with its key ID
+    pub fn next_key(&mut self) -> Option<(&[u8], usize)> {
+        let trie = self.trie_;
+        if self.at_start_ {
+            self.at_start_ = false;
+            if trie.is_empty() {
+                return None;
+            }
+            self.push(NodeID(0));
+            if trie.terminal_flags_.at(0) && !trie.is_deleted(trie.key_id(0)) {
+                return Some((&self.key_buf_[..], trie.key_id(0)));
+            }
+        }
+        loop {
+            let frame = match self.stack_.last() {
+                Some(&frame) => frame,
+                None => { return None; }
+            };
+            if frame.next_ == frame.end_ {
+                self.stack_.pop();
+                self.children_.truncate(frame.begin_);
+                continue;
+            }
+            self.stack_.last_mut().unwrap().next_ += 1;
+            let child_id = self.children_[frame.next_].1;
+            self.key_buf_.truncate(frame.key_len_);
+            trie.label_into(child_id.0, &mut self.key_buf_);
+            self.push(child_id);
+            let node_id = child_id.0;
+            if trie.terminal_flags_.at(node_id)
+            && !trie.is_deleted(trie.key_id(node_id)) {
+                return Some((&self.key_buf_[..], trie.key_id(node_id)));
+            }
+        }
+    }
+
+    /// The key last returned by `next_key`
+    pub fn key(&self) -> &[u8] {
+        &self.key_buf_[..]
+    }
+
+    fn push(&mut self, node_id: NodeID) {
+        let trie = self.trie_;
+        let begin = self.children_.len();
+        if let Some((mut child_id, mut louds_pos)) = trie.child_pos(node_id) {
+            loop {
+                let front = trie.label_front(child_id, &mut self.label_buf_);
+                self.children_.push((front, child_id));
+                if !trie.louds_.at(louds_pos.0 + 1) {
+                    break;
+                }
+                child_id = NodeID(child_id.0 + 1);
+                louds_pos = LoudsPos(louds_pos.0 + 1);
+            }
+        }
+        if trie.node_order() != NodeOrder::Label {
+            self.children_[begin..].sort_by_key(|&(front, _)| front);
+        }
+        self.stack_.push(SortedFrame { begin_: begin, next_: begin,
+                                       end_: self.children_.len(),
+                                       key_len_: self.key_buf_.len() });
+    }
+}
+
+impl<'a, L: 'a + LoudsBits> Iterator for SortedKeys<'a, L> {
+    type Item = (Vec<u8>, usize);
+    fn next(&mut self) -> Option<(Vec<u8>, usize)> {
+        self.next_key().map(|(key, id)| (key.to_vec(), id))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use env_logger;
@@ -328,6 +492,7 @@ mod test {
         }
 
         vv1.sort();
+        vv1.dedup();
         vv2.sort();
         debug!("vv1: {:?}", vv1);
         debug!("vv2: {:?}", vv2);
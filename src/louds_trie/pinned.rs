@@ -0,0 +1,235 @@
+use std::io;
+use std::io::{Read, Write};
+use base::BuildIndex;
+use config::Config;
+use io::{Reader, Writer, format_error};
+use key::Key;
+use super::{LoudsBits, LoudsTrie};
+use super::diff::DiffEntry;
+use super::id_map::IdMap;
+use vector::bit_flags::BitFlags;
+
+/// A trie whose key IDs survive rebuilds.
+///
+/// Callers see stable IDs, which go through an `IdMap` to the IDs of the
+/// trie underneath. On `rebuild`, keys that were already present keep their
+/// stable IDs, new keys get IDs after every stable ID handed out so far, and
+/// the IDs of removed keys are never reused.
+#[derive(Debug)]
+pub struct PinnedTrie<L = BitFlags> {
+    trie_: LoudsTrie<L>,
+    /// Stable IDs are the old side, IDs of `trie_` the new side
+    ids_: IdMap,
+}
+
+impl PinnedTrie {
+    pub fn read(reader: &mut Read) -> io::Result<PinnedTrie> {
+        PinnedTrie::read_with_louds(reader)
+    }
+}
+
+impl<L: LoudsBits> PinnedTrie<L> {
+    /// Start out with the trie's own IDs
+    pub fn new(trie: LoudsTrie<L>) -> PinnedTrie<L> {
+        let ids = IdMap::identity(trie.len(), trie.dict_size());
+        PinnedTrie { trie_: trie, ids_: ids }
+    }
+
+    /// Wrap `trie`, with `ids` mapping stable IDs to the trie's IDs
+    pub fn with_ids(trie: LoudsTrie<L>, ids: IdMap) -> PinnedTrie<L> {
+        assert!(ids.num_new() == trie.len()
+                && ids.dict_size() == trie.dict_size(), "MARISA_SIZE_ERROR");
+        PinnedTrie { trie_: trie, ids_: ids }
+    }
+
+    pub fn trie(&self) -> &LoudsTrie<L> {
+        &self.trie_
+    }
+    pub fn id_map(&self) -> &IdMap {
+        &self.ids_
+    }
+
+    /// Number of stable IDs handed out, including those of removed keys
+    pub fn num_ids(&self) -> usize {
+        self.ids_.num_old()
+    }
+
+    /// Stable ID of `key`
+    pub fn lookup(&self, key: &[u8]) -> Option<usize> {
+        self.trie_.lookup(key).and_then(|id| self.ids_.old_id(id))
+    }
+
+    /// Key with the given stable ID, unless it has been removed
+    pub fn id_lookup(&self, id: usize) -> Option<Vec<u8>> {
+        if id >= self.num_ids() {
+            return None;
+        }
+        self.ids_.new_id(id).map(|id| self.trie_.id_lookup(id))
+    }
+
+    /// Build a trie from `keys`, keeping the stable ID of every key that is
+    /// already here. The keys get the IDs of the trie underneath, as with
+    /// `LoudsTrie::build`.
+    pub fn rebuild<'a, I: BuildIndex>(&self, keys: &mut Vec<Key<'a, I>>,
+                                      config: &Config) -> PinnedTrie<L> {
+        let trie = LoudsTrie::build_with_louds(keys, config);
+        let mut stable_ids: Vec<Option<usize>> = vec![None; self.num_ids()];
+        let mut added: Vec<usize> = Vec::new();
+        {
+            let mut diff = self.trie_.diff(&trie);
+            while let Some(entry) = diff.next_entry() {
+                match entry {
+                    DiffEntry::Kept { old_id, new_id, .. } => {
+                        if let Some(stable_id) = self.ids_.old_id(old_id) {
+                            stable_ids[stable_id] = Some(new_id);
+                        }
+                    },
+                    DiffEntry::Added { new_id, .. } => added.push(new_id),
+                    DiffEntry::Removed { .. } => {},
+                }
+            }
+        }
+        // Number new keys in ID order, so that the order of their stable IDs
+        // doesn't depend on the walk
+        added.sort();
+        stable_ids.extend(added.into_iter().map(Some));
+        let ids = IdMap::from_old_to_new(&stable_ids, trie.len(),
+                                         trie.dict_size());
+        PinnedTrie { trie_: trie, ids_: ids }
+    }
+
+    /// `read` for any `L`
+    pub fn read_with_louds(reader: &mut Read) -> io::Result<PinnedTrie<L>> {
+        let trie = try!(LoudsTrie::read_with_louds(reader));
+        let ids = try!(IdMap::read(&mut Reader::new(reader),
+                                   trie.dict_size()));
+        if ids.num_new() != trie.len() {
+            return Err(format_error("ID map size"));
+        }
+        Ok(PinnedTrie { trie_: trie, ids_: ids })
+    }
+
+    pub fn write(&self, writer: &mut Write) -> io::Result<()> {
+        try!(self.trie_.write(writer));
+        self.ids_.write(&mut Writer::new(writer))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use std::collections::BTreeSet;
+    use config::{Config, DictSize, NumTries};
+    use key::Key;
+    use super::PinnedTrie;
+    use super::super::LoudsTrie;
+
+    fn build(v: &Vec<String>, num_tries: NumTries) -> LoudsTrie {
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        LoudsTrie::build(&mut keys, &Config::new().with_num_tries(num_tries))
+    }
+
+    /// Rebuild with each key set in turn, checking that stable IDs stick
+    fn pinned_prop(versions: Vec<Vec<String>>, num_tries: NumTries,
+                   large: bool) -> qc::TestResult {
+        // A few versions are enough, and keep this quick. Empty keys would
+        // make nearly every input a discard.
+        let versions: Vec<Vec<String>> = versions.into_iter().take(4)
+            .map(|v| v.into_iter().filter(|x| !x.is_empty()).collect())
+            .collect();
+        let dict_size = if large { DictSize::Large }
+                        else { DictSize::Standard };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_dict_size(dict_size);
+        let mut pinned: PinnedTrie = PinnedTrie::new(LoudsTrie::new());
+        // Key of every stable ID handed out, unless it has been removed
+        let mut by_id: Vec<Option<Vec<u8>>> = Vec::new();
+        for v in versions.iter() {
+            let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                     .collect();
+            pinned = pinned.rebuild(&mut keys, &config);
+            if pinned.id_map().dict_size() != dict_size {
+                return qc::TestResult::failed();
+            }
+            let present: BTreeSet<&[u8]> = v.iter().map(|x| x.as_bytes())
+                                           .collect();
+            for slot in by_id.iter_mut() {
+                if slot.as_ref().map_or(false, |k| !present.contains(&k[..])) {
+                    *slot = None;
+                }
+            }
+            let known: BTreeSet<Vec<u8>> = by_id.iter()
+                                           .filter_map(|x| x.clone()).collect();
+            let mut added: Vec<(usize, Vec<u8>)> = pinned.trie().iter()
+                .filter(|&(ref key, _)| !known.contains(key))
+                .map(|(key, id)| (id, key)).collect();
+            added.sort();
+            by_id.extend(added.into_iter().map(|(_, key)| Some(key)));
+
+            if pinned.num_ids() != by_id.len() {
+                return qc::TestResult::failed();
+            }
+            for (id, slot) in by_id.iter().enumerate() {
+                if pinned.id_lookup(id) != *slot {
+                    return qc::TestResult::failed();
+                }
+                if let Some(ref key) = *slot {
+                    if pinned.lookup(key) != Some(id) {
+                        return qc::TestResult::failed();
+                    }
+                }
+            }
+        }
+        let num_ids = by_id.len();
+
+        let mut buf: Vec<u8> = Vec::new();
+        pinned.write(&mut buf).unwrap();
+        let read = PinnedTrie::read(&mut &buf[..]).unwrap();
+        qc::TestResult::from_bool(
+            (0..num_ids).all(|id| read.id_lookup(id) == pinned.id_lookup(id)))
+    }
+
+    #[test]
+    fn pinned_trie_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(pinned_prop as fn(Vec<Vec<String>>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn pinned_trie_manual() {
+        let _ = env_logger::init();
+        let v1: Vec<String> = vec!["apple", "banana", "band"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let v2: Vec<String> = vec!["apple", "apply", "band", "aardvark"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let v3: Vec<String> = vec!["banana", "band"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &large in [false, true].iter() {
+                assert!(!pinned_prop(vec![v1.clone(), v2.clone(), v3.clone()],
+                                     NumTries::new(n), large).is_failure());
+            }
+        }
+
+        let pinned = PinnedTrie::new(build(&v1, NumTries::default()));
+        let band = pinned.lookup(b"band").unwrap();
+        let banana = pinned.lookup(b"banana").unwrap();
+        let mut keys: Vec<Key> = v2.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let pinned = pinned.rebuild(&mut keys, &Config::new());
+        assert!(pinned.lookup(b"band") == Some(band));
+        assert!(pinned.num_ids() == 5);
+        assert!(pinned.lookup(b"banana") == None);
+        assert!(pinned.lookup(b"apply").unwrap() >= 3);
+        let mut keys: Vec<Key> = v1.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let pinned = pinned.rebuild(&mut keys, &Config::new());
+        assert!(pinned.lookup(b"band") == Some(band));
+        // Removed keys don't get their old IDs back
+        assert!(pinned.lookup(b"banana") == Some(5));
+        assert!(pinned.id_lookup(banana) == None);
+    }
+}
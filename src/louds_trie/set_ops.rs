@@ -0,0 +1,201 @@
+use std::cmp::Ordering;
+use config::Config;
+use super::{KeyArena, LoudsBits, LoudsTrie};
+use super::nav::SortedKeys;
+use vector::bit_flags::BitFlags;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SetOp {
+    Union,
+    Intersection,
+    /// Keys in the first trie but not the second
+    Difference,
+}
+
+/// Keys of two tries combined by a `SetOp`, in label order.
+///
+/// Both tries are walked side by side with `SortedKeys`, so keys are produced
+/// one at a time without collecting either key set first.
+#[derive(Debug)]
+pub struct SetOpKeys<'a, L: 'a = BitFlags> {
+    op_: SetOp,
+    a_: SortedKeys<'a, L>,
+    b_: SortedKeys<'a, L>,
+    /// Does `a_`/`b_` hold a key that hasn't been consumed?
+    has_a_: bool,
+    has_b_: bool,
+    /// Should `a_`/`b_` be advanced before the next comparison?
+    advance_a_: bool,
+    advance_b_: bool,
+}
+
+impl<'a, L: 'a + LoudsBits> SetOpKeys<'a, L> {
+    pub fn new(a: &'a LoudsTrie<L>, b: &'a LoudsTrie<L>, op: SetOp)
+      -> SetOpKeys<'a, L> {
+        SetOpKeys { op_: op, a_: a.sorted_iter(), b_: b.sorted_iter(),
+                    has_a_: false, has_b_: false, advance_a_: true,
+                    advance_b_: true }
+    }
+
+    pub fn next_key(&mut self) -> Option<&[u8]> {
+        loop {
+            if self.advance_a_ {
+                self.has_a_ = self.a_.next_key().is_some();
+                self.advance_a_ = false;
+            }
+            if self.advance_b_ {
+                self.has_b_ = self.b_.next_key().is_some();
+                self.advance_b_ = false;
+            }
+            let done = match self.op_ {
+                SetOp::Union => !self.has_a_ && !self.has_b_,
+                SetOp::Intersection => !self.has_a_ || !self.has_b_,
+                SetOp::Difference => !self.has_a_,
+            };
+            if done {
+                return None;
+            }
+            let order = match (self.has_a_, self.has_b_) {
+                (true, true) => self.a_.key().cmp(self.b_.key()),
+                (true, false) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => {
+                    self.advance_a_ = true;
+                    if self.op_ != SetOp::Intersection {
+                        return Some(self.a_.key());
+                    }
+                },
+                Ordering::Greater => {
+                    self.advance_b_ = true;
+                    if self.op_ == SetOp::Union {
+                        return Some(self.b_.key());
+                    }
+                },
+                Ordering::Equal => {
+                    self.advance_a_ = true;
+                    self.advance_b_ = true;
+                    if self.op_ != SetOp::Difference {
+                        return Some(self.a_.key());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Build a trie from the remaining keys
+    pub fn build(mut self, config: &Config) -> LoudsTrie<L> {
+        let mut arena = KeyArena::new();
+        while let Some(key) = self.next_key() {
+            arena.push(key);
+        }
+        LoudsTrie::build_from_arena_with_louds(&arena, config)
+    }
+}
+
+impl<'a, L: 'a + LoudsBits> Iterator for SetOpKeys<'a, L> {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.next_key().map(|key| key.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use std::collections::BTreeSet;
+    use config::{Config, NodeOrder, NumTries};
+    use key::Key;
+    use super::SetOp;
+    use super::super::LoudsTrie;
+
+    fn build(v: &Vec<String>, num_tries: NumTries, label_order: bool)
+      -> LoudsTrie {
+        let mut keys: Vec<Key> = v.iter().map(|s| Key::new(s.as_bytes()))
+                                 .collect();
+        let node_order = if label_order { NodeOrder::Label }
+                         else { NodeOrder::Weight };
+        let config = Config::new().with_num_tries(num_tries)
+                                  .with_node_order(node_order);
+        LoudsTrie::build(&mut keys, &config)
+    }
+
+    fn set_ops_prop(a: Vec<String>, b: Vec<String>, num_tries: NumTries,
+                    label_order: bool) -> qc::TestResult {
+        if a.iter().chain(b.iter()).any(|x| x.is_empty()) {
+            return qc::TestResult::discard();
+        }
+        let trie_a = build(&a, num_tries, label_order);
+        let trie_b = build(&b, num_tries, !label_order);
+        let set_a: BTreeSet<Vec<u8>> = a.iter().map(|x| x.as_bytes().to_vec())
+                                       .collect();
+        let set_b: BTreeSet<Vec<u8>> = b.iter().map(|x| x.as_bytes().to_vec())
+                                       .collect();
+        for &op in [SetOp::Union, SetOp::Intersection, SetOp::Difference]
+                   .iter() {
+            let expected: Vec<Vec<u8>> = match op {
+                SetOp::Union => set_a.union(&set_b).cloned().collect(),
+                SetOp::Intersection => {
+                    set_a.intersection(&set_b).cloned().collect()
+                },
+                SetOp::Difference => {
+                    set_a.difference(&set_b).cloned().collect()
+                },
+            };
+            let keys = match op {
+                SetOp::Union => trie_a.union(&trie_b),
+                SetOp::Intersection => trie_a.intersection(&trie_b),
+                SetOp::Difference => trie_a.difference(&trie_b),
+            };
+            let found: Vec<Vec<u8>> = keys.collect();
+            if found != expected {
+                return qc::TestResult::failed();
+            }
+
+            let keys = match op {
+                SetOp::Union => trie_a.union(&trie_b),
+                SetOp::Intersection => trie_a.intersection(&trie_b),
+                SetOp::Difference => trie_a.difference(&trie_b),
+            };
+            let trie = keys.build(&Config::new());
+            let built: Vec<Vec<u8>> = trie.sorted_iter()
+                                      .map(|(key, _)| key).collect();
+            if built != expected {
+                return qc::TestResult::failed();
+            }
+        }
+        qc::TestResult::passed()
+    }
+
+    #[test]
+    fn set_ops_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(set_ops_prop
+                       as fn(Vec<String>, Vec<String>, NumTries, bool)
+                       -> qc::TestResult);
+    }
+
+    #[test]
+    fn set_ops_manual() {
+        let _ = env_logger::init();
+        let a: Vec<String> = vec!["apple", "applesauce", "banana", "band"]
+            .iter().map(|&s| s.to_owned()).collect();
+        let b: Vec<String> = vec!["apple", "apply", "band", "bandana", "can"]
+            .iter().map(|&s| s.to_owned()).collect();
+        for &n in [1, 2, 3].iter() {
+            for &label_order in [false, true].iter() {
+                assert!(!set_ops_prop(a.clone(), b.clone(), NumTries::new(n),
+                                      label_order).is_failure());
+                assert!(!set_ops_prop(a.clone(), vec![], NumTries::new(n),
+                                      label_order).is_failure());
+            }
+        }
+
+        let trie_a = build(&a, NumTries::new(3), false);
+        let trie_b = build(&b, NumTries::new(3), true);
+        let found: Vec<Vec<u8>> = trie_a.intersection(&trie_b).collect();
+        assert!(found == vec![b"apple".to_vec(), b"band".to_vec()]);
+    }
+}
@@ -1,7 +1,9 @@
-use std;
+use std::io;
+use base::BuildIndex;
 use config::TailMode;
 use entry;
 use entry::Entry;
+use io::{Reader, Writer};
 use vector::bit_vec::BitVec;
 
 #[derive(Debug)]
@@ -15,8 +17,11 @@ impl Tail {
         Tail { buf_: Vec::new(), end_flags_: BitVec::new() }
     }
 
-    pub fn build<'a>(entries: &mut Vec<Entry<'a>>, offsets: &mut Vec<u32>,
-                     mode: TailMode) -> Tail {
+    /// With `usize` offsets a tail may hold more than 4 GiB. Tries that need
+    /// that many can only be written as large dictionaries.
+    pub fn build<'a, I: BuildIndex>(entries: &mut Vec<Entry<'a, I>>,
+                                    offsets: &mut Vec<I>, mode: TailMode)
+      -> Tail {
         let mode = match mode {
             TailMode::Text => {
                 if entries.iter().any(
@@ -30,8 +35,7 @@ impl Tail {
         };
 
         for (i, entry) in entries.iter_mut().enumerate() {
-            assert!(i <= std::u32::MAX as usize);
-            entry.set_id(i as u32);
+            entry.set_id(i);
         }
 
         let mut out = Tail::new();
@@ -40,10 +44,10 @@ impl Tail {
         //        quicksort" here. Consider bringing that back.
         entries.sort_by(&entry::cmp_slice);
 
-        let mut tmp: Vec<u32> = Vec::new();
-        tmp.resize(entries.len(), 0);
+        let mut tmp: Vec<I> = Vec::new();
+        tmp.resize(entries.len(), I::default());
 
-        let mut optLast: Option<&Entry> = None;
+        let mut optLast: Option<&Entry<I>> = None;
         for entry in entries.iter().rev() {
             assert!(!entry.is_empty(), "MARISA_RANGE_ERROR");
 
@@ -51,10 +55,8 @@ impl Tail {
                 Some(last) => {
                     if entry.common_count(last) == entry.len() {
                         let diff = last.len() - entry.len();
-                        assert!(diff <= std::u32::MAX as usize);
-                        let diff = diff as u32;
-                        tmp[entry.get_id() as usize] =
-                            tmp[last.get_id() as usize] + diff;
+                        tmp[entry.get_id()] =
+                            I::from_usize(tmp[last.get_id()].to_usize() + diff);
                         false
                     } else {
                         true
@@ -64,7 +66,7 @@ impl Tail {
             };
 
             if doPush {
-                tmp[entry.get_id() as usize] = out.buf_.len() as u32;
+                tmp[entry.get_id()] = I::from_usize(out.buf_.len());
 
                 out.buf_.extend(entry.iter().rev());
 
@@ -77,8 +79,6 @@ impl Tail {
                         out.end_flags_.push(true);
                     }
                 }
-                assert!(out.buf_.len() <= std::u32::MAX as usize,
-                        "MARISA_SIZE_ERROR");
             }
             optLast = Some(&entry);
         }
@@ -89,28 +89,31 @@ impl Tail {
     }
 
     pub fn restore(&self, offset: usize, key_out: &mut Vec<u8>) {
+        key_out.extend_from_slice(self.get(offset));
+    }
+
+    /// Borrow the tail string starting at `offset`
+    pub fn get(&self, offset: usize) -> &[u8] {
         assert!(!self.buf_.is_empty(), "MARISA_STATE_ERROR");
+        assert!(offset < self.buf_.len(), "MARISA_BOUND_ERROR");
 
-        if self.end_flags_.is_empty() {
-            for &c in self.buf_.iter().skip(offset) {
-                if 0 == c { break; } // null-terminated
-                key_out.push(c);
-            }
+        let rest = &self.buf_[offset..];
+        let len = if self.end_flags_.is_empty() {
+            // null-terminated
+            rest.iter().position(|&c| c == 0).unwrap_or(rest.len())
         } else {
-            for (i, &c) in self.buf_.iter().skip(offset).enumerate() {
-                key_out.push(c);
-                if self.end_flags_.at(i + offset) { break; }
+            let mut end = offset;
+            while !self.end_flags_.at(end) {
+                end += 1;
             }
-        }
+            end - offset + 1
+        };
+        &rest[..len]
     }
 
 /*
     void map(Mapper &mapper);
-    void read(Reader &reader);
-    void write(Writer &writer) const;
     void map_(Mapper &mapper);
-    void read_(Reader &reader);
-    void write_(Writer &writer) const;
 
 void Tail::map(Mapper &mapper) {
   Tail temp;
@@ -118,30 +121,22 @@ void Tail::map(Mapper &mapper) {
   swap(temp);
 }
 
-void Tail::read(Reader &reader) {
-  Tail temp;
-  temp.read_(reader);
-  swap(temp);
-}
-
-void Tail::write(Writer &writer) const {
-  write_(writer);
-}
 void Tail::map_(Mapper &mapper) {
   buf_.map(mapper);
   end_flags_.map(mapper);
 }
+*/
 
-void Tail::read_(Reader &reader) {
-  buf_.read(reader);
-  end_flags_.read(reader);
-}
+    pub fn read(reader: &mut Reader) -> io::Result<Tail> {
+        let buf = try!(reader.read_vec::<u8>());
+        let end_flags = try!(BitVec::read(reader));
+        Ok(Tail { buf_: buf, end_flags_: end_flags })
+    }
 
-void Tail::write_(Writer &writer) const {
-  buf_.write(writer);
-  end_flags_.write(writer);
-}
-*/
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(writer.write_vec(&self.buf_));
+        self.end_flags_.write(writer)
+    }
 
     pub fn clear(&mut self) {
         *self = Tail::new();
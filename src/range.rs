@@ -1,28 +1,30 @@
 use std;
+use base::BuildIndex;
 
+/// Keys `begin..end` of a build, which share their first `key_pos` bytes.
+/// Key positions always fit in 32 bits, since keys do.
 #[derive(Copy, Clone)]
-pub struct Range {
-    begin_: u32,
-    end_: u32,
+pub struct Range<I = u32> {
+    begin_: I,
+    end_: I,
     key_pos_: u32,
 }
 
-impl Range {
-    pub fn new(begin: usize, end: usize, key_pos: usize) -> Range {
+impl<I: BuildIndex> Range<I> {
+    pub fn new(begin: usize, end: usize, key_pos: usize) -> Range<I> {
+        assert!(key_pos <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
         Range {
-            begin_: begin as u32,
-            end_: end as u32,
+            begin_: I::from_usize(begin),
+            end_: I::from_usize(end),
             key_pos_: key_pos as u32
         }
     }
 
     pub fn set_begin(&mut self, begin: usize) {
-        assert!(begin <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
-        self.begin_ = begin as u32;
+        self.begin_ = I::from_usize(begin);
     }
     pub fn set_end(&mut self, end: usize) {
-        assert!(end <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
-        self.end_ = end as u32;
+        self.end_ = I::from_usize(end);
     }
     pub fn set_key_pos(&mut self, key_pos: usize) {
         assert!(key_pos <= std::u32::MAX as usize, "MARISA_SIZE_ERROR");
@@ -30,10 +32,10 @@ impl Range {
     }
   
     pub fn begin(&self) -> usize {
-        self.begin_ as usize
+        self.begin_.to_usize()
     }
     pub fn end(&self) -> usize {
-        self.end_ as usize
+        self.end_.to_usize()
     }
     pub fn key_pos(&self) -> usize {
         self.key_pos_ as usize
@@ -41,21 +43,21 @@ impl Range {
 }
 
 #[derive(Copy, Clone)]
-pub struct WeightedRange {
-    range_: Range,
+pub struct WeightedRange<I = u32> {
+    range_: Range<I>,
     weight_: f32,
 }
 
-impl WeightedRange {
+impl<I: BuildIndex> WeightedRange<I> {
     pub fn new(begin: usize, end: usize, key_pos: usize, weight: f32)
-      -> WeightedRange {
+      -> WeightedRange<I> {
         WeightedRange {
             range_: Range::new(begin, end, key_pos),
             weight_ : weight,
         }
     }
   
-    pub fn set_range(&mut self, range: &Range) {
+    pub fn set_range(&mut self, range: &Range<I>) {
         self.range_ = *range
     }
     pub fn set_begin(&mut self, begin: usize) {
@@ -71,7 +73,7 @@ impl WeightedRange {
         self.weight_ = weight
     }
   
-    pub fn range(&self) -> &Range {
+    pub fn range(&self) -> &Range<I> {
         &self.range_
     }
     pub fn begin(&self) -> usize {
@@ -88,16 +90,17 @@ impl WeightedRange {
     }
 }
 
-impl PartialEq for WeightedRange { 
-    fn eq(&self, rhs: &WeightedRange) -> bool {
+impl<I: BuildIndex> PartialEq for WeightedRange<I> { 
+    fn eq(&self, rhs: &WeightedRange<I>) -> bool {
         self.weight() == rhs.weight()
     }
 }
 
-impl Eq for WeightedRange {}
+impl<I: BuildIndex> Eq for WeightedRange<I> {}
 
-impl PartialOrd for WeightedRange { 
-    fn partial_cmp(&self, rhs: &WeightedRange) -> Option<std::cmp::Ordering> {
+impl<I: BuildIndex> PartialOrd for WeightedRange<I> { 
+    fn partial_cmp(&self, rhs: &WeightedRange<I>)
+      -> Option<std::cmp::Ordering> {
         self.weight().partial_cmp(&rhs.weight())
     }
 }
@@ -0,0 +1,137 @@
+use std::io;
+use io::{Reader, Writer};
+use super::bit_vec::{BitVec, RankLayout, SelectInterval};
+use super::elias_fano::low_bits_for;
+use super::rank_select::RankSelect;
+use super::sparse_bit_vec::SparseBitVec;
+
+/// A bit vector that picks its own representation: converted from a built
+/// `BitVec`, it stays plain unless a `SparseBitVec` is much smaller.
+///
+/// `write` writes both as the plain `BitVec`, so standard dictionaries stay
+/// readable by marisa-trie and only shrink in memory. Large dictionaries,
+/// which marisa-trie can't read anyway, write the sparse form with
+/// `SparseBitVec::write_sparse`, so their files shrink as well. `read` packs
+/// each vector as it comes in, so the plain form is held only until its
+/// sparse form is built.
+#[derive(Clone, Debug)]
+pub enum BitFlags {
+    Plain(BitVec),
+    Sparse(SparseBitVec),
+}
+
+impl BitFlags {
+    pub fn new() -> BitFlags {
+        BitFlags::Plain(BitVec::new())
+    }
+
+    /// Switch to the sparse representation if it at least halves the size.
+    /// Elias-Fano takes about `2 + log2(len / num_1s)` bits per 1, and a plain
+    /// vector a little over one bit per bit. Vectors with select0 enabled are
+    /// left alone, since sparse select0 is a binary search.
+    pub fn pack(&mut self) {
+        let sparse = match *self {
+            BitFlags::Plain(ref bv) => {
+                let (len, num_1s) = (bv.len(), bv.num_1s());
+                let sparse_bits = num_1s * (2 + low_bits_for(len, num_1s));
+                if !bv.is_rank_enabled() || bv.is_select0_enabled()
+                || 2 * sparse_bits >= len {
+                    return;
+                }
+                BitFlags::Sparse(SparseBitVec::from_bit_vec(bv))
+            },
+            BitFlags::Sparse(_) => { return; },
+        };
+        *self = sparse;
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        match *self {
+            BitFlags::Plain(_) => false,
+            BitFlags::Sparse(_) => true,
+        }
+    }
+
+    /// Layout of the plain `BitVec`, as it is written
+    pub fn layout(&self) -> RankLayout {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.layout(),
+            BitFlags::Sparse(ref sparse) => sparse.layout(),
+        }
+    }
+
+    /// Select interval of the plain `BitVec`, as it is written
+    pub fn select_interval(&self) -> SelectInterval {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.select_interval(),
+            BitFlags::Sparse(ref sparse) => sparse.select_interval(),
+        }
+    }
+
+    /// Read a `BitVec` and pack it. The plain vector is dropped as soon as
+    /// the sparse one is built, before the next vector is read.
+    pub fn read(reader: &mut Reader) -> io::Result<BitFlags> {
+        Ok(BitFlags::from(try!(BitVec::read(reader))))
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.write(writer),
+            BitFlags::Sparse(ref sparse) => sparse.write(writer),
+        }
+    }
+}
+
+impl From<BitVec> for BitFlags {
+    /// Take a built `BitVec` and pack it
+    fn from(bv: BitVec) -> BitFlags {
+        let mut out = BitFlags::Plain(bv);
+        out.pack();
+        out
+    }
+}
+
+impl RankSelect for BitFlags {
+    fn len(&self) -> usize {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.len(),
+            BitFlags::Sparse(ref sparse) => sparse.len(),
+        }
+    }
+    fn num_1s(&self) -> usize {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.num_1s(),
+            BitFlags::Sparse(ref sparse) => sparse.num_1s(),
+        }
+    }
+    fn at(&self, i: usize) -> bool {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.at(i),
+            BitFlags::Sparse(ref sparse) => sparse.at(i),
+        }
+    }
+    fn rank1(&self, i: usize) -> usize {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.rank1(i),
+            BitFlags::Sparse(ref sparse) => sparse.rank1(i),
+        }
+    }
+    fn select0(&self, i: usize) -> usize {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.select0(i),
+            BitFlags::Sparse(ref sparse) => sparse.select0(i),
+        }
+    }
+    fn select1(&self, i: usize) -> usize {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.select1(i),
+            BitFlags::Sparse(ref sparse) => sparse.select1(i),
+        }
+    }
+    fn is_select0_enabled(&self) -> bool {
+        match *self {
+            BitFlags::Plain(ref bv) => bv.is_select0_enabled(),
+            BitFlags::Sparse(_) => true,
+        }
+    }
+}
@@ -1,10 +1,154 @@
 use std;
+use std::io;
+use std::iter::FromIterator;
 use std::mem;
 use base::WORD_SIZE;
-use super::intrinsic::Ctz;
+use io::{Reader, Writer, format_error};
+use super::intrinsic::{Ctz, SelectMethod, select_bmi2};
 use super::rank_index::RankIndex;
 use super::util::vec_resize;
 
+/// How `BitVec::build` stores absolute ranks and select samples
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RankLayout {
+    /// 32 bits each, as in marisa-trie. Only for fewer than 2^32 bits.
+    Compact,
+    /// 64 bits each. The low halves stay where the compact layout keeps them,
+    /// and the high halves go in parallel vectors.
+    Wide,
+}
+
+impl RankLayout {
+    /// The compact layout whenever it fits
+    pub fn for_len(len: usize) -> RankLayout {
+        if len as u64 >= std::u32::MAX as u64 {
+            RankLayout::Wide
+        } else {
+            RankLayout::Compact
+        }
+    }
+
+    /// This layout, or the wide one if `len` bits don't fit in it
+    pub fn fit(self, len: usize) -> RankLayout {
+        if self == RankLayout::Wide { self } else { RankLayout::for_len(len) }
+    }
+}
+impl Default for RankLayout {
+    fn default() -> RankLayout {
+        RankLayout::Compact
+    }
+}
+
+/// Marks a written bit vector with the wide layout, in place of the size
+const WIDE_MARKER: u32 = std::u32::MAX;
+
+pub const MIN_SELECT_INTERVAL: usize = 64;
+pub const MAX_SELECT_INTERVAL: usize = 1 << 16;
+
+/// How many 0s (or 1s) `BitVec::build` goes between select samples. Select
+/// searches the rank blocks between two samples, so a longer interval saves
+/// space and a shorter one speeds select up. marisa-trie uses 512.
+///
+/// Always a power of two, so that `BitVec::read` can tell which interval a
+/// vector was built with from how many samples it has.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SelectInterval { shift_: u32 }
+impl SelectInterval {
+    pub fn new(interval: usize) -> SelectInterval {
+        assert!(interval.is_power_of_two() && interval >= MIN_SELECT_INTERVAL
+                && interval <= MAX_SELECT_INTERVAL, "MARISA_CODE_ERROR");
+        SelectInterval { shift_: interval.trailing_zeros() }
+    }
+    pub fn get(&self) -> usize {
+        1 << self.shift_
+    }
+
+    /// The interval that leaves `num_samples` samples for `count` 0s or 1s:
+    /// one at the start of each interval, and one for the end. Several fit
+    /// only if `count` is no longer than the shortest of them, and then they
+    /// all select the same way, so the default is picked if it fits.
+    fn for_samples(count: usize, num_samples: usize)
+      -> Option<SelectInterval> {
+        if SelectInterval::default().num_samples(count) == num_samples {
+            return Some(SelectInterval::default());
+        }
+        let mut interval = MIN_SELECT_INTERVAL;
+        while interval <= MAX_SELECT_INTERVAL {
+            if SelectInterval::new(interval).num_samples(count)
+               == num_samples {
+                return Some(SelectInterval::new(interval));
+            }
+            interval *= 2;
+        }
+        None
+    }
+
+    fn num_samples(&self, count: usize) -> usize {
+        (count + self.get() - 1) / self.get() + 1
+    }
+}
+impl Default for SelectInterval {
+    fn default() -> SelectInterval {
+        SelectInterval::new(512)
+    }
+}
+
+pub const MIN_RANK_BLOCK: usize = 256;
+pub const MAX_RANK_BLOCK: usize = 1024;
+
+/// How many bits `BitVec::build` puts in each rank block. A `RankIndex` holds
+/// the 1s before the block and before each eighth of it, so rank counts the
+/// rest of at most an eighth, and select searches the blocks between two
+/// samples and then one eighth. Each takes 96 bits, so 256-bit blocks cost
+/// 37.5% on top of the bits, 512-bit ones 18.75% and 1024-bit ones 9.4%.
+/// marisa-trie uses 512.
+///
+/// Only the wide layout records the block size, so `BitVec::build` switches
+/// to it for the others.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RankBlock { shift_: u32 }
+impl RankBlock {
+    pub fn new(bits: usize) -> RankBlock {
+        assert!(RankBlock::is_valid(bits), "MARISA_CODE_ERROR");
+        RankBlock { shift_: bits.trailing_zeros() }
+    }
+    pub fn get(&self) -> usize {
+        1 << self.shift_
+    }
+
+    fn is_valid(bits: usize) -> bool {
+        bits.is_power_of_two() && bits >= MIN_RANK_BLOCK
+        && bits <= MAX_RANK_BLOCK
+    }
+
+    /// Bits in each eighth of a block
+    fn sub_block(&self) -> usize {
+        self.get() / 8
+    }
+}
+impl Default for RankBlock {
+    fn default() -> RankBlock {
+        RankBlock::new(512)
+    }
+}
+
+/// The arguments `BitVec::build_with_options` was called with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BuildOptions {
+    pub enables_select0: bool,
+    pub enables_select1: bool,
+    pub layout: RankLayout,
+    pub interval: SelectInterval,
+    pub rank_block: RankBlock,
+}
+
+/// Bit vector with rank and select.
+///
+/// Bits are added with `push` (or `set`, `extend` and `collect`), then
+/// `build` adds the indexes that rank and select need. Changing bits after
+/// `build` leaves the indexes stale, so `set` refuses to, and anything pushed
+/// afterwards is only seen by rank and select once `build` runs again.
+/// `at`, `len`, `num_1s`, `iter` and `ones` work at any time.
 #[derive(Clone, Debug)]
 pub struct BitVec {
     units_: Vec<usize>,
@@ -13,6 +157,14 @@ pub struct BitVec {
     ranks_: Vec<RankIndex>,
     select0s_: Vec<u32>,
     select1s_: Vec<u32>,
+    /// With `RankLayout::Wide`, the high 32 bits of each rank's `abs` and of
+    /// each select sample. Empty otherwise.
+    rank_highs_: Vec<u32>,
+    select0_highs_: Vec<u32>,
+    select1_highs_: Vec<u32>,
+    select0_interval_: SelectInterval,
+    select1_interval_: SelectInterval,
+    rank_block_: RankBlock,
 }
 
 impl PartialEq for BitVec {
@@ -32,7 +184,13 @@ impl BitVec {
             num_1s_: 0,
             ranks_: Default::default(),
             select0s_: Default::default(),
-            select1s_: Default::default()
+            select1s_: Default::default(),
+            rank_highs_: Default::default(),
+            select0_highs_: Default::default(),
+            select1_highs_: Default::default(),
+            select0_interval_: Default::default(),
+            select1_interval_: Default::default(),
+            rank_block_: Default::default(),
         }
     }
     pub fn from_words<'a, T>(x: T, bits: usize) -> BitVec
@@ -72,6 +230,16 @@ impl BitVec {
         (self.units_[i / WORD_SIZE] & (1usize << (i % WORD_SIZE))) != 0
     }
 
+    pub fn iter(&self) -> Iter {
+        Iter { bv_: self, pos_: 0 }
+    }
+
+    /// Positions of the 1s, in order
+    pub fn ones(&self) -> Ones {
+        Ones { units_: &self.units_, unit_id_: 0,
+               unit_: self.units_.first().map_or(0, |&unit| unit) }
+    }
+
     pub fn is_select0_enabled(&self) -> bool {
         !self.select0s_.is_empty()
     }
@@ -84,83 +252,164 @@ impl BitVec {
         !self.ranks_.is_empty()
     }
 
+    pub fn layout(&self) -> RankLayout {
+        if self.rank_highs_.is_empty() {
+            RankLayout::Compact
+        } else {
+            RankLayout::Wide
+        }
+    }
+
+    pub fn select0_interval(&self) -> SelectInterval {
+        self.select0_interval_
+    }
+    pub fn select1_interval(&self) -> SelectInterval {
+        self.select1_interval_
+    }
+    pub fn rank_block(&self) -> RankBlock {
+        self.rank_block_
+    }
+
+    /// How this was built, or `None` if it has no indexes
+    pub fn build_options(&self) -> Option<BuildOptions> {
+        if !self.is_rank_enabled() {
+            return None;
+        }
+        Some(BuildOptions { enables_select0: self.is_select0_enabled(),
+                            enables_select1: self.is_select1_enabled(),
+                            layout: self.layout(),
+                            interval: self.select_interval(),
+                            rank_block: self.rank_block_ })
+    }
+
+    /// Interval of the enabled selects, or the default if there are none
+    pub fn select_interval(&self) -> SelectInterval {
+        if self.is_select1_enabled() {
+            self.select1_interval_
+        } else if self.is_select0_enabled() {
+            self.select0_interval_
+        } else {
+            Default::default()
+        }
+    }
+
+    /// Build the rank and select indexes, in the compact layout unless there
+    /// are too many bits for it
     pub fn build(&mut self, enables_select0: bool, enables_select1: bool) {
+        self.build_with_interval(enables_select0, enables_select1,
+                                 SelectInterval::default());
+    }
+
+    pub fn build_with_layout(&mut self, enables_select0: bool,
+                             enables_select1: bool, layout: RankLayout) {
+        self.build_with_options(enables_select0, enables_select1, layout,
+                                SelectInterval::default(),
+                                RankBlock::default());
+    }
+
+    pub fn build_with_interval(&mut self, enables_select0: bool,
+                               enables_select1: bool,
+                               interval: SelectInterval) {
+        let layout = RankLayout::for_len(self.len());
+        self.build_with_options(enables_select0, enables_select1, layout,
+                                interval, RankBlock::default());
+    }
+
+    /// Build with any layout, select interval and rank block. Rank blocks
+    /// other than the default need `RankLayout::Wide`, and get it.
+    pub fn build_with_options(&mut self, enables_select0: bool,
+                              enables_select1: bool, layout: RankLayout,
+                              interval: SelectInterval,
+                              rank_block: RankBlock) {
 
         let mut old = BitVec::new();
         mem::swap(self, &mut old);
         let old = old;
-
-        let ranks_size = (old.len() / 512)
-                       + (if old.len() % 512 != 0 { 1 } else { 0 })
+        self.select0_interval_ = interval;
+        self.select1_interval_ = interval;
+        self.rank_block_ = rank_block;
+        let mask = interval.get() - 1;
+        let block = rank_block.get();
+        let sub_block = rank_block.sub_block();
+
+        let ranks_size = (old.len() / block)
+                       + (if old.len() % block != 0 { 1 } else { 0 })
                        + 1;
 
         vec_resize(&mut self.ranks_, ranks_size);
+        let wide = layout == RankLayout::Wide
+                   || rank_block != RankBlock::default();
+        if wide {
+            vec_resize(&mut self.rank_highs_, ranks_size);
+        } else {
+            assert!((old.len() as u64) < std::u32::MAX as u64,
+                    "MARISA_SIZE_ERROR");
+        }
 
         let mut num_0s: usize = 0;
         let mut num_1s: usize = 0;
-  
-        assert!(old.len() <= std::u32::MAX as usize);
 
         for i in 0..old.len() {
-            if i % 64 == 0 {
-                let rank_id: usize = i / 512;
-                let nu = num_1s as u32 - self.ranks_[rank_id].abs();
-                match (i / 64) % 8 {
-                    0 => { self.ranks_[rank_id].set_abs(num_1s as u32); },
-                    1 => { self.ranks_[rank_id].set_rel1(nu); },
-                    2 => { self.ranks_[rank_id].set_rel2(nu); },
-                    3 => { self.ranks_[rank_id].set_rel3(nu); },
-                    4 => { self.ranks_[rank_id].set_rel4(nu); },
-                    5 => { self.ranks_[rank_id].set_rel5(nu); },
-                    6 => { self.ranks_[rank_id].set_rel6(nu); },
-                    7 => { self.ranks_[rank_id].set_rel7(nu); },
-                    _ => { panic!(); }
+            if i % sub_block == 0 {
+                let rank_id: usize = i / block;
+                let nu = (num_1s - self.rank_abs(rank_id)) as u32;
+                match (i / sub_block) % 8 {
+                    0 => { self.set_rank_abs(rank_id, num_1s); },
+                    k => { self.set_rank_rel(rank_id, k, nu); },
                 }
             }
   
             if old.at(i) {
-                if enables_select1 && (num_1s % 512 == 0) {
-                    self.select1s_.push(i as u32);
+                if enables_select1 && (num_1s & mask) == 0 {
+                    push_sample(&mut self.select1s_, &mut self.select1_highs_,
+                                wide, i);
                 }
                 num_1s += 1;
             } else {
-                if enables_select0 && (num_0s % 512 == 0) {
-                    self.select0s_.push(i as u32);
+                if enables_select0 && (num_0s & mask) == 0 {
+                    push_sample(&mut self.select0s_, &mut self.select0_highs_,
+                                wide, i);
                 }
                 num_0s += 1;
             }
         }
   
-        if old.len() % 512 != 0 {
-            let rank_id = (old.len() - 1) / 512;
-            let nu = num_1s as u32 - self.ranks_[rank_id].abs();
+        if old.len() % block != 0 {
+            let rank_id = (old.len() - 1) / block;
+            let nu = (num_1s - self.rank_abs(rank_id)) as u32;
             match_fallthrough!(
-                ((old.len() - 1) / 64) % 8,
+                ((old.len() - 1) / sub_block) % 8,
             {
-                0 => { self.ranks_[rank_id].set_rel1(nu); },
-                1 => { self.ranks_[rank_id].set_rel2(nu); },
-                2 => { self.ranks_[rank_id].set_rel3(nu); },
-                3 => { self.ranks_[rank_id].set_rel4(nu); },
-                4 => { self.ranks_[rank_id].set_rel5(nu); },
-                5 => { self.ranks_[rank_id].set_rel6(nu); },
-                6 => { self.ranks_[rank_id].set_rel7(nu);
+                0 => { self.set_rank_rel(rank_id, 1, nu); },
+                1 => { self.set_rank_rel(rank_id, 2, nu); },
+                2 => { self.set_rank_rel(rank_id, 3, nu); },
+                3 => { self.set_rank_rel(rank_id, 4, nu); },
+                4 => { self.set_rank_rel(rank_id, 5, nu); },
+                5 => { self.set_rank_rel(rank_id, 6, nu); },
+                6 => { self.set_rank_rel(rank_id, 7, nu);
                        break;
                      },
-                _ => { panic!(); }
+                // The last block of the last rank: nothing to set
+                _ => {}
             });
         }
 
         self.size_ = old.len();
         self.num_1s_ = old.num_1s();
 
-        self.ranks_.last_mut().unwrap().set_abs(num_1s as u32);
+        let last = self.ranks_.len() - 1;
+        self.set_rank_abs(last, num_1s);
         if enables_select0 {
-            self.select0s_.push(old.len() as u32);
+            push_sample(&mut self.select0s_, &mut self.select0_highs_, wide,
+                        old.len());
             self.select0s_.shrink_to_fit();
+            self.select0_highs_.shrink_to_fit();
         }
         if enables_select1 {
-            self.select1s_.push(old.len() as u32);
+            push_sample(&mut self.select1s_, &mut self.select1_highs_, wide,
+                        old.len());
             self.select1s_.shrink_to_fit();
+            self.select1_highs_.shrink_to_fit();
         }
 
         let mut old = old;
@@ -170,13 +419,47 @@ impl BitVec {
 
     pub fn disable_select0(&mut self) {
         self.select0s_.clear();
+        self.select0_highs_.clear();
     }
     pub fn disable_select1(&mut self) {
         self.select1s_.clear();
+        self.select1_highs_.clear();
+    }
+
+    fn rank_abs(&self, rank_id: usize) -> usize {
+        let low = self.ranks_[rank_id].abs() as u64;
+        match self.rank_highs_.get(rank_id) {
+            Some(&high) => ((high as u64) << 32 | low) as usize,
+            None => low as usize,
+        }
+    }
+    fn set_rank_abs(&mut self, rank_id: usize, value: usize) {
+        self.ranks_[rank_id].set_abs(value as u32);
+        if !self.rank_highs_.is_empty() {
+            self.rank_highs_[rank_id] = (value as u64 >> 32) as u32;
+        }
+    }
+    /// Relative count `k` of rank `rank_id`, packed for the block size
+    fn rank_rel(&self, rank_id: usize, k: usize) -> usize {
+        let rank = &self.ranks_[rank_id];
+        (if self.rank_block_.get() == MAX_RANK_BLOCK { rank.long_rel(k) }
+         else { rank.rel(k) }) as usize
+    }
+    fn set_rank_rel(&mut self, rank_id: usize, k: usize, value: u32) {
+        if self.rank_block_.get() == MAX_RANK_BLOCK {
+            self.ranks_[rank_id].set_long_rel(k, value);
+        } else {
+            self.ranks_[rank_id].set_rel(k, value);
+        }
+    }
+    fn select0_sample(&self, select_id: usize) -> usize {
+        get_sample(&self.select0s_, &self.select0_highs_, select_id)
+    }
+    fn select1_sample(&self, select_id: usize) -> usize {
+        get_sample(&self.select1s_, &self.select1_highs_, select_id)
     }
 
     pub fn push(&mut self, bit: bool) {
-        assert!(self.size_ < std::u32::MAX as usize);
 
         if self.size_ == WORD_SIZE * self.units_.len() {
             let newSize = self.units_.len() + (64 / WORD_SIZE);
@@ -190,6 +473,23 @@ impl BitVec {
         self.size_ += 1;
     }
 
+    /// Set bit `i`. Only for bit vectors that haven't been built, since the
+    /// rank and select indexes would go stale.
+    pub fn set(&mut self, i: usize, bit: bool) {
+        assert!(i < self.size_, "MARISA_BOUND_ERROR");
+        assert!(!self.is_rank_enabled(), "MARISA_STATE_ERROR");
+        let mask = 1usize << (i % WORD_SIZE);
+        let unit = &mut self.units_[i / WORD_SIZE];
+        if (*unit & mask != 0) != bit {
+            *unit ^= mask;
+            if bit {
+                self.num_1s_ += 1;
+            } else {
+                self.num_1s_ -= 1;
+            }
+        }
+    }
+
     pub fn clear(&mut self) {
         *self = BitVec::new();
     }
@@ -205,67 +505,88 @@ impl BitVec {
         assert!(self.is_rank_enabled(),
                 "rank1 was called, but ranks are not enabled");
         assert!(i <= self.size_, "MARISA_BOUND_ERROR");
+        // There may be no unit (or rank block) past the last bit
+        if i == self.size_ {
+            return self.num_1s_;
+        }
 
-        // FIXME: looks like Index is returning a value instead of an address..
-        //        what am I doing wrong?
-        assert!(i / 512 < self.ranks_.len());
-        let rank = self.ranks_[i / 512];
-        let mut offset: usize = rank.abs() as usize;
-        match (i / 64) % 8 {
-            0 => {}
-            1 => { offset += rank.rel1() as usize; }
-            2 => { offset += rank.rel2() as usize; }
-            3 => { offset += rank.rel3() as usize; }
-            4 => { offset += rank.rel4() as usize; }
-            5 => { offset += rank.rel5() as usize; }
-            6 => { offset += rank.rel6() as usize; }
-            7 => { offset += rank.rel7() as usize; }
-            _ => { panic!() }
-        }
-        offset += self.rank1_offset_rest(i);
+        let block = self.rank_block_.get();
+        let sub_block = self.rank_block_.sub_block();
+        assert!(i / block < self.ranks_.len());
+        let mut offset: usize = self.rank_abs(i / block);
+        offset += self.rank_rel(i / block, (i / sub_block) % 8);
+        offset += self.count_ones((i / sub_block) * sub_block, i);
         offset
     }
 
-    #[cfg(target_pointer_width = "64")]
-    fn rank1_offset_rest(&self, i: usize) -> usize {
-        assert!(i / 64 < self.units_.len());
-        (self.units_[i / 64] & (((1u64.wrapping_shl((i % 64) as u32) as u64) - 1)) as usize)
-            .count_ones() as usize
+    /// Number of 1s in bits `begin..end`, which are in one eighth of a rank
+    /// block
+    fn count_ones(&self, begin: usize, end: usize) -> usize {
+        let mut count: usize = 0;
+        let mut pos = begin;
+        while pos < end {
+            let unit_id = pos / WORD_SIZE;
+            let lo = pos % WORD_SIZE;
+            let hi = std::cmp::min(end - unit_id * WORD_SIZE, WORD_SIZE);
+            let mut unit = self.units_[unit_id] >> lo;
+            if hi - lo < WORD_SIZE {
+                unit &= (1 << (hi - lo)) - 1;
+            }
+            count += unit.count_ones() as usize;
+            pos = unit_id * WORD_SIZE + hi;
+        }
+        count
     }
-    #[cfg(target_pointer_width = "32")]
-    fn rank1_offset_rest(&self, i: usize) -> usize {
-        let mut rest: usize = 0;
-        if ((i / 32) & 1) == 1 {
-          rest += (self.units_[(i / 32) - 1]).count_ones() as usize;
+
+    /// Position of the `i`th 1 from `begin`, or of the `i`th 0 if `!bit`,
+    /// where `begin` starts the eighth of a rank block that has it
+    #[cfg(target_pointer_width = "64")]
+    fn select_in_sub_block(&self, method: SelectMethod, mut i: usize,
+                           begin: usize, bit: bool) -> usize {
+        let mut bit_id = begin;
+        loop {
+            let unit = self.units_[bit_id / 64];
+            let unit = (if bit { unit } else { !unit }) >> (bit_id % 64);
+            let count = unit.count_ones() as usize;
+            if i < count {
+                return self.select_bit(method, i, bit_id, unit);
+            }
+            i -= count;
+            bit_id = (bit_id / 64 + 1) * 64;
         }
-        rest += (self.units_[i / 32] & ((1 << (i % 32)) - 1)).count_ones()
-                as usize;
-        rest
     }
 
     #[cfg(target_pointer_width = "64")]
-    pub fn select0(&self, mut i: usize) -> usize {
+    pub fn select0(&self, i: usize) -> usize {
+        self.select0_with(i, SelectMethod::detect())
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    fn select0_with(&self, mut i: usize, method: SelectMethod) -> usize {
         assert!(self.is_select0_enabled(),
                 "select0 was called, but select0 is not enabled");
         assert!(i < self.num_0s(), "MARISA_BOUND_ERROR");
 
-        let select_id: usize = i / 512;
+        let shift = self.select0_interval_.shift_;
+        let select_id: usize = i >> shift;
         assert!((select_id + 1) < self.select0s_.len(), "MARISA_BOUND_ERROR");
-        if i % 512 == 0 {
-            return self.select0s_[select_id] as usize;
+        if (i & ((1 << shift) - 1)) == 0 {
+            return self.select0_sample(select_id);
         }
-        let mut begin = (self.select0s_[select_id] as usize) / 512;
-        let mut end = ((self.select0s_[select_id + 1] as usize) + 511) / 512;
+        let block = self.rank_block_.get();
+        let mut begin = self.select0_sample(select_id) / block;
+        let mut end = (self.select0_sample(select_id + 1) + block - 1)
+                      / block;
         if begin + 10 >= end {
-            while i >= (begin + 1) * 512
-                       - (self.ranks_[begin + 1].abs() as usize)
+            while i >= (begin + 1) * block
+                       - self.rank_abs(begin + 1)
             {
                 begin += 1;
             }
         } else {
             while begin + 1 < end {
                 let middle = (begin + end) / 2;
-                if i < (middle * 512) - (self.ranks_[middle].abs() as usize) {
+                if i < (middle * block) - self.rank_abs(middle) {
                     end = middle;
                 } else {
                     begin = middle;
@@ -273,40 +594,24 @@ impl BitVec {
             }
         }
         let rank_id: usize = begin;
-        i -= (rank_id * 512) - (self.ranks_[rank_id].abs() as usize);
-    
-        let rank = self.ranks_[rank_id];
-        let mut unit_id = rank_id * 8;
-        if i < (256 - (rank.rel4() as usize)) {
-            if i < (128 - (rank.rel2() as usize)) {
-                if i >= (64 - (rank.rel1() as usize)) {
-                    unit_id += 1;
-                    i -= 64 - (rank.rel1() as usize);
-                }
-            } else if i < (192 - (rank.rel3() as usize)) {
-                unit_id += 2;
-                i -= 128 - (rank.rel2() as usize);
-            } else {
-                unit_id += 3;
-                i -= 192 - (rank.rel3() as usize);
-            }
-        } else if i < (384 - (rank.rel6() as usize)) {
-            if i < (320 - (rank.rel5() as usize)) {
-                unit_id += 4;
-                i -= 256 - (rank.rel4() as usize);
-            } else {
-                unit_id += 5;
-                i -= 320 - (rank.rel5() as usize);
-            }
-        } else if i < (448 - (rank.rel7() as usize)) {
-            unit_id += 6;
-            i -= 384 - (rank.rel6() as usize);
-        } else {
-            unit_id += 7;
-            i -= 448 - (rank.rel7() as usize);
+        i -= (rank_id * block) - self.rank_abs(rank_id);
+
+        // Halve the eighths the 0 can be in, three times
+        let sub_block = self.rank_block_.sub_block();
+        let zeros = |k: usize| k * sub_block - self.rank_rel(rank_id, k);
+        let mut k: usize = 0;
+        if i >= zeros(4) {
+            k = 4;
+        }
+        if i >= zeros(k + 2) {
+            k += 2;
         }
-        return self.select_bit(i, unit_id.wrapping_mul(64),
-                               !self.units_[unit_id]);
+        if i >= zeros(k + 1) {
+            k += 1;
+        }
+        i -= zeros(k);
+        self.select_in_sub_block(method, i, (rank_id * 8 + k) * sub_block,
+                                 false)
     }
 
     #[cfg(target_pointer_width = "32")]
@@ -315,9 +620,10 @@ impl BitVec {
                 "select0 was called, but select0 is not enabled");
         assert!(i < self.num_0s(), "MARISA_BOUND_ERROR");
     
-        let select_id: usize = i / 512;
+        let shift = self.select0_interval_.shift_;
+        let select_id: usize = i >> shift;
         assert!((select_id + 1) < self.select0s_.len(), "MARISA_BOUND_ERROR");
-        if (i % 512) == 0 {
+        if (i & ((1 << shift) - 1)) == 0 {
             return self.select0s_[select_id];
         }
         let mut begin: usize = self.select0s_[select_id] / 512;
@@ -404,27 +710,34 @@ impl BitVec {
     }
 
     #[cfg(target_pointer_width = "64")]
-    pub fn select1(&self, mut i: usize) -> usize {
+    pub fn select1(&self, i: usize) -> usize {
+        self.select1_with(i, SelectMethod::detect())
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    fn select1_with(&self, mut i: usize, method: SelectMethod) -> usize {
         assert!(self.is_select1_enabled(),
                 "select1 was called, but select1 is not enabled");
         assert!(i < self.num_1s(), "MARISA_BOUND_ERROR");
 
-        let select_id: usize = i / 512;
+        let shift = self.select1_interval_.shift_;
+        let select_id: usize = i >> shift;
         assert!((select_id + 1) < self.select1s_.len(), "MARISA_BOUND_ERROR");
-        if (i % 512) == 0 {
-            return self.select1s_[select_id] as usize;
+        if (i & ((1 << shift) - 1)) == 0 {
+            return self.select1_sample(select_id);
         }
-        let mut begin: usize = (self.select1s_[select_id] as usize) / 512;
-        let mut end: usize = ((self.select1s_[select_id + 1] as usize)+ 511)
-                             / 512;
+        let block = self.rank_block_.get();
+        let mut begin: usize = self.select1_sample(select_id) / block;
+        let mut end: usize = (self.select1_sample(select_id + 1) + block - 1)
+                             / block;
         if begin + 10 >= end {
-            while i >= self.ranks_[begin + 1].abs() as usize {
+            while i >= self.rank_abs(begin + 1) {
                 begin += 1;
             }
         } else {
             while begin + 1 < end {
                 let middle: usize = (begin + end) / 2;
-                if i < self.ranks_[middle].abs() as usize {
+                if i < self.rank_abs(middle) {
                     end = middle;
                 } else {
                     begin = middle;
@@ -432,40 +745,24 @@ impl BitVec {
             }
         }
         let rank_id: usize = begin;
-        i -= self.ranks_[rank_id].abs() as usize;
+        i -= self.rank_abs(rank_id);
 
-        //const RankIndex &rank = 
-        let rank = self.ranks_[rank_id];
-        let mut unit_id: usize = rank_id * 8;
-        if i < rank.rel4() as usize {
-            if i < rank.rel2() as usize {
-                if i >= rank.rel1() as usize {
-                    unit_id += 1;
-                    i -= rank.rel1() as usize;
-                }
-            } else if i < rank.rel3() as usize {
-                unit_id += 2;
-                i -= rank.rel2() as usize;
-            } else {
-                unit_id += 3;
-                i -= rank.rel3() as usize;
-            }
-        } else if i < rank.rel6() as usize {
-            if i < rank.rel5() as usize {
-                unit_id += 4;
-                i -= rank.rel4() as usize;
-            } else {
-                unit_id += 5;
-                i -= rank.rel5() as usize;
-            }
-        } else if i < rank.rel7() as usize {
-            unit_id += 6;
-            i -= rank.rel6() as usize;
-        } else {
-            unit_id += 7;
-            i -= rank.rel7() as usize;
+        // Halve the eighths the 1 can be in, three times
+        let ones = |k: usize| self.rank_rel(rank_id, k);
+        let mut k: usize = 0;
+        if i >= ones(4) {
+            k = 4;
+        }
+        if i >= ones(k + 2) {
+            k += 2;
         }
-        return self.select_bit(i, unit_id * 64, self.units_[unit_id]);
+        if i >= ones(k + 1) {
+            k += 1;
+        }
+        i -= ones(k);
+        let sub_block = self.rank_block_.sub_block();
+        self.select_in_sub_block(method, i, (rank_id * 8 + k) * sub_block,
+                                 true)
     }
 
     #[cfg(target_pointer_width = "32")]
@@ -474,9 +771,10 @@ impl BitVec {
                 "select1 was called, but select1 is not enabled");
         assert!(i < num_1s(), "MARISA_BOUND_ERROR");
     
-        let select_id: usize = i / 512;
+        let shift = self.select1_interval_.shift_;
+        let select_id: usize = i >> shift;
         assert!((select_id + 1) < self.select1s_.len(), "MARISA_BOUND_ERROR");
-        if (i % 512) == 0 {
+        if (i & ((1 << shift) - 1)) == 0 {
             return self.select1s_[select_id];
         }
         let mut begin: usize = self.select1s_[select_id] / 512;
@@ -562,9 +860,22 @@ impl BitVec {
 //#endif // MARISA_USE_SSE2
     }
 
+    /// Position of the `i`th 1 in `unit`, plus `bit_id`. `method` must be
+    /// available, as it is when it comes from `SelectMethod::detect`.
+    #[cfg(target_pointer_width = "64")]
+    fn select_bit(&self, method: SelectMethod, i: usize, bit_id: usize,
+                  unit: usize) -> usize {
+        match method {
+            SelectMethod::Portable => self.select_bit_portable(i, bit_id, unit),
+            SelectMethod::Bmi2 => {
+                bit_id + unsafe { select_bmi2(unit as u64, i) }
+            },
+        }
+    }
+
     #[cfg(target_pointer_width = "64")]
-    fn select_bit(&self, mut i: usize, mut bit_id: usize, mut unit: usize)
-                  -> usize {
+    fn select_bit_portable(&self, mut i: usize, mut bit_id: usize,
+                           mut unit: usize) -> usize {
         let MASK_55: usize = 0x5555555555555555usize;
         let MASK_33: usize = 0x3333333333333333usize;
         let MASK_0F: usize = 0x0F0F0F0F0F0F0F0Fusize;
@@ -775,34 +1086,209 @@ std::size_t select_bit(std::size_t i, std::size_t bit_id,
     select1s_.map(mapper);
   }
 
-  void read_(Reader &reader) {
-    units_.read(reader);
-    {
-      u32 temp_size;
-      reader.read(&temp_size);
-      size_ = temp_size;
+*/
+
+    /// Read a bit vector written by `write`. The compact layout is the same
+    /// as marisa-trie's. The wide layout puts `WIDE_MARKER` where the size
+    /// would be, followed by the rank block size (0 for the default, as
+    /// written before it could change), a 64-bit size and count of 1s, and
+    /// the high halves after each vector of low halves. Neither records the
+    /// select intervals, since the number of samples gives them away.
+    pub fn read(reader: &mut Reader) -> io::Result<BitVec> {
+        let mut out = BitVec::new();
+        out.units_ = try!(reader.read_vec::<usize>());
+        let size = try!(reader.read_u32());
+        let wide = size == WIDE_MARKER;
+        if wide {
+            out.rank_block_ = match try!(reader.read_u32()) as usize {
+                0 => RankBlock::default(),
+                bits if RankBlock::is_valid(bits) => RankBlock::new(bits),
+                _ => return Err(format_error("bit vector rank block")),
+            };
+            let size = try!(reader.read_u64());
+            let num_1s = try!(reader.read_u64());
+            if size > std::usize::MAX as u64 {
+                return Err(format_error("bit vector too large"));
+            }
+            out.size_ = size as usize;
+            out.num_1s_ = num_1s as usize;
+        } else {
+            out.size_ = size as usize;
+            out.num_1s_ = try!(reader.read_u32()) as usize;
+        }
+        if out.num_1s_ > out.size_
+        || (out.units_.len() as u64) * (WORD_SIZE as u64) < out.size_ as u64 {
+            return Err(format_error("bit vector size"));
+        }
+        out.ranks_ = try!(reader.read_vec::<RankIndex>());
+        if wide {
+            out.rank_highs_ = try!(reader.read_vec::<u32>());
+        }
+        out.select0s_ = try!(reader.read_vec::<u32>());
+        if wide {
+            out.select0_highs_ = try!(reader.read_vec::<u32>());
+        }
+        out.select1s_ = try!(reader.read_vec::<u32>());
+        if wide {
+            out.select1_highs_ = try!(reader.read_vec::<u32>());
+        }
+        // Rank is built whenever select is, with one entry per block and one
+        // past the end
+        let block = out.rank_block_.get();
+        let ranks_size = if out.ranks_.is_empty() { 0 }
+                         else { (out.size_ / block)
+                                + (if out.size_ % block != 0 { 1 } else { 0 })
+                                + 1 };
+        if out.ranks_.len() != ranks_size
+        || (out.ranks_.is_empty()
+            && (!out.select0s_.is_empty() || !out.select1s_.is_empty())) {
+            return Err(format_error("bit vector rank index"));
+        }
+        if wide && (out.rank_highs_.len() != out.ranks_.len()
+                    || out.rank_highs_.is_empty()
+                    || out.select0_highs_.len() != out.select0s_.len()
+                    || out.select1_highs_.len() != out.select1s_.len()) {
+            return Err(format_error("bit vector layout"));
+        }
+        // Enabled selects have samples at whatever interval they were built
+        // with. Disabled ones keep the default.
+        if out.is_select0_enabled() {
+            out.select0_interval_ = match SelectInterval::for_samples(
+                out.num_0s(), out.select0s_.len()) {
+                Some(interval) => interval,
+                None => return Err(format_error("bit vector select samples")),
+            };
+        }
+        if out.is_select1_enabled() {
+            out.select1_interval_ = match SelectInterval::for_samples(
+                out.num_1s(), out.select1s_.len()) {
+                Some(interval) => interval,
+                None => return Err(format_error("bit vector select samples")),
+            };
+        }
+        Ok(out)
     }
-    {
-      u32 temp_num_1s;
-      reader.read(&temp_num_1s);
-      MARISA_THROW_IF(temp_num_1s > size_, MARISA_FORMAT_ERROR);
-      num_1s_ = temp_num_1s;
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        // Also true for unbuilt vectors, which have no indexes to widen
+        let wide = self.layout() == RankLayout::Wide
+                   || self.size_ as u64 >= WIDE_MARKER as u64;
+        try!(writer.write_vec(&self.units_));
+        if wide {
+            try!(writer.write_u32(WIDE_MARKER));
+            try!(writer.write_u32(self.rank_block_.get() as u32));
+            try!(writer.write_u64(self.size_ as u64));
+            try!(writer.write_u64(self.num_1s_ as u64));
+        } else {
+            try!(writer.write_u32(self.size_ as u32));
+            try!(writer.write_u32(self.num_1s_ as u32));
+        }
+        try!(writer.write_vec(&self.ranks_));
+        if wide {
+            try!(writer.write_vec(&self.rank_highs_));
+        }
+        try!(writer.write_vec(&self.select0s_));
+        if wide {
+            try!(writer.write_vec(&self.select0_highs_));
+        }
+        try!(writer.write_vec(&self.select1s_));
+        if wide {
+            try!(writer.write_vec(&self.select1_highs_));
+        }
+        Ok(())
     }
-    ranks_.read(reader);
-    select0s_.read(reader);
-    select1s_.read(reader);
-  }
 
-  void write_(Writer &writer) const {
-    units_.write(writer);
-    writer.write((u32)size_);
-    writer.write((u32)num_1s_);
-    ranks_.write(writer);
-    select0s_.write(writer);
-    select1s_.write(writer);
-  }
-*/
+}
+
+fn push_sample(lows: &mut Vec<u32>, highs: &mut Vec<u32>, wide: bool,
+               value: usize) {
+    lows.push(value as u32);
+    if wide {
+        highs.push((value as u64 >> 32) as u32);
+    }
+}
+
+fn get_sample(lows: &[u32], highs: &[u32], i: usize) -> usize {
+    match highs.get(i) {
+        Some(&high) => ((high as u64) << 32 | lows[i] as u64) as usize,
+        None => lows[i] as usize,
+    }
+}
+
+impl FromIterator<bool> for BitVec {
+    /// Collect bits into a bit vector that hasn't been built
+    fn from_iter<T: IntoIterator<Item=bool>>(iter: T) -> BitVec {
+        let mut out = BitVec::new();
+        out.extend(iter);
+        out
+    }
+}
+
+impl Extend<bool> for BitVec {
+    fn extend<T: IntoIterator<Item=bool>>(&mut self, iter: T) {
+        for bit in iter {
+            self.push(bit);
+        }
+    }
+}
 
+impl<'a> IntoIterator for &'a BitVec {
+    type Item = bool;
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Every bit of a `BitVec`, in order
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    bv_: &'a BitVec,
+    pos_: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = bool;
+    fn next(&mut self) -> Option<bool> {
+        if self.pos_ == self.bv_.len() {
+            return None;
+        }
+        self.pos_ += 1;
+        Some(self.bv_.at(self.pos_ - 1))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let rest = self.bv_.len() - self.pos_;
+        (rest, Some(rest))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+/// Positions of the 1s in a `BitVec`, in order. Scans a word at a time, so
+/// it doesn't need select1.
+#[derive(Clone, Debug)]
+pub struct Ones<'a> {
+    units_: &'a [usize],
+    /// Index of `unit_` in `units_`
+    unit_id_: usize,
+    /// What's left of the current word
+    unit_: usize,
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        while self.unit_ == 0 {
+            self.unit_id_ += 1;
+            match self.units_.get(self.unit_id_) {
+                Some(&unit) => { self.unit_ = unit; },
+                None => { return None; }
+            }
+        }
+        let offset = self.unit_.trailing_zeros() as usize;
+        self.unit_ &= self.unit_ - 1;
+        Some(self.unit_id_ * WORD_SIZE + offset)
+    }
 }
 
 const SELECT_TABLE: [[u8; 256]; 8] =
@@ -948,13 +1434,17 @@ const SELECT_TABLE: [[u8; 256]; 8] =
 mod test {
     use base::WORD_SIZE;
     use env_logger;
+    use io::{Reader, Writer};
     use quickcheck as qc;
     use std;
-    use super::BitVec;
+    use super::{BitVec, RankBlock, RankLayout, SelectInterval};
+    use super::super::intrinsic::SelectMethod;
 
     fn build_same(old: &BitVec, new: &mut BitVec) {
         if old.is_rank_enabled() {
-            new.build(old.is_select0_enabled(), old.is_select1_enabled());
+            new.build_with_options(old.is_select0_enabled(),
+                                   old.is_select1_enabled(), old.layout(),
+                                   old.select0_interval(), old.rank_block());
         }
     }
 
@@ -965,12 +1455,32 @@ mod test {
             for _ in 0..vec_size {
                 v.push(g.gen());
             }
-            match g.gen_range(0, 5) {
+            match g.gen_range(0, 11) {
                 0 => {},
                 1 => { v.build(false, false); }
                 2 => { v.build(true, false); }
                 3 => { v.build(false, true); }
                 4 => { v.build(true, true); }
+                5 => { v.build_with_layout(false, false, RankLayout::Wide); }
+                6 => { v.build_with_layout(true, true, RankLayout::Wide); }
+                7 => {
+                    v.build_with_interval(true, true, SelectInterval::new(64));
+                }
+                8 => {
+                    v.build_with_options(true, true, RankLayout::Wide,
+                                         SelectInterval::new(128),
+                                         RankBlock::default());
+                }
+                9 => {
+                    v.build_with_options(true, true, RankLayout::Compact,
+                                         SelectInterval::new(64),
+                                         RankBlock::new(256));
+                }
+                10 => {
+                    v.build_with_options(true, true, RankLayout::Compact,
+                                         SelectInterval::default(),
+                                         RankBlock::new(1024));
+                }
                 _ => panic!()
             }
             v
@@ -1034,12 +1544,16 @@ mod test {
                 (true, s0, s1) if s0 || s1 => {
                     if s0 {
                         let mut cpy = self.clone();
-                        cpy.build(false, s1);
+                        cpy.build_with_options(false, s1, self.layout(),
+                                               self.select1_interval(),
+                                               self.rank_block());
                         v.push(cpy);
                     }
                     if s1 {
                         let mut cpy = self.clone();
-                        cpy.build(s0, false);
+                        cpy.build_with_options(s0, false, self.layout(),
+                                               self.select0_interval(),
+                                               self.rank_block());
                         v.push(cpy);
                     }
                 },
@@ -1296,6 +1810,10 @@ mod test {
                 zeros.push(i)
             };
         }
+        let len = bv.size_;
+        if bv.rank0(len) != zeros.len() || bv.rank1(len) != ones.len() {
+            return qc::TestResult::failed();
+        }
         for (sel_idx, &val) in zeros.iter().enumerate() {
             if bv.select0(sel_idx) != val {
                 return qc::TestResult::failed();
@@ -1312,54 +1830,365 @@ mod test {
         if bv.num_1s() != ones.len() {
             return qc::TestResult::failed();
         }
-        qc::TestResult::passed()
 
-        // Add this back in when we can read/write
-/*
-        std::stringstream stream;
-        {
-          marisa::grimoire::Writer writer;
-          writer.open(stream);
-          bv.write(writer);
+        let mut buf: Vec<u8> = Vec::new();
+        bv.write(&mut Writer::new(&mut buf)).unwrap();
+        bv = BitVec::new();
+        if bv.len() != 0 || !bv.is_empty() {
+            return qc::TestResult::failed();
+        }
+        let mut slice = &buf[..];
+        bv = BitVec::read(&mut Reader::new(&mut slice)).unwrap();
+        if !slice.is_empty() || bv.len() != zeros.len() + ones.len() {
+            return qc::TestResult::failed();
         }
+        let (mut num_0s, mut num_1s) = (0, 0);
+        for i in 0..bv.len() {
+            if bv.rank0(i) != num_0s || bv.rank1(i) != num_1s {
+                return qc::TestResult::failed();
+            }
+            if bv.at(i) { num_1s += 1; } else { num_0s += 1; }
+        }
+        if zeros.iter().enumerate().any(|(i, &x)| bv.select0(i) != x)
+        || ones.iter().enumerate().any(|(i, &x)| bv.select1(i) != x) {
+            return qc::TestResult::failed();
+        }
+        qc::TestResult::from_bool(bv.num_0s() == zeros.len()
+                                  && bv.num_1s() == ones.len())
+    }
 
-        bv.clear();
+    #[test]
+    fn test_bit_vector() {
+        let _ = env_logger::init();
+        qc::quickcheck(test_bit_vector_prop as fn(BitVec) -> qc::TestResult);
+    }
 
-        assert!(bv.size() == 0);
-        assert!(bv.empty());
-        assert!(bv.total_size() == 0);
-        assert!(bv.io_size() == sizeof(marisa::UInt64) * 5);
-        
-        {
-          marisa::grimoire::Reader reader;
-          reader.open(stream);
-          bv.read(reader);
+    /// Every select method the CPU has must agree with the naive select
+    fn select_methods_prop(bv: BitVec) -> bool {
+        let methods = [SelectMethod::Portable, SelectMethod::Bmi2];
+        for &method in methods.iter().filter(|m| m.is_available()) {
+            if bv.is_select0_enabled()
+            && (0..bv.num_0s()).any(|i| bv.select0_with(i, method)
+                                        != naive_select0(&bv, i)) {
+                return false;
+            }
+            if bv.is_select1_enabled()
+            && (0..bv.num_1s()).any(|i| bv.select1_with(i, method)
+                                        != naive_select1(&bv, i)) {
+                return false;
+            }
         }
+        true
+    }
 
-        assert!(bv.size() == bits.size());
+    #[test]
+    fn select_methods_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(select_methods_prop as fn(BitVec) -> bool);
+    }
 
-        num_zeros = 0, num_ones = 0;
-        for (std::size_t i = 0; i < bits.size(); ++i) {
-          assert!(bv[i] == bits[i]);
-          assert!(bv.rank0(i) == num_zeros);
-          assert!(bv.rank1(i) == num_ones);
-          ++(bv[i] ? num_ones : num_zeros);
+    #[test]
+    fn select_methods_manual() {
+        let _ = env_logger::init();
+        // Runs of 1s and 0s of every length up to 100, so that the `i`th bit
+        // lands at every offset within a word
+        let mut bv = BitVec::new();
+        for run in 1..101 {
+            for _ in 0..run {
+                bv.push(true);
+            }
+            for _ in 0..run {
+                bv.push(false);
+            }
         }
-        for (std::size_t i = 0; i < zeros.size(); ++i) {
-          assert!(bv.select0(i) == zeros[i]);
+        for &layout in [RankLayout::Compact, RankLayout::Wide].iter() {
+            let mut cpy = bv.clone();
+            cpy.build_with_layout(true, true, layout);
+            assert!(select_methods_prop(cpy));
         }
-        for (std::size_t i = 0; i < ones.size(); ++i) {
-          assert!(bv.select1(i) == ones[i]);
+    }
+
+    /// Both layouts must give the same answers, before and after a round
+    /// trip through `write` and `read`
+    fn layouts_prop(bv: BitVec) -> bool {
+        let build = |layout| {
+            let mut out = BitVec::from_words(bv.units_.iter(), bv.len());
+            out.build_with_layout(true, true, layout);
+            let mut buf: Vec<u8> = Vec::new();
+            out.write(&mut Writer::new(&mut buf)).unwrap();
+            let read = BitVec::read(&mut Reader::new(&mut &buf[..])).unwrap();
+            (out, read)
+        };
+        let (compact, compact_read) = build(RankLayout::Compact);
+        let (wide, wide_read) = build(RankLayout::Wide);
+        if compact.layout() != RankLayout::Compact
+        || compact_read.layout() != RankLayout::Compact
+        || wide.layout() != RankLayout::Wide
+        || wide_read.layout() != RankLayout::Wide {
+            return false;
         }
-        assert!(bv.num_0s() == num_zeros);
-        assert!(bv.num_1s() == num_ones);
-*/
+        let all = [&compact, &compact_read, &wide, &wide_read];
+        (0..bv.len()).all(|i| {
+            all.iter().all(|x| x.rank1(i) == naive_rank1(&bv, i))
+        })
+        && (0..compact.num_0s()).all(|i| {
+            all.iter().all(|x| x.select0(i) == compact.select0(i))
+        })
+        && (0..compact.num_1s()).all(|i| {
+            all.iter().all(|x| x.select1(i) == compact.select1(i))
+        })
     }
 
     #[test]
-    fn test_bit_vector() {
+    fn layouts_qc() {
         let _ = env_logger::init();
-        qc::quickcheck(test_bit_vector_prop as fn(BitVec) -> qc::TestResult);
+        qc::quickcheck(layouts_prop as fn(BitVec) -> bool);
+    }
+
+    /// Every select interval must give the same answers as the default,
+    /// before and after a round trip through `write` and `read`
+    fn intervals_prop(bv: BitVec) -> bool {
+        let mut plain = BitVec::from_words(bv.units_.iter(), bv.len());
+        plain.build(true, true);
+        for &interval in [64, 128, 512, 4096, 1 << 16].iter() {
+            let interval = SelectInterval::new(interval);
+            let mut out = BitVec::from_words(bv.units_.iter(), bv.len());
+            out.build_with_interval(true, true, interval);
+            let mut buf: Vec<u8> = Vec::new();
+            out.write(&mut Writer::new(&mut buf)).unwrap();
+            let read = BitVec::read(&mut Reader::new(&mut &buf[..])).unwrap();
+            // `read` picks the same interval unless there are too few 0s or
+            // 1s to tell, and then the default or else the shortest
+            let fits = |x: SelectInterval, count: usize| {
+                x == interval || (x.num_samples(count)
+                                  == interval.num_samples(count)
+                                  && (x == SelectInterval::default()
+                                      || x.get() < interval.get()))
+            };
+            if out.select0_interval() != interval
+            || out.select1_interval() != interval
+            || !fits(read.select0_interval(), out.num_0s())
+            || !fits(read.select1_interval(), out.num_1s()) {
+                return false;
+            }
+            for x in [&out, &read].iter() {
+                if (0..plain.num_0s()).any(|i| x.select0(i) != plain.select0(i))
+                || (0..plain.num_1s()).any(|i| {
+                    x.select1(i) != plain.select1(i)
+                }) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn intervals_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(intervals_prop as fn(BitVec) -> bool);
+    }
+
+    #[test]
+    fn intervals_manual() {
+        let _ = env_logger::init();
+        // Runs of every length up to 400, so that samples land both inside
+        // and at the start of rank blocks
+        let mut bv = BitVec::new();
+        for run in 1..401 {
+            for _ in 0..run {
+                bv.push(true);
+            }
+            for _ in 0..(run % 7) {
+                bv.push(false);
+            }
+        }
+        assert!(intervals_prop(bv));
+        assert!(SelectInterval::default().get() == 512);
+        assert!(SelectInterval::for_samples(0, 1)
+                == Some(SelectInterval::default()));
+        assert!(SelectInterval::for_samples(100, 3)
+                == Some(SelectInterval::new(64)));
+        assert!(SelectInterval::for_samples(1000, 3)
+                == Some(SelectInterval::new(512)));
+        assert!(SelectInterval::for_samples(1000, 1000) == None);
+    }
+
+    /// Every rank block must give the same answers as the default, before
+    /// and after a round trip through `write` and `read`
+    fn rank_blocks_prop(bv: BitVec) -> bool {
+        let mut plain = BitVec::from_words(bv.units_.iter(), bv.len());
+        plain.build(true, true);
+        for &bits in [256, 512, 1024].iter() {
+            let rank_block = RankBlock::new(bits);
+            let mut out = BitVec::from_words(bv.units_.iter(), bv.len());
+            out.build_with_options(true, true, RankLayout::Compact,
+                                   SelectInterval::default(), rank_block);
+            let mut buf: Vec<u8> = Vec::new();
+            out.write(&mut Writer::new(&mut buf)).unwrap();
+            let read = BitVec::read(&mut Reader::new(&mut &buf[..])).unwrap();
+            // Only the wide layout records other blocks
+            let layout = if bits == 512 { RankLayout::Compact }
+                         else { RankLayout::Wide };
+            if out.rank_block() != rank_block || read.rank_block() != rank_block
+            || out.layout() != layout || read.layout() != layout
+            || out.ranks_.len() != read.ranks_.len() {
+                return false;
+            }
+            let methods = [SelectMethod::Portable, SelectMethod::Bmi2];
+            for x in [&out, &read].iter() {
+                if (0..plain.len() + 1).any(|i| x.rank1(i) != plain.rank1(i)) {
+                    return false;
+                }
+                for &method in methods.iter().filter(|m| m.is_available()) {
+                    if (0..plain.num_0s()).any(|i| {
+                        x.select0_with(i, method) != plain.select0(i)
+                    })
+                    || (0..plain.num_1s()).any(|i| {
+                        x.select1_with(i, method) != plain.select1(i)
+                    }) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn rank_blocks_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(rank_blocks_prop as fn(BitVec) -> bool);
+    }
+
+    #[test]
+    fn rank_blocks_manual() {
+        let _ = env_logger::init();
+        // Runs of every length up to 400, so that the bits land at every
+        // offset within the eighths of each block size, then lengths around
+        // the ends of the eighths
+        let mut bv = BitVec::new();
+        for run in 1..401 {
+            for _ in 0..run {
+                bv.push(run % 3 != 0);
+            }
+            if run % 50 == 0 {
+                assert!(rank_blocks_prop(bv.clone()));
+            }
+        }
+        for &bits in [1, 31, 32, 33, 127, 128, 129, 255, 257, 1023].iter() {
+            assert!(rank_blocks_prop(bv.iter().take(bits).collect()));
+        }
+        assert!(RankBlock::default().get() == 512);
+        // One whole 1024-bit block, all 1s
+        let words = vec![!0usize; 1024 / WORD_SIZE];
+        assert!(rank_blocks_prop(BitVec::from_words(words.iter(), 1024)));
+    }
+
+    #[test]
+    fn rank_blocks_read_corrupt() {
+        let _ = env_logger::init();
+        let words = vec![0x0123456789abcdefusize; 20];
+        let mut bv = BitVec::from_words(words.iter(), 20 * WORD_SIZE);
+        bv.build_with_options(true, true, RankLayout::Wide,
+                              SelectInterval::default(), RankBlock::new(256));
+        let mut buf: Vec<u8> = Vec::new();
+        bv.write(&mut Writer::new(&mut buf)).unwrap();
+        // After the units and their count comes `WIDE_MARKER`, then the
+        // block size
+        let offset = 8 + 20 * 8 + 4;
+        assert!(buf[offset..offset + 4] == [0, 1, 0, 0]);
+        for &bits in [0u8, 3].iter() {
+            // 0 reads as the default, which has the wrong number of ranks,
+            // and 768 isn't a block size
+            buf[offset + 1] = bits;
+            let err = BitVec::read(&mut Reader::new(&mut &buf[..]))
+                      .unwrap_err();
+            assert!(err.kind() == std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// `iter` and `ones` must agree with `at`, and collecting the bits must
+    /// give back the same vector
+    fn iter_prop(bv: BitVec, extra: Vec<bool>) -> bool {
+        let bits: Vec<bool> = (0..bv.len()).map(|i| bv.at(i)).collect();
+        let ones: Vec<usize> = (0..bv.len()).filter(|&i| bv.at(i)).collect();
+        let collected: BitVec = bv.iter().collect();
+        let mut extended = collected.clone();
+        extended.extend(extra.iter().cloned());
+        bv.iter().collect::<Vec<bool>>() == bits
+        && bv.iter().len() == bv.len()
+        && bv.ones().collect::<Vec<usize>>() == ones
+        && collected == bv
+        && extended.iter().eq(bits.iter().chain(extra.iter()).cloned())
+        && extended.num_1s() == extended.ones().count()
+    }
+
+    #[test]
+    fn iter_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(iter_prop as fn(BitVec, Vec<bool>) -> bool);
+    }
+
+    #[test]
+    fn layouts_manual() {
+        let words = vec![0x0123456789abcdefusize; 40];
+        let bv = BitVec::from_words(words.iter(), 40 * WORD_SIZE - 3);
+        assert!(layouts_prop(bv));
+        assert!(RankLayout::for_len(1000) == RankLayout::Compact);
+        assert!(RankLayout::for_len(std::u32::MAX as usize)
+                == RankLayout::Wide);
+    }
+
+    #[test]
+    fn bit_vector_read_corrupt() {
+        let _ = env_logger::init();
+        let words = vec![0x0123456789abcdefusize; 20];
+        let mut bv = BitVec::from_words(words.iter(), 20 * WORD_SIZE);
+        bv.build(true, true);
+        let mut short_ranks = bv.clone();
+        short_ranks.ranks_.pop();
+        let mut no_ranks = bv.clone();
+        no_ranks.ranks_.clear();
+        for corrupt in [short_ranks, no_ranks].iter() {
+            let mut buf: Vec<u8> = Vec::new();
+            corrupt.write(&mut Writer::new(&mut buf)).unwrap();
+            let err = BitVec::read(&mut Reader::new(&mut &buf[..]))
+                      .unwrap_err();
+            assert!(err.kind() == std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_bit_vector_last_block() {
+        let _ = env_logger::init();
+        // Sizes ending in the last 64-bit block of a rank
+        for &bits in [449, 500, 512 + 480].iter() {
+            let mut words = vec![0x5555555555555555usize;
+                                 (bits + WORD_SIZE - 1) / WORD_SIZE];
+            if bits % WORD_SIZE != 0 {
+                *words.last_mut().unwrap() &= (1 << (bits % WORD_SIZE)) - 1;
+            }
+            let mut bv = BitVec::from_words(words.iter(), bits);
+            bv.build(true, true);
+            assert!(!test_bit_vector_prop(bv).is_failure());
+        }
+    }
+
+    #[test]
+    fn test_bit_vector_rank_at_len() {
+        let _ = env_logger::init();
+        // Lengths with no unit or rank block past the last bit
+        for &bits in [64, 512, 1024].iter() {
+            let words = vec![0x0123456789abcdefusize; bits / WORD_SIZE];
+            for &layout in [RankLayout::Compact, RankLayout::Wide].iter() {
+                let mut bv = BitVec::from_words(words.iter(), bits);
+                bv.build_with_layout(true, true, layout);
+                assert!(bv.rank1(bits) == bv.num_1s());
+                assert!(bv.rank0(bits) == bv.num_0s());
+                assert!(!test_bit_vector_prop(bv).is_failure());
+            }
+        }
     }
 }
 
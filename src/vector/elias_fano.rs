@@ -0,0 +1,294 @@
+use std;
+use std::io;
+use std::iter::FromIterator;
+use base::WORD_SIZE;
+use io::{Reader, Writer, format_error};
+use super::bit_vec::{self, BitVec};
+use super::flat_vec::FlatVec;
+
+/// Non-decreasing sequence of integers less than some `universe`, in the
+/// Elias-Fano encoding.
+///
+/// The low `low_bits_` bits of each value go in `lows_`. The rest are stored
+/// in unary in `highs_`: there is a 0 at the end of each run of values with
+/// the same high bits, so the `k`th value has a 1 at `(value >> low_bits_) +
+/// k`. All told that is about `2 + log2(universe / len)` bits per value.
+#[derive(Clone, Debug)]
+pub struct EliasFano {
+    /// Built with select0 and select1
+    highs_: BitVec,
+    /// Empty if `low_bits_` is 0
+    lows_: FlatVec,
+    low_bits_: usize,
+    universe_: usize,
+}
+
+impl EliasFano {
+    pub fn new() -> EliasFano {
+        EliasFano::from_values(&[], 0)
+    }
+
+    /// Encode `values`, which must be non-decreasing and less than `universe`
+    pub fn from_values(values: &[usize], universe: usize) -> EliasFano {
+        EliasFano::from_sorted(values.iter().cloned(), values.len(), universe)
+    }
+
+    /// Encode the `len` values of `values` in one pass, without collecting
+    /// them first
+    pub fn from_sorted<T>(values: T, len: usize, universe: usize)
+      -> EliasFano where T: IntoIterator<Item=usize> {
+        let low_bits = low_bits_for(universe, len);
+        let mask = (1 << low_bits) - 1;
+        let mut highs = BitVec::new();
+        let mut lows: Vec<u32> = Vec::new();
+        let mut high = 0;
+        let mut prev = 0;
+        for value in values {
+            assert!(value < universe, "MARISA_BOUND_ERROR");
+            assert!(prev <= value, "MARISA_CODE_ERROR");
+            prev = value;
+            while high < value >> low_bits {
+                highs.push(false);
+                high += 1;
+            }
+            highs.push(true);
+            if low_bits != 0 {
+                lows.push((value & mask) as u32);
+            }
+        }
+        assert!(highs.len() - high == len, "MARISA_SIZE_ERROR");
+        while high <= universe >> low_bits {
+            highs.push(false);
+            high += 1;
+        }
+        highs.build(true, true);
+        EliasFano { highs_: highs, lows_: FlatVec::from_values(&lows),
+                    low_bits_: low_bits, universe_: universe }
+    }
+
+    /// Number of values
+    pub fn len(&self) -> usize {
+        self.highs_.num_1s()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Every value is less than this
+    pub fn universe(&self) -> usize {
+        self.universe_
+    }
+
+    /// The `i`th value
+    pub fn get(&self, i: usize) -> usize {
+        assert!(i < self.len(), "MARISA_BOUND_ERROR");
+        self.value(self.highs_.select1(i), i)
+    }
+
+    /// The first value that is at least `x`, and its index
+    pub fn next_geq(&self, x: usize) -> Option<(usize, usize)> {
+        let k = self.rank(x);
+        if k < self.len() {
+            Some((k, self.get(k)))
+        } else {
+            None
+        }
+    }
+
+    /// Number of values less than `x`
+    pub fn rank(&self, x: usize) -> usize {
+        let high = x >> self.low_bits_;
+        if high >= self.highs_.num_0s() {
+            return self.len();
+        }
+        let low = x & ((1 << self.low_bits_) - 1);
+        // The values with the same high bits as `x` are the 1s between the
+        // `high - 1`th 0 and the `high`th. Their low bits are sorted, so
+        // binary search them for the first that is at least `x`'s.
+        let begin = if high == 0 { 0 }
+                    else { self.highs_.select0(high - 1) + 1 - high };
+        let mut end = self.highs_.select0(high) - high;
+        let mut k = begin;
+        while k < end {
+            let middle = k + (end - k) / 2;
+            if self.low(middle) < low {
+                k = middle + 1;
+            } else {
+                end = middle;
+            }
+        }
+        k
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter { ef_: self, ones_: self.highs_.ones(), k_: 0 }
+    }
+
+    pub fn to_vec(&self) -> Vec<usize> {
+        self.iter().collect()
+    }
+
+    /// The `k`th value, given where its 1 is in `highs_`
+    fn value(&self, pos: usize, k: usize) -> usize {
+        ((pos - k) << self.low_bits_) | self.low(k)
+    }
+
+    fn low(&self, k: usize) -> usize {
+        if self.low_bits_ == 0 { 0 } else { self.lows_.at(k) as usize }
+    }
+
+    pub fn read(reader: &mut Reader) -> io::Result<EliasFano> {
+        let highs = try!(BitVec::read(reader));
+        let lows = try!(FlatVec::read(reader));
+        let low_bits = try!(reader.read_u64());
+        let universe = try!(reader.read_u64());
+        if low_bits > 32 || universe > std::usize::MAX as u64 {
+            return Err(format_error("Elias-Fano sequence"));
+        }
+        let (low_bits, universe) = (low_bits as usize, universe as usize);
+        if !highs.is_select0_enabled() || !highs.is_select1_enabled()
+        || (low_bits != 0 && lows.len() != highs.num_1s())
+        || highs.num_0s() != (universe >> low_bits) + 1 {
+            return Err(format_error("Elias-Fano sequence"));
+        }
+        Ok(EliasFano { highs_: highs, lows_: lows, low_bits_: low_bits,
+                       universe_: universe })
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(self.highs_.write(writer));
+        try!(self.lows_.write(writer));
+        try!(writer.write_u64(self.low_bits_ as u64));
+        writer.write_u64(self.universe_ as u64)
+    }
+}
+
+impl FromIterator<usize> for EliasFano {
+    /// Encode the values, with a universe just big enough for the last one
+    fn from_iter<T: IntoIterator<Item=usize>>(iter: T) -> EliasFano {
+        let values: Vec<usize> = iter.into_iter().collect();
+        let universe = values.last().map_or(Some(0),
+                                            |&last| last.checked_add(1));
+        assert!(universe.is_some(), "MARISA_SIZE_ERROR");
+        EliasFano::from_values(&values, universe.unwrap())
+    }
+}
+
+impl<'a> IntoIterator for &'a EliasFano {
+    type Item = usize;
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The values of an `EliasFano`, in order. Walks `highs_` a word at a time
+/// instead of calling select1 for each value.
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    ef_: &'a EliasFano,
+    ones_: bit_vec::Ones<'a>,
+    k_: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        self.ones_.next().map(|pos| {
+            self.k_ += 1;
+            self.ef_.value(pos, self.k_ - 1)
+        })
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let rest = self.ef_.len() - self.k_;
+        (rest, Some(rest))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+/// Low bits per value. About `log2(universe / len)` minimizes the size, and
+/// `FlatVec` holds at most 32.
+pub fn low_bits_for(universe: usize, len: usize) -> usize {
+    if len == 0 || universe <= len {
+        return 0;
+    }
+    let ratio = universe / len;
+    std::cmp::min(WORD_SIZE - 1 - ratio.leading_zeros() as usize, 32)
+}
+
+#[cfg(test)]
+mod test {
+    use std;
+    use env_logger;
+    use quickcheck as qc;
+    use io::{Reader, Writer};
+    use super::EliasFano;
+
+    fn elias_fano_prop(mut values: Vec<usize>, extra: usize,
+                       queries: Vec<usize>) -> bool {
+        values.sort();
+        let universe = values.last().map_or(0, |&last| last + 1)
+                       + extra % 1000;
+        let ef = EliasFano::from_values(&values, universe);
+        let mut buf: Vec<u8> = Vec::new();
+        ef.write(&mut Writer::new(&mut buf)).unwrap();
+        let read = EliasFano::read(&mut Reader::new(&mut &buf[..])).unwrap();
+        for x in [&ef, &read].iter() {
+            if x.len() != values.len() || x.universe() != universe
+            || x.to_vec() != values
+            || (0..values.len()).any(|i| x.get(i) != values[i]) {
+                return false;
+            }
+            let checks = queries.iter().cloned()
+                         .chain(values.iter().cloned())
+                         .chain(vec![0, universe, universe + 1]);
+            for q in checks {
+                let expected = values.iter().position(|&v| v >= q)
+                               .map(|k| (k, values[k]));
+                if x.next_geq(q) != expected
+                || x.rank(q) != expected.map_or(values.len(), |(k, _)| k) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn elias_fano_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(elias_fano_prop
+                       as fn(Vec<usize>, usize, Vec<usize>) -> bool);
+    }
+
+    #[test]
+    fn elias_fano_manual() {
+        let _ = env_logger::init();
+        let values = vec![0, 0, 3, 64, 64, 65, 9000, 99999];
+        assert!(elias_fano_prop(values.clone(), 0, vec![1, 4, 66, 9001]));
+        let ef: EliasFano = values.iter().cloned().collect();
+        assert!(ef.universe() == 100000 && ef.len() == 8);
+        assert!(ef.next_geq(1) == Some((2, 3)));
+        assert!(ef.next_geq(64) == Some((3, 64)));
+        assert!(ef.next_geq(9001) == Some((7, 99999)));
+        assert!(ef.next_geq(100000) == None);
+        assert!((&ef).into_iter().eq(values.into_iter()));
+        let empty = EliasFano::new();
+        assert!(empty.is_empty() && empty.next_geq(0) == None);
+        assert!(empty.iter().next() == None);
+        let dense: Vec<usize> = (0..5000).map(|i| i / 3).collect();
+        assert!(elias_fano_prop(dense, 5, vec![]));
+        // One long run of equal high bits
+        let mut same = vec![7; 100000];
+        same.push(1 << 20);
+        let ef = EliasFano::from_values(&same, 1 << 21);
+        assert!(ef.rank(7) == 0 && ef.rank(8) == 100000);
+        assert!(ef.next_geq(8) == Some((100000, 1 << 20)));
+    }
+
+    #[test]
+    #[should_panic(expected = "MARISA_SIZE_ERROR")]
+    fn elias_fano_max_value_manual() {
+        let _: EliasFano = vec![std::usize::MAX].into_iter().collect();
+    }
+}
@@ -1,7 +1,15 @@
 use std;
+use std::io;
+use std::iter::FromIterator;
 use base::WORD_SIZE;
+use io::{Reader, Writer, format_error};
 use super::util::vec_resize;
 
+/// Static vector of `u32` values, each stored in as many bits as the largest
+/// one needs.
+///
+/// The values are all given to `build` (or `collect`) at once. `extend`
+/// works, but builds the whole vector again.
 #[derive(Clone, Debug)]
 pub struct FlatVec {
     units_: Vec<usize>,
@@ -15,9 +23,6 @@ impl FlatVec {
         FlatVec { units_: Vec::new(), value_size_: 0, mask_: 0, len_: 0, }
     }
 
-    // I imagine there's a better way to do this? I guess implementing
-    // FromIterator would be a start
-
     pub fn from_values<'a, T, Q>(x: T) -> FlatVec
       where T: Clone + IntoIterator<Item=&'a u32, IntoIter=Q>,
             Q: ExactSizeIterator<Item=&'a u32> {
@@ -113,11 +118,12 @@ impl FlatVec {
         self.units_.len()
     }
 
-    // FIXME: use From/Into
-    fn to_vec(&self) -> Vec<u32> {
-        let mut vals = Vec::new();
-        for i in 0..self.len() { vals.push(self.at(i)); }
-        vals
+    pub fn iter(&self) -> Iter {
+        Iter { fv_: self, pos_: 0 }
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        self.iter().collect()
     }
 
 /*
@@ -159,34 +165,31 @@ impl FlatVec {
     }
   }
 
-  void read_(Reader &reader) {
-    units_.read(reader);
-    {
-      u32 temp_value_size;
-      reader.read(&temp_value_size);
-      MARISA_THROW_IF(temp_value_size > 32, MARISA_FORMAT_ERROR);
-      value_size_ = temp_value_size;
-    }
-    {
-      u32 temp_mask;
-      reader.read(&temp_mask);
-      mask_ = temp_mask;
-    }
-    {
-      u64 temp_size;
-      reader.read(&temp_size);
-      MARISA_THROW_IF(temp_size > MARISA_SIZE_MAX, MARISA_SIZE_ERROR);
-      len_ = (usize)temp_size;
+*/
+
+    pub fn read(reader: &mut Reader) -> io::Result<FlatVec> {
+        let units = try!(reader.read_vec::<usize>());
+        let value_size = try!(reader.read_u32()) as usize;
+        if value_size > 32 {
+            return Err(format_error("flat vector value size"));
+        }
+        let mask = try!(reader.read_u32());
+        let len = try!(reader.read_u64());
+        if len > std::usize::MAX as u64
+        || (len as usize).saturating_mul(value_size)
+           > units.len() * WORD_SIZE {
+            return Err(format_error("flat vector size"));
+        }
+        Ok(FlatVec { units_: units, value_size_: value_size, mask_: mask,
+                     len_: len as usize })
     }
-  }
 
-  void write_(Writer &writer) const {
-    units_.write(writer);
-    writer.write((u32)value_size_);
-    writer.write((u32)mask_);
-    writer.write((u64)len_);
-  }
-*/
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(writer.write_vec(&self.units_));
+        try!(writer.write_u32(self.value_size_ as u32));
+        try!(writer.write_u32(self.mask_));
+        writer.write_u64(self.len_ as u64)
+    }
 
     fn set(&mut self, i: usize, value: u32) {
         assert!(i < self.len(), "MARISA_BOUND_ERROR");
@@ -213,6 +216,54 @@ impl FlatVec {
     }
 }
 
+impl FromIterator<u32> for FlatVec {
+    fn from_iter<T: IntoIterator<Item=u32>>(iter: T) -> FlatVec {
+        let values: Vec<u32> = iter.into_iter().collect();
+        FlatVec::from_values(&values)
+    }
+}
+
+impl Extend<u32> for FlatVec {
+    /// Append values, rebuilding the vector
+    fn extend<T: IntoIterator<Item=u32>>(&mut self, iter: T) {
+        let mut values = self.to_vec();
+        values.extend(iter);
+        self.build(&values);
+    }
+}
+
+impl<'a> IntoIterator for &'a FlatVec {
+    type Item = u32;
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The values of a `FlatVec`, in order
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    fv_: &'a FlatVec,
+    pos_: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = u32;
+    fn next(&mut self) -> Option<u32> {
+        if self.pos_ == self.fv_.len() {
+            return None;
+        }
+        self.pos_ += 1;
+        Some(self.fv_.at(self.pos_ - 1))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let rest = self.fv_.len() - self.pos_;
+        (rest, Some(rest))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
 #[cfg(test)]
 mod test {
     use std;
@@ -259,6 +310,22 @@ mod test {
         qc::quickcheck(prop as fn(FlatVec) -> bool);
     }
 
+    #[test]
+    fn flat_vec_iter_qc() {
+        let _ = env_logger::init();
+        fn prop(a: Vec<u32>, b: Vec<u32>) -> bool {
+            let mut fv: FlatVec = a.iter().cloned().collect();
+            if fv.iter().len() != a.len() || fv.to_vec() != a
+            || !(&fv).into_iter().eq(a.iter().cloned()) {
+                return false;
+            }
+            fv.extend(b.iter().cloned());
+            fv.len() == a.len() + b.len()
+            && fv.iter().eq(a.iter().chain(b.iter()).cloned())
+        }
+        qc::quickcheck(prop as fn(Vec<u32>, Vec<u32>) -> bool);
+    }
+
     // From marisa-trie/tests/vector-test.cc
     #[test]
     fn test_flat_vec_manual() {
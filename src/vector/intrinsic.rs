@@ -1,7 +1,10 @@
 
-// FIXME: For now, these are portable versions of intrinsic functions we need.
-// They should be replaced by the fastest available intrinsics on each supported
-// platform, and the fastest known portable version on other platforms.
+// FIXME: For now, most of these are portable versions of intrinsic functions
+// we need. They should be replaced by the fastest available intrinsics on each
+// supported platform, and the fastest known portable version on other
+// platforms. Select already picks BMI2 at runtime where the CPU has it.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Count trailing zeros
 pub trait Ctz {
@@ -65,13 +68,77 @@ impl Ctz for usize {
     }
 }
 
+/// `SelectMethod::detect`'s answer, once it has checked the CPU
+static DETECTED: AtomicUsize = AtomicUsize::new(0);
+const PORTABLE: usize = 1;
+const BMI2: usize = 2;
+
+/// How to find the `i`th 1 in a word for `BitVec` select
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectMethod {
+    /// Byte counts in one word by shifts and masks, then a table lookup in
+    /// the right byte. Works everywhere.
+    Portable,
+    /// `pdep` moves a single 1 to where the `i`th 1 is, and `tzcnt` reads off
+    /// its position. Needs an x86-64 CPU with BMI2.
+    Bmi2,
+}
+
+impl SelectMethod {
+    /// The fastest method this CPU has. The CPU is only checked on the first
+    /// call; after that this is one atomic load.
+    pub fn detect() -> SelectMethod {
+        match DETECTED.load(Ordering::Relaxed) {
+            PORTABLE => SelectMethod::Portable,
+            BMI2 => SelectMethod::Bmi2,
+            _ => {
+                let method = if SelectMethod::Bmi2.is_available() {
+                    SelectMethod::Bmi2
+                } else {
+                    SelectMethod::Portable
+                };
+                DETECTED.store(if method == SelectMethod::Bmi2 { BMI2 }
+                               else { PORTABLE }, Ordering::Relaxed);
+                method
+            },
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn is_available(self) -> bool {
+        match self {
+            SelectMethod::Portable => true,
+            SelectMethod::Bmi2 => is_x86_feature_detected!("bmi2"),
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn is_available(self) -> bool {
+        self == SelectMethod::Portable
+    }
+}
+
+/// Position of the `i`th 1 in `x`, counting from 0. `x` must have more than
+/// `i` 1s, and the CPU must have BMI2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi2")]
+pub unsafe fn select_bmi2(x: u64, i: usize) -> usize {
+    use std::arch::x86_64::{_pdep_u64, _tzcnt_u64};
+    _tzcnt_u64(_pdep_u64(1 << i, x)) as usize
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub unsafe fn select_bmi2(_: u64, _: usize) -> usize {
+    panic!("MARISA_STATE_ERROR")
+}
+
 
 #[cfg(test)]
 mod test {
     use std;
     use quickcheck as qc;
     use env_logger;
-    use super::Ctz;
+    use super::{Ctz, SelectMethod, select_bmi2};
 
     #[test]
     fn test_ctz_usize() {
@@ -88,5 +155,44 @@ mod test {
         }
         qc::quickcheck(prop as fn(usize) -> bool);
     }
-}
 
+    fn naive_select(x: u64, i: usize) -> usize {
+        let mut seen = 0;
+        for pos in 0..64 {
+            if x & (1 << pos) != 0 {
+                if seen == i {
+                    return pos;
+                }
+                seen += 1;
+            }
+        }
+        unreachable!();
+    }
+
+    #[test]
+    fn test_select_bmi2() {
+        let _ = env_logger::init();
+        assert!(SelectMethod::Portable.is_available());
+        // The cached answer must match a fresh check
+        assert!(SelectMethod::detect() == SelectMethod::detect());
+        assert!((SelectMethod::detect() == SelectMethod::Bmi2)
+                == SelectMethod::Bmi2.is_available());
+        if SelectMethod::detect() != SelectMethod::Bmi2 {
+            return;
+        }
+        fn prop(x: u64, i: usize) -> qc::TestResult {
+            if x == 0 {
+                return qc::TestResult::discard();
+            }
+            let i = i % x.count_ones() as usize;
+            let pos = unsafe { select_bmi2(x, i) };
+            qc::TestResult::from_bool(pos == naive_select(x, i))
+        }
+        qc::quickcheck(prop as fn(u64, usize) -> qc::TestResult);
+        for &x in [1u64, !0, 1 << 63, 0x8000000000000001].iter() {
+            for i in 0..x.count_ones() as usize {
+                assert!(unsafe { select_bmi2(x, i) } == naive_select(x, i));
+            }
+        }
+    }
+}
@@ -1,9 +1,14 @@
+pub mod bit_flags;
 pub mod bit_vec;
+pub mod elias_fano;
 pub mod flat_vec;
 pub mod packed_vec;
+pub mod rank_index;
+pub mod rank_select;
+pub mod sparse_bit_vec;
+pub mod wavelet_matrix;
 
 mod intrinsic;
 mod pop_count;
-mod rank_index;
 mod util;
 
@@ -1,187 +1,495 @@
-use base::WORD_SIZE;
 use std;
-use std::default::Default;
-use super::util::vec_resize;
+use std::io;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use io::{Reader, Writer, format_error};
 
-/// Static packed vector of u32 values. Bit size of each element is determined
-/// by the size of the largest element.
-struct PackedVec {
-    units_: Vec<usize>,
+/// Values a `PackedVec` can hold
+pub trait PackedValue: Copy {
+    /// Widest value, in bits
+    fn max_bits() -> usize;
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+impl PackedValue for u32 {
+    fn max_bits() -> usize {
+        32
+    }
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+    fn from_u64(value: u64) -> u32 {
+        value as u32
+    }
+}
+
+impl PackedValue for u64 {
+    fn max_bits() -> usize {
+        64
+    }
+    fn to_u64(self) -> u64 {
+        self
+    }
+    fn from_u64(value: u64) -> u64 {
+        value
+    }
+}
+
+/// Packed vector of `u32` or `u64` values. Every value takes as many bits as
+/// the largest one needs.
+///
+/// Unlike `FlatVec`, values can be changed and appended. Storing a value
+/// that doesn't fit repacks the whole vector at the new width, which happens
+/// at most once per bit of `T`.
+///
+/// Units are 64 bits on every platform, so written vectors are portable.
+/// `MappedPackedVec` reads one in place, out of a buffer such as a memory
+/// mapped file.
+#[derive(Clone, Debug)]
+pub struct PackedVec<T: PackedValue = u32> {
+    units_: Vec<u64>,
     value_size_: usize,
-    mask_: u32,
     size_: usize,
+    phantom_: PhantomData<T>,
 }
 
-impl PackedVec {
-    fn new() -> PackedVec {
-        PackedVec {
-            units_: Default::default(),
-            value_size_: 0,
-            mask_: 0,
-            size_: 0,
-        }
+impl<T: PackedValue> PackedVec<T> {
+    pub fn new() -> PackedVec<T> {
+        PackedVec::with_value_size(0)
     }
- 
-    fn build(values: &Vec<u32>) -> PackedVec {
-        let mut out = PackedVec::new();
 
-        let mut max_value = match values.iter().max() {
-            Some(x) => *x,
-            None => { return out; }
-        };
+    /// An empty vector that starts out `value_size` bits wide
+    pub fn with_value_size(value_size: usize) -> PackedVec<T> {
+        assert!(value_size <= T::max_bits(), "MARISA_RANGE_ERROR");
+        PackedVec { units_: Vec::new(), value_size_: value_size, size_: 0,
+                    phantom_: PhantomData }
+    }
 
-        let mut value_size: usize = 0;
-        while max_value != 0 {
-            value_size += 1;
-            max_value >>= 1;
+    pub fn build(values: &[T]) -> PackedVec<T> {
+        let max_value = values.iter().map(|x| x.to_u64()).max().unwrap_or(0);
+        let mut out = PackedVec::with_value_size(bits_for(max_value));
+        out.units_.reserve(num_units(out.value_size_, values.len()));
+        for &value in values {
+            out.push(value);
         }
+        out
+    }
 
-        let num_units = match value_size {
-            0 => if values.is_empty() { 0 } else { 64 / WORD_SIZE },
-            vs => {
-                let tmp = value_size as u64 * values.len() as u64;
-                // # of words, rounded up
-                let tmp = ((tmp + (WORD_SIZE - 1) as u64)
-                           / WORD_SIZE as u64) as usize;
-                tmp + tmp % (64 / WORD_SIZE)
-            }
-        };
+    pub fn at(&self, i: usize) -> T {
+        assert!(i < self.size_, "MARISA_BOUND_ERROR");
+        T::from_u64(get(&self.units_[..], self.value_size_, i))
+    }
+
+    /// Set value `i`, widening the vector if `value` doesn't fit
+    pub fn set(&mut self, i: usize, value: T) {
+        assert!(i < self.size_, "MARISA_BOUND_ERROR");
+        let value = value.to_u64();
+        self.reserve_bits(bits_for(value));
+        let value_size = self.value_size_;
+        put(&mut self.units_, value_size, i, value);
+    }
+
+    /// Append a value, widening the vector if it doesn't fit
+    pub fn push(&mut self, value: T) {
+        let value = value.to_u64();
+        self.reserve_bits(bits_for(value));
+        self.size_ += 1;
+        let needed = num_units(self.value_size_, self.size_);
+        self.units_.resize(needed, 0);
+        let (value_size, i) = (self.value_size_, self.size_ - 1);
+        put(&mut self.units_, value_size, i, value);
+    }
 
-        vec_resize(&mut out.units_, num_units);
-        if num_units > 0 {
-            *out.units_.last_mut().unwrap() = 0;
+    pub fn pop(&mut self) -> Option<T> {
+        if self.size_ == 0 {
+            return None;
+        }
+        let value = self.at(self.size_ - 1);
+        self.size_ -= 1;
+        let needed = num_units(self.value_size_, self.size_);
+        self.units_.truncate(needed);
+        // Keep the bits past the end clear for `==` and `write`
+        let used = self.value_size_ * self.size_ % 64;
+        if used != 0 {
+            *self.units_.last_mut().unwrap() &= (1 << used) - 1;
         }
-  
-        out.value_size_ = value_size;
-        if value_size != 0 {
-            out.mask_ = std::u32::MAX >> (32 - value_size);
+        Some(value)
+    }
+
+    /// Repack at `value_size` bits or more
+    fn reserve_bits(&mut self, value_size: usize) {
+        if value_size <= self.value_size_ {
+            return;
         }
-        out.size_ = values.len();
-  
-        for i in 0..values.len() {
-            out.set(i, values[i]);
+        let mut units: Vec<u64> = vec![0; num_units(value_size, self.size_)];
+        for i in 0..self.size_ {
+            put(&mut units, value_size, i, get(&self.units_[..],
+                                               self.value_size_, i));
         }
-        out
+        self.units_ = units;
+        self.value_size_ = value_size;
+    }
+
+    pub fn iter(&self) -> Iter<T> {
+        Iter { pv_: self, pos_: 0 }
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().collect()
     }
 
-// Need to implement Mapper, Reader, Writer first. I believe we can use
-// existing standard traits for Reader and Writer at least.
-/*    void map(Mapper &mapper) {
-        units_.map(mapper);
-        {
-            u32 temp_value_size;
-            mapper.map(&temp_value_size);
-            MARISA_THROW_IF(temp_value_size > 32, MARISA_FORMAT_ERROR);
-            value_size_ = temp_value_size;
-        }
-        {
-            u32 temp_mask;
-            mapper.map(&temp_mask);
-            mask_ = temp_mask;
-        }
-        {
-            u64 temp_size;
-            mapper.map(&temp_size);
-            MARISA_THROW_IF(temp_size > MARISA_SIZE_MAX, MARISA_SIZE_ERROR);
-            size_ = (usize)temp_size;
-        }
-    }
-
-    void read(Reader &reader) {
-        units_.read(reader);
-        {
-            u32 temp_value_size;
-            reader.read(&temp_value_size);
-            MARISA_THROW_IF(temp_value_size > 32, MARISA_FORMAT_ERROR);
-            value_size_ = temp_value_size;
-        }
-        {
-            u32 temp_mask;
-            reader.read(&temp_mask);
-            mask_ = temp_mask;
-        }
-        {
-            u64 temp_size;
-            reader.read(&temp_size);
-            MARISA_THROW_IF(temp_size > MARISA_SIZE_MAX, MARISA_SIZE_ERROR);
-            size_ = (usize)temp_size;
-        }
-    }
- 
-    void write(Writer &writer) const {
-        units_.write(writer);
-        writer.write((u32)value_size_);
-        writer.write((u32)mask_);
-        writer.write((u64)size_);
-    }
-*/
-
-    // I'm not sure whether it's possible to create a nice iterator over a class
-    // like this (that would require a proxy object) just yet. Should try it
-    // though.
-
-    fn at(&self, i: usize) -> u32 {
-      assert!(i < self.size_, "MARISA_BOUND_ERROR");
-  
-      let pos: usize = i * self.value_size_;
-      let unit_id: usize = pos / WORD_SIZE;
-      let unit_offset: usize = pos % WORD_SIZE;
-  
-      (if unit_offset + self.value_size_ <= WORD_SIZE {
-          self.units_[unit_id] >> unit_offset
-      } else {
-          (self.units_[unit_id] >> unit_offset)
-          | (self.units_[unit_id + 1] << (WORD_SIZE - unit_offset))
-      })
-      as u32 & self.mask_
-    }
-  
-    fn value_size(&self) -> usize {
+    pub fn value_size(&self) -> usize {
         self.value_size_
     }
-    fn mask(&self) -> u32 {
-        self.mask_
+    pub fn mask(&self) -> u64 {
+        mask(self.value_size_)
     }
-  
-    fn is_empty(&self) -> bool {
+
+    pub fn is_empty(&self) -> bool {
         self.size_ == 0
     }
-    fn size(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.size_
     }
-    fn total_size(&self) -> usize {
-        self.units_.len() * std::mem::size_of::<usize>()
+    pub fn total_size(&self) -> usize {
+        self.units_.len() * std::mem::size_of::<u64>()
+    }
+    /// Bytes taken by `write`
+    pub fn io_size(&self) -> usize {
+        8 + self.total_size() + (4 * 2) + 8
     }
-//    fn io_size(&self) -> usize {
-//        units_.io_size()
-//        + (std::mem::size_of::<u32>() * 2)
-//        + std::mem::size_of::<u64>()
-//    }
 
-    fn clear(&mut self) {
+    pub fn clear(&mut self) {
         *self = PackedVec::new();
     }
- 
-//   private:
-    fn set(&mut self, i: usize, value: u32) {
- 
-        // FIXME: is this assertion correct...?
+
+    pub fn read(reader: &mut Reader) -> io::Result<PackedVec<T>> {
+        let units = try!(reader.read_vec::<u64>());
+        let (value_size, size) = try!(read_params::<T>(
+            try!(reader.read_u32()), try!(reader.read_u32()),
+            try!(reader.read_u64()), units.len()));
+        Ok(PackedVec { units_: units, value_size_: value_size, size_: size,
+                       phantom_: PhantomData })
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(writer.write_vec(&self.units_));
+        try!(writer.write_u32(self.value_size_ as u32));
+        try!(writer.write_u32(T::max_bits() as u32));
+        writer.write_u64(self.size_ as u64)
+    }
+}
+
+impl<T: PackedValue> PartialEq for PackedVec<T> {
+    fn eq(&self, other: &PackedVec<T>) -> bool {
+        self.size_ == other.size_
+        && self.iter().map(T::to_u64).eq(other.iter().map(T::to_u64))
+    }
+}
+
+impl<T: PackedValue> FromIterator<T> for PackedVec<T> {
+    fn from_iter<I: IntoIterator<Item=T>>(iter: I) -> PackedVec<T> {
+        let values: Vec<T> = iter.into_iter().collect();
+        PackedVec::build(&values)
+    }
+}
+
+impl<T: PackedValue> Extend<T> for PackedVec<T> {
+    fn extend<I: IntoIterator<Item=T>>(&mut self, iter: I) {
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+impl<'a, T: PackedValue> IntoIterator for &'a PackedVec<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// The values of a `PackedVec`, in order
+#[derive(Clone, Debug)]
+pub struct Iter<'a, T: 'a + PackedValue> {
+    pv_: &'a PackedVec<T>,
+    pos_: usize,
+}
+
+impl<'a, T: PackedValue> Iterator for Iter<'a, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        if self.pos_ == self.pv_.len() {
+            return None;
+        }
+        self.pos_ += 1;
+        Some(self.pv_.at(self.pos_ - 1))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let rest = self.pv_.len() - self.pos_;
+        (rest, Some(rest))
+    }
+}
+
+impl<'a, T: PackedValue> ExactSizeIterator for Iter<'a, T> {}
+
+/// A `PackedVec` read in place from the bytes `PackedVec::write` wrote.
+/// Nothing is copied, and the bytes need no alignment.
+#[derive(Clone, Copy, Debug)]
+pub struct MappedPackedVec<'a, T: PackedValue = u32> {
+    units_: MappedUnits<'a>,
+    value_size_: usize,
+    size_: usize,
+    phantom_: PhantomData<T>,
+}
+
+impl<'a, T: PackedValue> MappedPackedVec<'a, T> {
+    /// Map the vector at the start of `bytes`. Also returns the bytes after
+    /// it.
+    pub fn map(bytes: &'a [u8])
+      -> io::Result<(MappedPackedVec<'a, T>, &'a [u8])> {
+        let total_size = try!(le_u64(bytes, 0));
+        if total_size % 8 != 0 || total_size > (bytes.len() - 8) as u64 {
+            return Err(format_error("packed vector units"));
+        }
+        let end = 8 + total_size as usize;
+        let units = MappedUnits(&bytes[8..end]);
+        let params = try!(le_u64(bytes, end));
+        let (value_size, size) = try!(read_params::<T>(
+            params as u32, (params >> 32) as u32, try!(le_u64(bytes, end + 8)),
+            total_size as usize / 8));
+        Ok((MappedPackedVec { units_: units, value_size_: value_size,
+                              size_: size, phantom_: PhantomData },
+            &bytes[end + 16..]))
+    }
+
+    pub fn at(&self, i: usize) -> T {
         assert!(i < self.size_, "MARISA_BOUND_ERROR");
-        assert!(value <= self.mask_, "MARISA_RANGE_ERROR");
-  
-        let pos: usize = i * self.value_size_;
-        let unit_id: usize = pos / WORD_SIZE;
-        let unit_offset: usize = pos % WORD_SIZE;
-  
-        self.units_[unit_id] &= !((self.mask_ as usize) << unit_offset);
-        self.units_[unit_id] |= ((value & self.mask_) as usize) << unit_offset;
-        if (unit_offset + self.value_size_) > WORD_SIZE {
-            self.units_[unit_id + 1] &=
-                !((self.mask_ as usize) >> (WORD_SIZE - unit_offset));
-            self.units_[unit_id + 1] |=
-                ((value & self.mask_) as usize)
-                >> (WORD_SIZE - unit_offset);
+        T::from_u64(get(&self.units_, self.value_size_, i))
+    }
+
+    pub fn value_size(&self) -> usize {
+        self.value_size_
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size_ == 0
+    }
+    pub fn len(&self) -> usize {
+        self.size_
+    }
+
+    /// Copy into an owned vector
+    pub fn to_packed_vec(&self) -> PackedVec<T> {
+        let mut out = PackedVec::with_value_size(self.value_size_);
+        for i in 0..self.size_ {
+            out.push(self.at(i));
+        }
+        out
+    }
+}
+
+/// Where the 64-bit units of a packed vector come from
+trait Units {
+    fn unit(&self, i: usize) -> u64;
+}
+
+impl Units for [u64] {
+    fn unit(&self, i: usize) -> u64 {
+        self[i]
+    }
+}
+
+/// Little-endian units in a byte buffer
+#[derive(Clone, Copy, Debug)]
+struct MappedUnits<'a>(&'a [u8]);
+
+impl<'a> Units for MappedUnits<'a> {
+    fn unit(&self, i: usize) -> u64 {
+        self.0[i * 8..(i + 1) * 8].iter().rev()
+            .fold(0, |acc, &b| (acc << 8) | b as u64)
+    }
+}
+
+fn le_u64(bytes: &[u8], pos: usize) -> io::Result<u64> {
+    if bytes.len() < pos + 8 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  "packed vector"));
+    }
+    Ok(MappedUnits(&bytes[pos..pos + 8]).unit(0))
+}
+
+/// Check the sizes written after the units
+fn read_params<T: PackedValue>(value_size: u32, max_bits: u32, size: u64,
+                               num_units_read: usize)
+  -> io::Result<(usize, usize)> {
+    let value_size = value_size as usize;
+    if max_bits as usize != T::max_bits() || value_size > T::max_bits() {
+        return Err(format_error("packed vector value size"));
+    }
+    if size > std::usize::MAX as u64
+    || checked_num_units(value_size, size as usize) != Some(num_units_read) {
+        return Err(format_error("packed vector size"));
+    }
+    Ok((value_size, size as usize))
+}
+
+fn bits_for(value: u64) -> usize {
+    64 - value.leading_zeros() as usize
+}
+
+fn mask(value_size: usize) -> u64 {
+    if value_size == 64 { !0 } else { (1 << value_size) - 1 }
+}
+
+fn num_units(value_size: usize, size: usize) -> usize {
+    let num_units = checked_num_units(value_size, size);
+    assert!(num_units.is_some(), "MARISA_SIZE_ERROR");
+    num_units.unwrap()
+}
+
+/// `num_units`, or `None` if the size is too big to count, as it may be in
+/// a corrupt file
+fn checked_num_units(value_size: usize, size: usize) -> Option<usize> {
+    (value_size as u64).checked_mul(size as u64).and_then(|bits| {
+        let units = bits / 64 + if bits % 64 != 0 { 1 } else { 0 };
+        if units <= std::usize::MAX as u64 {
+            Some(units as usize)
+        } else {
+            None
         }
+    })
+}
+
+fn get<U: Units + ?Sized>(units: &U, value_size: usize, i: usize) -> u64 {
+    if value_size == 0 {
+        return 0;
+    }
+    let pos = i * value_size;
+    let (unit_id, unit_offset) = (pos / 64, pos % 64);
+    let mut value = units.unit(unit_id) >> unit_offset;
+    if unit_offset + value_size > 64 {
+        value |= units.unit(unit_id + 1) << (64 - unit_offset);
+    }
+    value & mask(value_size)
+}
+
+fn put(units: &mut [u64], value_size: usize, i: usize, value: u64) {
+    if value_size == 0 {
+        return;
+    }
+    let mask = mask(value_size);
+    assert!(value <= mask, "MARISA_RANGE_ERROR");
+    let pos = i * value_size;
+    let (unit_id, unit_offset) = (pos / 64, pos % 64);
+    units[unit_id] &= !(mask << unit_offset);
+    units[unit_id] |= value << unit_offset;
+    if unit_offset + value_size > 64 {
+        units[unit_id + 1] &= !(mask >> (64 - unit_offset));
+        units[unit_id + 1] |= value >> (64 - unit_offset);
     }
 }
 
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use io::{Reader, Writer};
+    use super::{MappedPackedVec, PackedValue, PackedVec};
+
+    /// Push, set and pop like a `Vec`, then round trip through `write`, and
+    /// through `read` and `map`
+    fn packed_vec_prop<T>(values: Vec<T>, sets: Vec<(usize, T)>) -> bool
+      where T: PackedValue + Eq + Ord + qc::Arbitrary {
+        let mut pv: PackedVec<T> = PackedVec::new();
+        let mut expected: Vec<T> = Vec::new();
+        for &value in values.iter() {
+            pv.push(value);
+            expected.push(value);
+        }
+        if pv.to_vec() != expected
+        || PackedVec::build(&expected).to_vec() != expected {
+            return false;
+        }
+        for &(i, value) in sets.iter() {
+            if !expected.is_empty() {
+                let i = i % expected.len();
+                pv.set(i, value);
+                expected[i] = value;
+            }
+        }
+        if let Some(last) = expected.pop() {
+            if pv.pop() != Some(last) {
+                return false;
+            }
+        }
+        let max = expected.iter().map(|x| x.to_u64()).max().unwrap_or(0);
+        if pv.to_vec() != expected || pv.len() != expected.len()
+        || pv.mask() < max {
+            return false;
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        pv.write(&mut Writer::new(&mut buf)).unwrap();
+        buf.extend(b"rest");
+        let read: PackedVec<T> = PackedVec::read(&mut Reader::new(
+            &mut &buf[..])).unwrap();
+        let (mapped, rest) = MappedPackedVec::<T>::map(&buf[..]).unwrap();
+        buf.len() == pv.io_size() + 4 && rest == b"rest"
+        && read == pv && read.value_size() == pv.value_size()
+        && mapped.len() == pv.len()
+        && (0..pv.len()).all(|i| mapped.at(i) == expected[i])
+        && mapped.to_packed_vec() == pv
+    }
+
+    #[test]
+    fn packed_vec_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(packed_vec_prop::<u32>
+                       as fn(Vec<u32>, Vec<(usize, u32)>) -> bool);
+        qc::quickcheck(packed_vec_prop::<u64>
+                       as fn(Vec<u64>, Vec<(usize, u64)>) -> bool);
+    }
+
+    #[test]
+    fn packed_vec_manual() {
+        let _ = env_logger::init();
+        let mut pv: PackedVec<u64> = PackedVec::new();
+        assert!(pv.value_size() == 0 && pv.is_empty());
+        pv.push(0);
+        pv.push(0);
+        assert!(pv.value_size() == 0 && pv.total_size() == 0);
+        pv.push(5);
+        assert!(pv.value_size() == 3 && pv.to_vec() == vec![0, 0, 5]);
+        pv.set(0, !0);
+        assert!(pv.value_size() == 64 && pv.mask() == !0);
+        assert!(pv.to_vec() == vec![!0, 0, 5]);
+        let collected: PackedVec<u64> = pv.iter().collect();
+        assert!(collected == pv);
+        let mut small: PackedVec = vec![1u32, 2, 3].into_iter().collect();
+        small.extend(vec![1 << 20]);
+        assert!(small.value_size() == 21);
+        assert!((&small).into_iter().eq(vec![1, 2, 3, 1 << 20]));
+
+        // A u32 vector can't be read as u64, and truncated input fails
+        let mut buf: Vec<u8> = Vec::new();
+        small.write(&mut Writer::new(&mut buf)).unwrap();
+        assert!(PackedVec::<u64>::read(&mut Reader::new(&mut &buf[..]))
+                .is_err());
+        assert!(MappedPackedVec::<u64>::map(&buf[..]).is_err());
+        assert!(MappedPackedVec::<u32>::map(&buf[..buf.len() - 1]).is_err());
+        assert!(PackedVec::<u32>::read(&mut Reader::new(
+            &mut &buf[..buf.len() - 1])).is_err());
+
+        // So does a size whose bit count overflows
+        let len = buf.len();
+        for byte in buf[len - 8..].iter_mut() {
+            *byte = 0xFF;
+        }
+        buf[len - 1] = 0x7F;
+        assert!(PackedVec::<u32>::read(&mut Reader::new(&mut &buf[..]))
+                .is_err());
+        assert!(MappedPackedVec::<u32>::map(&buf[..]).is_err());
+    }
+}
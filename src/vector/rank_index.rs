@@ -1,3 +1,14 @@
+use std::io;
+use io::{Element, Reader, Writer};
+
+/// Rank directory entry for 512 bits of a `BitVec`: the number of 1s before
+/// the block (`abs`), and the number before each of its 64-bit words after
+/// the first, relative to the block (`rel1` to `rel7`). The relative counts
+/// are packed into 64 bits, so each is limited to what fits before its word.
+///
+/// 256-bit blocks use the same fields, for each 32 bits. 1024-bit blocks have
+/// a count for each 128 bits, which don't fit that way, so `set_long_rel`
+/// packs them differently.
 #[derive(Clone, Copy, Debug)]
 pub struct RankIndex {
     abs_: u32,
@@ -52,6 +63,68 @@ impl RankIndex {
                      | ((value & 0x1FF).wrapping_shl(18));
     }
 
+    /// `rel1` to `rel7` by number, with `rel(0)` as 0
+    pub fn rel(&self, k: usize) -> u32 {
+        match k {
+            0 => 0,
+            1 => self.rel1(),
+            2 => self.rel2(),
+            3 => self.rel3(),
+            4 => self.rel4(),
+            5 => self.rel5(),
+            6 => self.rel6(),
+            7 => self.rel7(),
+            _ => panic!("MARISA_RANGE_ERROR"),
+        }
+    }
+    pub fn set_rel(&mut self, k: usize, value: u32) {
+        match k {
+            1 => self.set_rel1(value),
+            2 => self.set_rel2(value),
+            3 => self.set_rel3(value),
+            4 => self.set_rel4(value),
+            5 => self.set_rel5(value),
+            6 => self.set_rel6(value),
+            7 => self.set_rel7(value),
+            _ => panic!("MARISA_RANGE_ERROR"),
+        }
+    }
+
+    /// Relative count `k` of a 1024-bit block, the number of 1s before its
+    /// `k`th 128 bits. The first four are relative to the block and take 8,
+    /// 9, 9 and 10 bits. The last three are relative to the fourth, so they
+    /// take 8, 9 and 9 bits again, and all seven fit in 62.
+    pub fn long_rel(&self, k: usize) -> u32 {
+        assert!(k < 8, "MARISA_RANGE_ERROR");
+        if k == 0 {
+            return 0;
+        }
+        let (offset, width) = LONG_RELS[k - 1];
+        let value = (self.rels() >> offset) as u32 & ((1 << width) - 1);
+        if k > 4 { value + self.long_rel(4) } else { value }
+    }
+    /// Set the counts in order, since the last three depend on the fourth
+    pub fn set_long_rel(&mut self, k: usize, value: u32) {
+        assert!(k >= 1 && k < 8, "MARISA_RANGE_ERROR");
+        let value = if k > 4 {
+            let base = self.long_rel(4);
+            assert!(value >= base, "MARISA_RANGE_ERROR");
+            value - base
+        } else {
+            value
+        };
+        let (offset, width) = LONG_RELS[k - 1];
+        assert!(value <= 128 * (k as u32 - (if k > 4 { 4 } else { 0 })),
+                "MARISA_RANGE_ERROR");
+        let mask = ((1u64 << width) - 1) << offset;
+        let rels = (self.rels() & !mask) | ((value as u64) << offset);
+        self.rel_lo_ = rels as u32;
+        self.rel_hi_ = (rels >> 32) as u32;
+    }
+    fn rels(&self) -> u64 {
+        (self.rel_hi_ as u64) << 32 | self.rel_lo_ as u64
+    }
+
     pub fn abs(&self) -> u32 {
         self.abs_
     }
@@ -78,6 +151,27 @@ impl RankIndex {
     }
 }
 
+/// Offset and width of each of `long_rel(1)` to `long_rel(7)`
+const LONG_RELS: [(u32, u32); 7] =
+    [(0, 8), (8, 9), (17, 9), (26, 10), (36, 8), (44, 9), (53, 9)];
+
+impl Element for RankIndex {
+    fn io_size() -> usize {
+        12
+    }
+    fn read_from(reader: &mut Reader) -> io::Result<RankIndex> {
+        let abs = try!(reader.read_u32());
+        let rel_lo = try!(reader.read_u32());
+        let rel_hi = try!(reader.read_u32());
+        Ok(RankIndex { abs_: abs, rel_lo_: rel_lo, rel_hi_: rel_hi })
+    }
+    fn write_to(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(writer.write_u32(self.abs_));
+        try!(writer.write_u32(self.rel_lo_));
+        writer.write_u32(self.rel_hi_)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::RankIndex;
@@ -112,6 +206,29 @@ mod test {
         assert!(rank.rel5() == 320);
         assert!(rank.rel6() == 384);
         assert!(rank.rel7() == 448);
+        assert!((0..8).all(|k| rank.rel(k) == 64 * k as u32));
+    }
+
+    #[test]
+    fn test_rank_index_long() {
+        let mut rank: RankIndex = Default::default();
+        rank.set_abs(10000);
+        for k in 1..8 {
+            rank.set_long_rel(k, 128 * k as u32);
+        }
+        assert!(rank.abs() == 10000);
+        assert!((0..8).all(|k| rank.long_rel(k) == 128 * k as u32));
+
+        // Only the first four are relative to the block
+        let mut rank: RankIndex = Default::default();
+        for &(k, value) in [(1, 5), (2, 200), (3, 300), (4, 400), (5, 401),
+                            (6, 656), (7, 784)].iter() {
+            rank.set_long_rel(k, value);
+        }
+        assert!(rank.long_rel(3) == 300);
+        assert!(rank.long_rel(4) == 400);
+        assert!(rank.long_rel(5) == 401);
+        assert!(rank.long_rel(7) == 784);
     }
 }
 
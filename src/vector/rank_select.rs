@@ -0,0 +1,54 @@
+use super::bit_vec::BitVec;
+
+/// Queries shared by the bit vectors in this module, so that code reading
+/// them doesn't care how the bits are stored.
+///
+/// `rank0(i)` and `rank1(i)` count the 0s and 1s before position `i`, and
+/// `select0(k)` and `select1(k)` give the position of the `k`th 0 or 1,
+/// counting from 0.
+pub trait RankSelect {
+    fn len(&self) -> usize;
+    fn num_1s(&self) -> usize;
+    fn at(&self, i: usize) -> bool;
+    fn rank1(&self, i: usize) -> usize;
+    fn select0(&self, i: usize) -> usize;
+    fn select1(&self, i: usize) -> usize;
+
+    /// Can `select0` be called? Plain bit vectors need an index for it.
+    fn is_select0_enabled(&self) -> bool {
+        true
+    }
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn num_0s(&self) -> usize {
+        self.len() - self.num_1s()
+    }
+    fn rank0(&self, i: usize) -> usize {
+        i - self.rank1(i)
+    }
+}
+
+impl RankSelect for BitVec {
+    fn len(&self) -> usize {
+        BitVec::len(self)
+    }
+    fn num_1s(&self) -> usize {
+        BitVec::num_1s(self)
+    }
+    fn at(&self, i: usize) -> bool {
+        BitVec::at(self, i)
+    }
+    fn rank1(&self, i: usize) -> usize {
+        BitVec::rank1(self, i)
+    }
+    fn select0(&self, i: usize) -> usize {
+        BitVec::select0(self, i)
+    }
+    fn select1(&self, i: usize) -> usize {
+        BitVec::select1(self, i)
+    }
+    fn is_select0_enabled(&self) -> bool {
+        BitVec::is_select0_enabled(self)
+    }
+}
@@ -0,0 +1,273 @@
+use std::io;
+use io::{Reader, Writer, format_error};
+use super::bit_vec::{BitVec, BuildOptions, RankBlock, RankLayout};
+use super::bit_vec::{SelectInterval, MIN_SELECT_INTERVAL, MAX_SELECT_INTERVAL};
+use super::bit_vec::{MIN_RANK_BLOCK, MAX_RANK_BLOCK};
+use super::elias_fano::EliasFano;
+use super::rank_select::RankSelect;
+
+/// Bit vector for sparse 1s, stored as the Elias-Fano encoding of their
+/// positions. That is about `2 + log2(len / num_1s)` bits per 1, however
+/// long the vector is.
+///
+/// `write` writes it as a plain `BitVec`, built the way the one it came from
+/// was, which marisa-trie can read. `write_sparse` keeps the Elias-Fano form.
+#[derive(Clone, Debug)]
+pub struct SparseBitVec {
+    /// Positions of the 1s, with the length as the universe
+    ones_: EliasFano,
+    /// How to build the plain `BitVec` it is written as, if at all
+    plain_: Option<BuildOptions>,
+}
+
+impl SparseBitVec {
+    pub fn new() -> SparseBitVec {
+        SparseBitVec::from_positions(&[], 0)
+    }
+
+    /// Build from the positions of the 1s, which must be increasing and less
+    /// than `len`
+    pub fn from_positions(positions: &[usize], len: usize) -> SparseBitVec {
+        assert!(positions.windows(2).all(|w| w[0] < w[1]),
+                "MARISA_CODE_ERROR");
+        SparseBitVec { ones_: EliasFano::from_values(positions, len),
+                       plain_: None }
+    }
+
+    /// Encode straight from `bv.ones()`, so the positions are never held in
+    /// a `Vec`
+    pub fn from_bit_vec(bv: &BitVec) -> SparseBitVec {
+        SparseBitVec {
+            ones_: EliasFano::from_sorted(bv.ones(), bv.num_1s(), bv.len()),
+            plain_: bv.build_options(),
+        }
+    }
+
+    /// Rank layout of the plain `BitVec`
+    pub fn layout(&self) -> RankLayout {
+        self.plain_.map_or(RankLayout::for_len(self.len()), |x| x.layout)
+    }
+
+    /// Select interval of the plain `BitVec`
+    pub fn select_interval(&self) -> SelectInterval {
+        self.plain_.map_or(Default::default(), |x| x.interval)
+    }
+
+    /// The same bits as a `BitVec`, built with the given selects
+    pub fn to_bit_vec(&self, enables_select0: bool, enables_select1: bool)
+      -> BitVec {
+        let mut out = self.unbuilt_bit_vec();
+        out.build(enables_select0, enables_select1);
+        out
+    }
+
+    /// The plain `BitVec` it is written as
+    pub fn to_plain(&self) -> BitVec {
+        let mut out = self.unbuilt_bit_vec();
+        if let Some(plain) = self.plain_ {
+            out.build_with_options(plain.enables_select0,
+                                   plain.enables_select1, plain.layout,
+                                   plain.interval, plain.rank_block);
+        }
+        out
+    }
+
+    fn unbuilt_bit_vec(&self) -> BitVec {
+        let mut out = BitVec::new();
+        let mut next = 0;
+        for pos in self.ones_.iter() {
+            while next < pos {
+                out.push(false);
+                next += 1;
+            }
+            out.push(true);
+            next += 1;
+        }
+        while next < self.len() {
+            out.push(false);
+            next += 1;
+        }
+        out
+    }
+
+    /// Read a plain `BitVec` and encode it
+    pub fn read(reader: &mut Reader) -> io::Result<SparseBitVec> {
+        Ok(SparseBitVec::from_bit_vec(&try!(BitVec::read(reader))))
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        self.to_plain().write(writer)
+    }
+
+    /// Read what `write_sparse` wrote
+    pub fn read_sparse(reader: &mut Reader) -> io::Result<SparseBitVec> {
+        let ones = try!(EliasFano::read(reader));
+        let flags = try!(reader.read_u32());
+        let interval = try!(reader.read_u32()) as usize;
+        let rank_block = try!(reader.read_u32()) as usize;
+        try!(reader.seek(4));
+        if flags & !(BUILT | SELECT0 | SELECT1 | WIDE) != 0 {
+            return Err(format_error("sparse bit vector flags"));
+        }
+        let plain = if flags & BUILT == 0 {
+            None
+        } else {
+            let layout = if flags & WIDE != 0 { RankLayout::Wide }
+                         else { RankLayout::Compact };
+            if !interval.is_power_of_two() || interval < MIN_SELECT_INTERVAL
+            || interval > MAX_SELECT_INTERVAL
+            || !rank_block.is_power_of_two() || rank_block < MIN_RANK_BLOCK
+            || rank_block > MAX_RANK_BLOCK
+            || layout.fit(ones.universe()) != layout
+            || (layout == RankLayout::Compact
+                && rank_block != RankBlock::default().get()) {
+                return Err(format_error("sparse bit vector options"));
+            }
+            Some(BuildOptions { enables_select0: flags & SELECT0 != 0,
+                                enables_select1: flags & SELECT1 != 0,
+                                layout: layout,
+                                interval: SelectInterval::new(interval),
+                                rank_block: RankBlock::new(rank_block) })
+        };
+        Ok(SparseBitVec { ones_: ones, plain_: plain })
+    }
+
+    /// Write the Elias-Fano form, then how to build the plain `BitVec`. Much
+    /// smaller than `write`, but only this crate can read it.
+    pub fn write_sparse(&self, writer: &mut Writer) -> io::Result<()> {
+        try!(self.ones_.write(writer));
+        let (flags, interval, rank_block) = match self.plain_ {
+            Some(plain) => {
+                (BUILT | (if plain.enables_select0 { SELECT0 } else { 0 })
+                       | (if plain.enables_select1 { SELECT1 } else { 0 })
+                       | (if plain.layout == RankLayout::Wide { WIDE }
+                          else { 0 }),
+                 plain.interval.get(), plain.rank_block.get())
+            },
+            None => (0, 0, 0),
+        };
+        try!(writer.write_u32(flags));
+        try!(writer.write_u32(interval as u32));
+        try!(writer.write_u32(rank_block as u32));
+        writer.seek(4)
+    }
+}
+
+/// Flags `write_sparse` records the plain `BitVec`'s build options in
+const BUILT: u32 = 1;
+const SELECT0: u32 = 2;
+const SELECT1: u32 = 4;
+const WIDE: u32 = 8;
+
+impl From<BitVec> for SparseBitVec {
+    fn from(bv: BitVec) -> SparseBitVec {
+        SparseBitVec::from_bit_vec(&bv)
+    }
+}
+
+impl RankSelect for SparseBitVec {
+    fn len(&self) -> usize {
+        self.ones_.universe()
+    }
+    fn num_1s(&self) -> usize {
+        self.ones_.len()
+    }
+    fn at(&self, i: usize) -> bool {
+        assert!(i < self.len(), "MARISA_BOUND_ERROR");
+        self.ones_.next_geq(i).map_or(false, |(_, pos)| pos == i)
+    }
+    fn rank1(&self, i: usize) -> usize {
+        assert!(i <= self.len(), "MARISA_BOUND_ERROR");
+        self.ones_.rank(i)
+    }
+    fn select0(&self, i: usize) -> usize {
+        assert!(i < self.num_0s(), "MARISA_BOUND_ERROR");
+        // The `k`th 1 has `select1(k) - k` 0s before it. Find how many of the
+        // 1s come before the `i`th 0.
+        let (mut begin, mut end) = (0, self.num_1s());
+        while begin < end {
+            let middle = (begin + end) / 2;
+            if self.ones_.get(middle) - middle <= i {
+                begin = middle + 1;
+            } else {
+                end = middle;
+            }
+        }
+        i + begin
+    }
+    fn select1(&self, i: usize) -> usize {
+        assert!(i < self.num_1s(), "MARISA_BOUND_ERROR");
+        self.ones_.get(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quickcheck as qc;
+    use io::{Reader, Writer};
+    use vector::bit_vec::BitVec;
+    use vector::rank_select::RankSelect;
+    use super::SparseBitVec;
+
+    /// Every query must agree with the plain bit vector, and it must be
+    /// written the same way
+    fn sparse_prop(bv: BitVec) -> bool {
+        let sparse = SparseBitVec::from_bit_vec(&bv);
+        let mut buf: Vec<u8> = Vec::new();
+        sparse.write(&mut Writer::new(&mut buf)).unwrap();
+        let mut plain_buf: Vec<u8> = Vec::new();
+        bv.write(&mut Writer::new(&mut plain_buf)).unwrap();
+        if buf != plain_buf {
+            return false;
+        }
+        let read = SparseBitVec::read(&mut Reader::new(&mut &buf[..]))
+                   .unwrap();
+        let mut sparse_buf: Vec<u8> = Vec::new();
+        sparse.write_sparse(&mut Writer::new(&mut sparse_buf)).unwrap();
+        let read_sparse = SparseBitVec::read_sparse(
+            &mut Reader::new(&mut &sparse_buf[..])).unwrap();
+        let mut rewritten: Vec<u8> = Vec::new();
+        read_sparse.write(&mut Writer::new(&mut rewritten)).unwrap();
+        if rewritten != plain_buf || sparse_buf.len() % 8 != 0 {
+            return false;
+        }
+        let plain = sparse.to_bit_vec(true, true);
+        for x in [&sparse, &read, &read_sparse].iter() {
+            if x.len() != bv.len() || x.num_1s() != plain.num_1s()
+            || x.rank1(bv.len()) != x.num_1s() {
+                return false;
+            }
+            if (0..bv.len()).any(|i| x.at(i) != bv.at(i))
+            || (0..bv.len()).any(|i| x.rank1(i) != plain.rank1(i))
+            || (0..plain.num_0s()).any(|i| x.select0(i) != plain.select0(i))
+            || (0..plain.num_1s()).any(|i| x.select1(i) != plain.select1(i)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn sparse_bit_vec_qc() {
+        qc::quickcheck(sparse_prop as fn(BitVec) -> bool);
+    }
+
+    #[test]
+    fn sparse_bit_vec_manual() {
+        for &(len, step) in [(0, 1), (1, 1), (1000, 1), (1000, 7),
+                             (5000, 999), (100000, 4096)].iter() {
+            let mut bv = BitVec::new();
+            for i in 0..len {
+                bv.push(i % step == 0);
+            }
+            assert!(sparse_prop(bv.clone()));
+            bv.build(false, true);
+            assert!(sparse_prop(bv));
+        }
+        let sparse = SparseBitVec::from_positions(&[3, 64, 65, 9000], 10000);
+        assert!(sparse.rank1(65) == 2 && sparse.rank1(66) == 3);
+        assert!(sparse.select1(3) == 9000);
+        assert!(sparse.select0(3) == 4 && sparse.select0(63) == 66);
+        assert!(sparse.rank0(10000) == 9996);
+    }
+}
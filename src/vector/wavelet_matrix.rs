@@ -0,0 +1,261 @@
+use std::io;
+use std::iter::FromIterator;
+use io::{Reader, Writer, format_error};
+use super::bit_vec::BitVec;
+
+/// Number of levels, one per bit of a byte
+const NUM_LEVELS: usize = 8;
+
+/// Byte sequence that answers `rank` and `select` for any label, in a little
+/// over one bit per bit of input.
+///
+/// Level `l` holds bit `7 - l` of every byte, after the bytes have been
+/// stably sorted by their higher bits: those with a 0 at level `l - 1` come
+/// first, then those with a 1. Each query walks down (or back up) the levels
+/// with one rank (or select) per level.
+#[derive(Clone, Debug)]
+pub struct WaveletMatrix {
+    /// Built with select0 and select1
+    levels_: Vec<BitVec>,
+    /// Number of 0s in each level, where its 1s start in the next
+    zeros_: Vec<usize>,
+}
+
+impl WaveletMatrix {
+    pub fn new() -> WaveletMatrix {
+        WaveletMatrix::from_bytes(&[])
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> WaveletMatrix {
+        let mut levels = Vec::with_capacity(NUM_LEVELS);
+        let mut current = bytes.to_vec();
+        for level in 0..NUM_LEVELS {
+            let shift = NUM_LEVELS - 1 - level;
+            let mut bv = BitVec::new();
+            let (mut zeros, mut ones) = (Vec::new(), Vec::new());
+            for &byte in current.iter() {
+                let bit = (byte >> shift) & 1 == 1;
+                bv.push(bit);
+                if bit { ones.push(byte); } else { zeros.push(byte); }
+            }
+            bv.build(true, true);
+            levels.push(bv);
+            zeros.extend(ones);
+            current = zeros;
+        }
+        WaveletMatrix::from_levels(levels)
+    }
+
+    fn from_levels(levels: Vec<BitVec>) -> WaveletMatrix {
+        let zeros = levels.iter().map(|bv| bv.num_0s()).collect();
+        WaveletMatrix { levels_: levels, zeros_: zeros }
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels_[0].len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `i`th byte
+    pub fn access(&self, mut i: usize) -> u8 {
+        assert!(i < self.len(), "MARISA_BOUND_ERROR");
+        let mut byte = 0;
+        for (level, bv) in self.levels_.iter().enumerate() {
+            byte <<= 1;
+            if bv.at(i) {
+                byte |= 1;
+                i = self.zeros_[level] + bv.rank1(i);
+            } else {
+                i = bv.rank0(i);
+            }
+        }
+        byte
+    }
+
+    /// Number of times `label` occurs before position `i`
+    pub fn rank(&self, label: u8, i: usize) -> usize {
+        assert!(i <= self.len(), "MARISA_BOUND_ERROR");
+        let (begin, end) = self.range(label, i);
+        end - begin
+    }
+
+    /// Number of times `label` occurs
+    pub fn count(&self, label: u8) -> usize {
+        self.rank(label, self.len())
+    }
+
+    /// Position of the `k`th occurrence of `label`, counting from 0
+    pub fn select(&self, label: u8, k: usize) -> usize {
+        let (begin, end) = self.range(label, self.len());
+        assert!(k < end - begin, "MARISA_BOUND_ERROR");
+        // Occurrences of `label` are contiguous after the last level, so walk
+        // back up from there
+        let mut pos = begin + k;
+        for (level, bv) in self.levels_.iter().enumerate().rev() {
+            if bit_at(label, level) {
+                pos = bv.select1(pos - self.zeros_[level]);
+            } else {
+                pos = bv.select0(pos);
+            }
+        }
+        pos
+    }
+
+    /// Where the occurrences of `label` before `i` end up after the last
+    /// level
+    fn range(&self, label: u8, i: usize) -> (usize, usize) {
+        let (mut begin, mut end) = (0, i);
+        for (level, bv) in self.levels_.iter().enumerate() {
+            if bit_at(label, level) {
+                begin = self.zeros_[level] + bv.rank1(begin);
+                end = self.zeros_[level] + bv.rank1(end);
+            } else {
+                begin -= bv.rank1(begin);
+                end -= bv.rank1(end);
+            }
+        }
+        (begin, end)
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter { wm_: self, i_: 0 }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.iter().collect()
+    }
+
+    /// Read the levels, top first
+    pub fn read(reader: &mut Reader) -> io::Result<WaveletMatrix> {
+        let mut levels = Vec::with_capacity(NUM_LEVELS);
+        for _ in 0..NUM_LEVELS {
+            let bv = try!(BitVec::read(reader));
+            if !bv.is_select0_enabled() || !bv.is_select1_enabled()
+            || levels.first().map_or(false, |first: &BitVec| {
+                first.len() != bv.len()
+            }) {
+                return Err(format_error("wavelet matrix"));
+            }
+            levels.push(bv);
+        }
+        Ok(WaveletMatrix::from_levels(levels))
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> io::Result<()> {
+        for bv in self.levels_.iter() {
+            try!(bv.write(writer));
+        }
+        Ok(())
+    }
+}
+
+impl FromIterator<u8> for WaveletMatrix {
+    fn from_iter<T: IntoIterator<Item=u8>>(iter: T) -> WaveletMatrix {
+        let bytes: Vec<u8> = iter.into_iter().collect();
+        WaveletMatrix::from_bytes(&bytes)
+    }
+}
+
+impl<'a> IntoIterator for &'a WaveletMatrix {
+    type Item = u8;
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The bytes of a `WaveletMatrix`, in order
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    wm_: &'a WaveletMatrix,
+    i_: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        if self.i_ < self.wm_.len() {
+            self.i_ += 1;
+            Some(self.wm_.access(self.i_ - 1))
+        } else {
+            None
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let rest = self.wm_.len() - self.i_;
+        (rest, Some(rest))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+/// Whether `label` goes to the 1s at `level`
+fn bit_at(label: u8, level: usize) -> bool {
+    (label >> (NUM_LEVELS - 1 - level)) & 1 == 1
+}
+
+#[cfg(test)]
+mod test {
+    use env_logger;
+    use quickcheck as qc;
+    use io::{Reader, Writer};
+    use super::WaveletMatrix;
+
+    /// Labels are folded into a small alphabet so that they repeat
+    fn wavelet_matrix_prop(bytes: Vec<u8>, fold: u8) -> bool {
+        let fold = fold.saturating_add(1);
+        let bytes: Vec<u8> = bytes.iter().map(|&b| b % fold).collect();
+        let wm = WaveletMatrix::from_bytes(&bytes);
+        let mut buf: Vec<u8> = Vec::new();
+        wm.write(&mut Writer::new(&mut buf)).unwrap();
+        let read = WaveletMatrix::read(&mut Reader::new(&mut &buf[..]))
+                   .unwrap();
+        for x in [&wm, &read].iter() {
+            if x.len() != bytes.len() || x.to_vec() != bytes {
+                return false;
+            }
+            let mut labels = bytes.clone();
+            labels.extend(vec![0, 255, fold]);
+            labels.sort();
+            labels.dedup();
+            for &label in labels.iter() {
+                let positions: Vec<usize> = (0..bytes.len())
+                                            .filter(|&i| bytes[i] == label)
+                                            .collect();
+                if x.count(label) != positions.len()
+                || (0..bytes.len() + 1).any(|i| {
+                    x.rank(label, i)
+                    != positions.iter().take_while(|&&p| p < i).count()
+                })
+                || positions.iter().enumerate()
+                   .any(|(k, &p)| x.select(label, k) != p) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn wavelet_matrix_qc() {
+        let _ = env_logger::init();
+        qc::quickcheck(wavelet_matrix_prop as fn(Vec<u8>, u8) -> bool);
+    }
+
+    #[test]
+    fn wavelet_matrix_manual() {
+        let _ = env_logger::init();
+        let wm: WaveletMatrix = b"abracadabra".iter().cloned().collect();
+        assert!(wm.access(4) == b'c' && wm.count(b'a') == 5);
+        assert!(wm.rank(b'a', 7) == 3 && wm.rank(b'z', 11) == 0);
+        assert!(wm.select(b'a', 3) == 7 && wm.select(b'r', 1) == 9);
+        assert!((&wm).into_iter().eq(b"abracadabra".iter().cloned()));
+        let empty = WaveletMatrix::new();
+        assert!(empty.is_empty() && empty.count(0) == 0);
+        let long: Vec<u8> = (0..2000).map(|i| (i * 7 % 256) as u8).collect();
+        assert!(wavelet_matrix_prop(long.clone(), 255));
+        assert!(wavelet_matrix_prop(long, 2));
+    }
+}
@@ -0,0 +1,110 @@
+//! Compare two dictionaries and print their differences in key order, one
+//! key per line:
+//!
+//!     -  OLD_ID  KEY            removed
+//!     +  NEW_ID  KEY            added
+//!     ~  OLD_ID  NEW_ID  KEY    kept, with a new ID
+//!     =  ID      KEY            kept, same ID (only with --all)
+//!
+//! Fields are separated by single tabs. Keys are printed as bytes, with `\\`,
+//! tab, newline, carriage return and other control bytes escaped as `\\`,
+//! `\t`, `\n`, `\r` and `\xNN`.
+
+extern crate mars_trie;
+
+use mars_trie::{DiffEntry, LoudsTrie};
+use std::env;
+use std::io;
+use std::io::Write;
+use std::process;
+
+fn print_help(cmd: &str) {
+    let _ = writeln!(io::stderr(),
+                     "Usage: {} [OPTION]... OLD NEW\n\n\
+                      Options:\n  \
+                      -a, --all   also print keys that kept their ID\n  \
+                      -h, --help  print this help", cmd);
+}
+
+fn write_key(out: &mut Write, key: &[u8]) -> io::Result<()> {
+    for &c in key {
+        match c {
+            b'\\' => try!(out.write_all(b"\\\\")),
+            b'\t' => try!(out.write_all(b"\\t")),
+            b'\n' => try!(out.write_all(b"\\n")),
+            b'\r' => try!(out.write_all(b"\\r")),
+            0...0x1F | 0x7F => try!(write!(out, "\\x{:02X}", c)),
+            _ => try!(out.write_all(&[c])),
+        }
+    }
+    out.write_all(b"\n")
+}
+
+fn diff(old: &LoudsTrie, new: &LoudsTrie, all: bool) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut diff = old.diff(new);
+    while let Some(entry) = diff.next_entry() {
+        match entry {
+            DiffEntry::Removed { old_id, .. } => {
+                try!(write!(out, "-\t{}\t", old_id));
+            },
+            DiffEntry::Added { new_id, .. } => {
+                try!(write!(out, "+\t{}\t", new_id));
+            },
+            DiffEntry::Kept { old_id, new_id, .. } if old_id != new_id => {
+                try!(write!(out, "~\t{}\t{}\t", old_id, new_id));
+            },
+            DiffEntry::Kept { old_id, .. } => {
+                if !all {
+                    continue;
+                }
+                try!(write!(out, "=\t{}\t", old_id));
+            },
+        }
+        try!(write_key(&mut out, entry.key()));
+    }
+    out.flush()
+}
+
+fn load(path: &str) -> LoudsTrie {
+    match LoudsTrie::load(path) {
+        Ok(trie) => trie,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "error: failed to load: {}: {}",
+                             path, e);
+            process::exit(20);
+        },
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut all = false;
+    let mut paths: Vec<&str> = Vec::new();
+    for arg in args.iter().skip(1) {
+        match &arg[..] {
+            "-a" | "--all" => { all = true; },
+            "-h" | "--help" => {
+                print_help(&args[0]);
+                return;
+            },
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                let _ = writeln!(io::stderr(), "error: unknown option: {}",
+                                 arg);
+                process::exit(1);
+            },
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        print_help(&args[0]);
+        process::exit(1);
+    }
+    let old = load(paths[0]);
+    let new = load(paths[1]);
+    if let Err(e) = diff(&old, &new, all) {
+        let _ = writeln!(io::stderr(), "error: failed to write: {}", e);
+        process::exit(30);
+    }
+}
@@ -22,154 +22,126 @@
 // ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
 // POSSIBILITY OF SUCH DAMAGE.
 
-#ifdef _WIN32
- #include <fcntl.h>
- #include <io.h>
- #include <stdio.h>
-#endif  // _WIN32
+//! Print every key of one or more dictionaries, each followed by the
+//! delimiter. Dictionaries are read from the files given, or from standard
+//! input if there are none. Keys come out depth-first, in label order for
+//! dictionaries built with `NodeOrder::Label`. Deleted keys are skipped.
+//!
+//! marisa-trie's `-m` (memory-map the dictionary) is accepted for
+//! compatibility, but dictionaries are always read into memory.
 
-#include <cstdlib>
-#include <iostream>
-#include <string>
+extern crate mars_trie;
 
-#include <marisa.h>
+use mars_trie::LoudsTrie;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::process;
 
-#include "cmdopt.h"
-
-namespace {
-
-const char *delimiter = "\n";
-bool mmap_flag = true;
-
-void print_help(const char *cmd) {
-  std::cerr << "Usage: " << cmd << " [OPTION]... DIC...\n\n"
-      "Options:\n"
-      "  -d, --delimiter=[S]    specify the delimier (default: \"\\n\")\n"
-      "  -m, --mmap-dictionary  use memory-mapped I/O to load a dictionary"
-      " (default)\n"
-      "  -r, --read-dictionary  read an entire dictionary into memory\n"
-      "  -h, --help             print this help\n"
-      << std::endl;
+fn print_help(cmd: &str) {
+    let _ = writeln!(io::stderr(),
+                     "Usage: {} [OPTION]... DIC...\n\n\
+                      Options:\n  \
+                      -d, --delimiter=[S]    specify the delimiter \
+                      (default: \"\\n\")\n  \
+                      -m, --mmap-dictionary  same as -r\n  \
+                      -r, --read-dictionary  read an entire dictionary \
+                      into memory (default)\n  \
+                      -h, --help             print this help", cmd);
 }
 
-int dump(const marisa::Trie &trie) {
-  std::size_t num_keys = 0;
-  marisa::Agent agent;
-  agent.set_query("");
-  try {
-    while (trie.predictive_search(agent)) {
-      std::cout.write(agent.key().ptr(), agent.key().length()) << delimiter;
-      if (!std::cout) {
-        std::cerr << "error: failed to write results to standard output"
-            << std::endl;
-        return 20;
-      }
-      ++num_keys;
+fn dump(trie: &LoudsTrie, delimiter: &[u8]) -> io::Result<usize> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut num_keys = 0;
+    let mut keys = trie.iter();
+    while let Some((key, _)) = keys.next_key() {
+        try!(out.write_all(key));
+        try!(out.write_all(delimiter));
+        num_keys += 1;
     }
-  } catch (const marisa::Exception &ex) {
-    std::cerr << ex.what() << ": predictive_search() failed" << std::endl;
-    return 21;
-  }
-  std::cerr << "#keys: " << num_keys << std::endl;
-  return 0;
+    try!(out.flush());
+    Ok(num_keys)
 }
 
-int dump(const char *filename) {
-  marisa::Trie trie;
-  if (filename != NULL) {
-    std::cerr << "input: " << filename << std::endl;
-    if (mmap_flag) {
-      try {
-        trie.mmap(filename);
-      } catch (const marisa::Exception &ex) {
-        std::cerr << ex.what() << ": failed to mmap a dictionary file: "
-            << filename << std::endl;
-        return 10;
-      }
-    } else {
-      try {
-        trie.load(filename);
-      } catch (const marisa::Exception &ex) {
-        std::cerr << ex.what() << ": failed to load a dictionary file: "
-            << filename << std::endl;
-        return 11;
-      }
-    }
-  } else {
-    std::cerr << "input: <stdin>" << std::endl;
-#ifdef _WIN32
-    const int stdin_fileno = ::_fileno(stdin);
-    if (stdin_fileno < 0) {
-      std::cerr << "error: failed to get the file descriptor of "
-          "standard input" << std::endl;
-      return 20;
-    }
-    if (::_setmode(stdin_fileno, _O_BINARY) == -1) {
-      std::cerr << "error: failed to set binary mode" << std::endl;
-      return 21;
+fn load(path: Option<&str>) -> LoudsTrie {
+    let result = match path {
+        Some(path) => {
+            let _ = writeln!(io::stderr(), "input: {}", path);
+            LoudsTrie::load(path)
+        },
+        None => {
+            let _ = writeln!(io::stderr(), "input: <stdin>");
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            LoudsTrie::read(&mut stdin)
+        },
+    };
+    match result {
+        Ok(trie) => trie,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "error: failed to load: {}: {}",
+                             path.unwrap_or("<stdin>"), e);
+            process::exit(if path.is_some() { 11 } else { 22 });
+        },
     }
-#endif  // _WIN32
-    try {
-      std::cin >> trie;
-    } catch (const marisa::Exception &ex) {
-      std::cerr << ex.what()
-          << ": failed to read a dictionary from standard input" << std::endl;
-      return 22;
-    }
-  }
-  return dump(trie);
 }
 
-int dump(const char * const *args, std::size_t num_args) {
-  if (num_args == 0) {
-    return dump(NULL);
-  }
-  for (std::size_t i = 0; i < num_args; ++i) {
-    const int result = dump(args[i]);
-    if (result != 0) {
-      return result;
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut delimiter = b"\n".to_vec();
+    let mut paths: Vec<&str> = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i][..];
+        match arg {
+            "-d" | "--delimiter" => {
+                i += 1;
+                match args.get(i) {
+                    Some(d) => { delimiter = d.as_bytes().to_vec(); },
+                    None => {
+                        let _ = writeln!(io::stderr(),
+                                         "error: {} needs an argument", arg);
+                        process::exit(1);
+                    },
+                }
+            },
+            _ if arg.starts_with("--delimiter=") => {
+                delimiter = arg["--delimiter=".len()..].as_bytes().to_vec();
+            },
+            _ if arg.starts_with("-d") => {
+                delimiter = arg[2..].as_bytes().to_vec();
+            },
+            "-m" | "--mmap-dictionary" | "-r" | "--read-dictionary" => {},
+            "-h" | "--help" => {
+                print_help(&args[0]);
+                return;
+            },
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                let _ = writeln!(io::stderr(), "error: unknown option: {}",
+                                 arg);
+                process::exit(1);
+            },
+            _ => paths.push(arg),
+        }
+        i += 1;
     }
-  }
-  return 0;
-}
-
-}  // namespace
-
-int main(int argc, char *argv[]) {
-  std::ios::sync_with_stdio(false);
 
-  ::cmdopt_option long_options[] = {
-    { "delimiter", 1, NULL, 'd' },
-    { "mmap-dictionary", 0, NULL, 'm' },
-    { "read-dictionary", 0, NULL, 'r' },
-    { "help", 0, NULL, 'h' },
-    { NULL, 0, NULL, 0 }
-  };
-  ::cmdopt_t cmdopt;
-  ::cmdopt_init(&cmdopt, argc, argv, "d:mrh", long_options);
-  int label;
-  while ((label = ::cmdopt_get(&cmdopt)) != -1) {
-    switch (label) {
-      case 'd': {
-        delimiter = cmdopt.optarg;
-        break;
-      }
-      case 'm': {
-        mmap_flag = true;
-        break;
-      }
-      case 'r': {
-        mmap_flag = false;
-        break;
-      }
-      case 'h': {
-        print_help(argv[0]);
-        return 0;
-      }
-      default: {
-        return 1;
-      }
+    let inputs: Vec<Option<&str>> = if paths.is_empty() { vec![None] }
+                                    else { paths.iter().map(|&p| Some(p))
+                                                 .collect() };
+    for path in inputs {
+        let trie = load(path);
+        match dump(&trie, &delimiter) {
+            Ok(num_keys) => {
+                let _ = writeln!(io::stderr(), "#keys: {}", num_keys);
+            },
+            Err(e) => {
+                let _ = writeln!(io::stderr(),
+                                 "error: failed to write results to standard \
+                                  output: {}", e);
+                process::exit(20);
+            },
+        }
     }
-  }
-  return dump(cmdopt.argv + cmdopt.optind, cmdopt.argc - cmdopt.optind);
 }